@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+use ndarray::prelude::*;
+
+use crate::binary_tree::{group_id, TreeArena};
+
+/// The share of an allele in the counts of its transcript in a sample,
+/// over the inferential replicates in which the transcript is expressed
+#[derive(Debug, Clone)]
+pub struct AlleleRatio {
+    pub txp: usize,
+    pub allele: usize,
+    pub sample: usize,
+    /// the mean count of the allele
+    pub mean: f64,
+    pub ratio: Option<f64>,
+    pub ratio_var: Option<f64>,
+}
+
+/// Computes the allelic ratio of every allele of the transcripts having
+/// several, in each sample, from the inferential replicates taken before
+/// any collapse
+pub fn allelic_ratios(
+    txp_alleles: &HashMap<usize, Vec<usize>>,
+    gibbs_mats: &[&Array2<f64>],
+) -> Vec<AlleleRatio> {
+    let mut txps: Vec<&usize> = txp_alleles.keys().collect();
+    txps.sort_unstable();
+    let mut ratios = Vec::new();
+    for txp in txps {
+        let mut alleles = txp_alleles[txp].clone();
+        if alleles.len() < 2 {
+            continue;
+        }
+        alleles.sort_unstable();
+        for (sample, gb) in gibbs_mats.iter().enumerate() {
+            let mut total = Array1::<f64>::zeros(gb.shape()[1]);
+            for a in alleles.iter() {
+                total += &gb.row(*a);
+            }
+            for a in alleles.iter() {
+                let row = gb.row(*a);
+                let shares: Vec<f64> = row
+                    .iter()
+                    .zip(total.iter())
+                    .filter(|(_, t)| **t > 0.0)
+                    .map(|(c, t)| c / t)
+                    .collect();
+                let shares = Array1::from(shares);
+                let (ratio, ratio_var) = match shares.len() {
+                    0 => (None, None),
+                    1 => (shares.mean(), Some(0.0)),
+                    _ => (
+                        shares.mean(),
+                        Some(shares.var_axis(Axis(0), 1.).into_scalar()),
+                    ),
+                };
+                ratios.push(AlleleRatio {
+                    txp: *txp,
+                    allele: *a,
+                    sample,
+                    mean: row.mean().unwrap(),
+                    ratio,
+                    ratio_var,
+                });
+            }
+        }
+    }
+    ratios
+}
+
+/// Maps every leaf of the trees of the groups to the id of its tree
+pub fn leaf_trees(
+    groups: &HashMap<usize, Vec<usize>>,
+    c_order: &TreeArena,
+) -> HashMap<u32, String> {
+    let mut trees = HashMap::new();
+    for group in groups.keys() {
+        let leaves = c_order.leaves(c_order.roots[*group]);
+        let tree_id = group_id(&leaves);
+        for l in leaves {
+            trees.insert(l, tree_id.clone());
+        }
+    }
+    trees
+}
+
+/// Writes a row for every leaf of the trees, with the transcript it is an
+/// allele of
+pub fn allele_leaves_writer(
+    out: &mut dyn Write,
+    leaf_trees: &HashMap<u32, String>,
+    allele_txp: &[usize],
+    allele_names: &[String],
+    txp_names: &[String],
+) -> Result<bool, io::Error> {
+    let mut leaves: Vec<(&String, &u32)> = leaf_trees.iter().map(|(l, t)| (t, l)).collect();
+    leaves.sort_unstable();
+    writeln!(out, "tree\tleaf\tallele\ttranscript")?;
+    for (tree_id, leaf) in leaves {
+        let l = *leaf as usize;
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}",
+            tree_id, l, allele_names[l], txp_names[allele_txp[l]]
+        )?;
+    }
+    Ok(true)
+}
+
+fn or_na(x: Option<f64>) -> String {
+    x.map_or("NA".to_string(), |x| x.to_string())
+}
+
+/// Writes the allelic ratios as a table, along with the tree holding each
+/// allele, `NA` when it was not grouped
+pub fn allelic_ratio_writer(
+    out: &mut dyn Write,
+    ratios: &[AlleleRatio],
+    leaf_trees: &HashMap<u32, String>,
+    allele_names: &[String],
+    txp_names: &[String],
+    sample_names: &[String],
+) -> Result<bool, io::Error> {
+    writeln!(
+        out,
+        "transcript\tallele\tsample\ttree\tmean_count\tratio\tratio_var"
+    )?;
+    for r in ratios.iter() {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            txp_names[r.txp],
+            allele_names[r.allele],
+            sample_names[r.sample],
+            leaf_trees
+                .get(&(r.allele as u32))
+                .map_or("NA", |t| t.as_str()),
+            r.mean,
+            or_na(r.ratio),
+            or_na(r.ratio_var)
+        )?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(prefix: &str, n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("{}{}", prefix, i)).collect()
+    }
+
+    #[test]
+    fn ratios_of_a_two_allele_transcript() {
+        // transcript 0 has the alleles 0 and 1, transcript 1 only allele 2;
+        // the last replicate has no counts for transcript 0
+        let txp_alleles: HashMap<usize, Vec<usize>> =
+            [(0, vec![1, 0]), (1, vec![2])].into_iter().collect();
+        let gb = array![[1.0, 3.0, 0.0], [3.0, 1.0, 0.0], [5.0, 5.0, 5.0]];
+        let ratios = allelic_ratios(&txp_alleles, &[&gb]);
+        assert_eq!(ratios.len(), 2);
+        let (a, b) = (&ratios[0], &ratios[1]);
+        assert_eq!((a.txp, a.allele, a.sample), (0, 0, 0));
+        assert_eq!((b.txp, b.allele, b.sample), (0, 1, 0));
+        assert_eq!(a.mean, 4.0 / 3.0);
+        assert_eq!(a.ratio, Some(0.5));
+        assert_eq!(b.ratio, Some(0.5));
+        assert_eq!(a.ratio_var, Some(0.125));
+        assert_eq!(b.ratio_var, Some(0.125));
+    }
+
+    #[test]
+    fn ratios_without_counts_or_replicates() {
+        let txp_alleles: HashMap<usize, Vec<usize>> = [(0, vec![0, 1])].into_iter().collect();
+        let empty = array![[0.0, 0.0], [0.0, 0.0]];
+        let single = array![[1.0], [3.0]];
+        let ratios = allelic_ratios(&txp_alleles, &[&empty, &single]);
+        assert_eq!(ratios.len(), 4);
+        assert!(ratios[..2]
+            .iter()
+            .all(|r| r.sample == 0 && r.ratio.is_none() && r.ratio_var.is_none()));
+        assert_eq!(ratios[2].ratio, Some(0.25));
+        assert_eq!(ratios[3].ratio, Some(0.75));
+        assert!(ratios[2..].iter().all(|r| r.ratio_var == Some(0.0)));
+
+        let mut out = Vec::new();
+        allelic_ratio_writer(
+            &mut out,
+            &ratios[..1],
+            &HashMap::new(),
+            &names("a", 2),
+            &names("t", 1),
+            &names("S", 2),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "transcript\tallele\tsample\ttree\tmean_count\tratio\tratio_var\n\
+             t0\ta0\tS0\tNA\t0\tNA\tNA\n"
+        );
+    }
+
+    #[test]
+    fn writes_the_trees_of_the_alleles() {
+        // the alleles 0, 1 and 3 are grouped, allele 2 is left alone
+        let mut arena = TreeArena::with_leaves(4);
+        arena.merge(0, 1);
+        arena.merge(0, 3);
+        let groups: HashMap<usize, Vec<usize>> = [(0, vec![0, 1, 3])].into_iter().collect();
+        let trees = leaf_trees(&groups, &arena);
+        assert_eq!(trees.len(), 3);
+        assert!(trees.values().all(|t| t == "0_1_3"));
+
+        let allele_txp = vec![0, 0, 1, 1];
+        let mut out = Vec::new();
+        allele_leaves_writer(
+            &mut out,
+            &trees,
+            &allele_txp,
+            &names("a", 4),
+            &names("t", 2),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tree\tleaf\tallele\ttranscript\n\
+             0_1_3\t0\ta0\tt0\n\
+             0_1_3\t1\ta1\tt0\n\
+             0_1_3\t3\ta3\tt1\n"
+        );
+
+        let ratios = vec![AlleleRatio {
+            txp: 1,
+            allele: 3,
+            sample: 0,
+            mean: 2.5,
+            ratio: Some(0.5),
+            ratio_var: Some(0.0),
+        }];
+        let mut out = Vec::new();
+        allelic_ratio_writer(
+            &mut out,
+            &ratios,
+            &trees,
+            &names("a", 4),
+            &names("t", 2),
+            &names("S", 1),
+        )
+        .unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with("t1\ta3\tS0\t0_1_3\t2.5\t0.5\t0\n"));
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::io::Write;
+
+use log::warn;
+use serde::Serialize;
+
+use crate::binary_tree::{group_id, TreeArena};
+use crate::nary_tree::NaryNode;
+
+/// The gene given to the transcripts missing from the mapping
+pub const UNMAPPED_GENE: &str = "unmapped";
+
+/// The genes of the transcripts, given by their index in `names`
+pub struct GeneMap {
+    pub txp_gene: Vec<usize>,
+    pub names: Vec<String>,
+}
+
+impl GeneMap {
+    /// Builds the genes of `ntxps` transcripts from the transcripts of each
+    /// gene of `names`, as read by `util::get_map_bw_ent`. The transcripts
+    /// missing from the mapping are given the gene `UNMAPPED_GENE`.
+    pub fn new(
+        mut names: Vec<String>,
+        gene_txps: &HashMap<usize, Vec<usize>>,
+        ntxps: usize,
+    ) -> GeneMap {
+        let unmapped = names.len();
+        let mut txp_gene = vec![unmapped; ntxps];
+        for (gene, txps) in gene_txps.iter() {
+            for t in txps.iter() {
+                txp_gene[*t] = *gene;
+            }
+        }
+        let n_unmapped = txp_gene.iter().filter(|g| **g == unmapped).count();
+        if n_unmapped > 0 {
+            warn!(
+                "{} transcripts are missing from the gene mapping, annotated with the gene {:?}",
+                n_unmapped, UNMAPPED_GENE
+            );
+            names.push(UNMAPPED_GENE.to_string());
+        }
+        GeneMap { txp_gene, names }
+    }
+}
+
+/// The number of trees whose transcripts come from a single gene, and of
+/// those crossing gene boundaries
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GeneSummary {
+    pub n_trees: usize,
+    pub n_gene_pure: usize,
+    pub n_cross_gene: usize,
+    /// the largest number of genes spanned by a tree
+    pub max_genes: usize,
+}
+
+/// Writes a row for every node of the trees of the groups, leaves included,
+/// with the transcripts and genes below it, and returns how many trees
+/// cross gene boundaries. The trees are written in the order of their
+/// groups, and their nodes numbered in the postorder of the multifurcating
+/// trees of `group_nwk.txt`, so that the root comes last.
+pub fn gene_annotation_writer(
+    out: &mut dyn Write,
+    groups: &HashMap<usize, Vec<usize>>,
+    c_order: &TreeArena,
+    genes: &GeneMap,
+) -> Result<GeneSummary, io::Error> {
+    let mut summary = GeneSummary::default();
+    writeln!(out, "tree\tnode\ttxps\tn_txps\tn_genes\tgene_pure\tgenes")?;
+    let mut group_order: Vec<usize> = groups.keys().copied().collect();
+    group_order.sort_unstable();
+    for group in group_order.iter() {
+        let root = c_order.roots[*group];
+        let tree_id = group_id(&c_order.leaves(root));
+        let tree = NaryNode::from_arena(c_order, root);
+        // the transcripts below each finished node whose parent is yet to
+        // be reached
+        let mut done: Vec<Vec<u32>> = Vec::new();
+        let mut n_genes = 0;
+        for (v, node) in tree.postorder().into_iter().enumerate() {
+            let mut txps = match node.is_leaf() {
+                true => vec![node.txp],
+                false => done.split_off(done.len() - node.children.len()).concat(),
+            };
+            txps.sort_unstable();
+            let node_genes: BTreeSet<&str> = txps
+                .iter()
+                .map(|t| genes.names[genes.txp_gene[*t as usize]].as_str())
+                .collect();
+            n_genes = node_genes.len();
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                tree_id,
+                v,
+                group_id(&txps),
+                txps.len(),
+                n_genes,
+                n_genes == 1,
+                node_genes.into_iter().collect::<Vec<&str>>().join(",")
+            )?;
+            done.push(txps);
+        }
+        summary.n_trees += 1;
+        if n_genes == 1 {
+            summary.n_gene_pure += 1;
+        } else {
+            summary.n_cross_gene += 1;
+        }
+        summary.max_genes = summary.max_genes.max(n_genes);
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binary_tree::{POSTORDER_CHAIN, POSTORDER_MERGE};
+
+    fn gene_map(ntxps: usize) -> GeneMap {
+        // transcripts 0 and 1 belong to gene A, 2 to gene B
+        let gene_txps: HashMap<usize, Vec<usize>> =
+            vec![(0, vec![0, 1]), (1, vec![2])].into_iter().collect();
+        GeneMap::new(vec!["A".to_string(), "B".to_string()], &gene_txps, ntxps)
+    }
+
+    #[test]
+    fn gives_unmapped_transcripts_a_gene() {
+        let genes = gene_map(4);
+        assert_eq!(genes.txp_gene, vec![0, 0, 1, 2]);
+        assert_eq!(genes.names, vec!["A", "B", UNMAPPED_GENE]);
+        assert_eq!(gene_map(3).names, vec!["A", "B"]);
+
+        let empty = GeneMap::new(Vec::new(), &HashMap::new(), 2);
+        assert_eq!(empty.txp_gene, vec![0, 0]);
+        assert_eq!(empty.names, vec![UNMAPPED_GENE]);
+    }
+
+    #[test]
+    fn annotates_trees_in_group_order() {
+        let mut arena = TreeArena::with_leaves(4);
+        // group 0 is the multifurcation (1,0,2), stored as a chain, and
+        // group 3 the single transcript 3
+        let a = arena.push_postorder(&[1, 0, POSTORDER_CHAIN, 2, POSTORDER_MERGE], &[]);
+        let b = arena.push_postorder(&[3], &[]);
+        arena.roots[0] = a;
+        arena.roots[3] = b;
+        let groups: HashMap<usize, Vec<usize>> =
+            vec![(3, vec![3]), (0, vec![0, 1, 2])].into_iter().collect();
+
+        let mut out = Vec::new();
+        let summary = gene_annotation_writer(&mut out, &groups, &arena, &gene_map(4)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tree\tnode\ttxps\tn_txps\tn_genes\tgene_pure\tgenes\n\
+             0_1_2\t0\t1\t1\t1\ttrue\tA\n\
+             0_1_2\t1\t0\t1\t1\ttrue\tA\n\
+             0_1_2\t2\t2\t1\t1\ttrue\tB\n\
+             0_1_2\t3\t0_1_2\t3\t2\tfalse\tA,B\n\
+             3\t0\t3\t1\t1\ttrue\tunmapped\n"
+        );
+        assert_eq!(summary.n_trees, 2);
+        assert_eq!(summary.n_gene_pure, 1);
+        assert_eq!(summary.n_cross_gene, 1);
+        assert_eq!(summary.max_genes, 2);
+    }
+}
@@ -1,11 +1,96 @@
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Token used in the postorder encoding of a tree for an internal node,
+/// whose children are the two subtrees preceding it
+pub const POSTORDER_MERGE: u32 = u32::MAX;
+
+/// Token for an internal node that only resolves a multifurcation of its
+/// parent into a binary chain (see `TreeNode::chained`)
+pub const POSTORDER_CHAIN: u32 = u32::MAX - 1;
+
+/// Version of the compact collapse order format
+pub const COLLAPSE_ORDER_VERSION: u32 = 1;
+
+/// Compact representation of the collapse order of a run, where each
+/// tree is stored as a postorder sequence of leaf (transcript) indices
+/// and `POSTORDER_MERGE` or `POSTORDER_CHAIN` tokens. Unlike the nested JSON, its size is linear
+/// in the number of nodes.
+/// `stats[i]` holds the statistics of the nodes of `trees[i]` in the same
+/// postorder, and is empty if they are not known.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CollapseOrder {
+    pub version: u32,
+    pub trees: Vec<Vec<u32>>,
+    pub stats: Vec<Vec<NodeStats>>,
+}
+
+/// Inferential relative variance and mean count of the transcript or
+/// group represented by a node, at the time it was formed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NodeStats {
+    pub infrv: f64,
+    pub mean: f64,
+}
+
+/// A node of a binary group tree. The collapse order builds deep chains
+/// of these, so every traversal, as well as cloning, dropping and the JSON
+/// format (see `tree_json`), is done without recursing.
+#[derive(Debug)]
 pub struct TreeNode {
     pub id: String,
     pub left: Option<Box<TreeNode>>,
     pub right: Option<Box<TreeNode>>,
+    pub stats: Option<NodeStats>,
+    /// set on the internal nodes that are an arbitrary binary resolution of
+    /// a multifurcation, such as the chain joining a group of identical
+    /// transcripts, and that belong to their parent in the n-ary tree
+    pub chained: bool,
+}
+
+impl Clone for TreeNode {
+    fn clone(&self) -> TreeNode {
+        let mut done: Vec<TreeNode> = Vec::new();
+        for node in self.postorder() {
+            let right = node.right.as_ref().map(|_| Box::new(done.pop().unwrap()));
+            let left = node.left.as_ref().map(|_| Box::new(done.pop().unwrap()));
+            done.push(TreeNode {
+                id: node.id.clone(),
+                left,
+                right,
+                stats: node.stats,
+                chained: node.chained,
+            });
+        }
+        done.pop().unwrap()
+    }
+}
+
+impl Drop for TreeNode {
+    // The children are detached before being dropped, so that dropping a
+    // chain does not recurse down it
+    fn drop(&mut self) {
+        let mut stack: Vec<Box<TreeNode>> = Vec::new();
+        stack.extend(self.left.take());
+        stack.extend(self.right.take());
+        while let Some(mut node) = stack.pop() {
+            stack.extend(node.left.take());
+            stack.extend(node.right.take());
+        }
+    }
+}
+
+/// Options controlling how trees are written in the Newick format
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NewickOptions<'a> {
+    /// label the leaves by these transcript names rather than their indices
+    pub tnames: Option<&'a [String]>,
+    /// write the reduction in infRV from a node to its parent as the
+    /// length of the branch between them
+    pub branch_lengths: bool,
+    /// write the infRV and the mean count of each node as NHX tags
+    pub nhx: bool,
 }
 
 impl TreeNode {
@@ -14,14 +99,21 @@ impl TreeNode {
             id,
             left: None,
             right: None,
+            stats: None,
+            chained: false,
         }
     }
 
+    /// Joins two trees under a new node. Its id is left empty rather than
+    /// built from those of its children, which would take quadratic space
+    /// along the chains of the collapse order (see `legacy_id`).
     pub fn create_group(n1: TreeNode, n2: TreeNode) -> TreeNode {
         TreeNode {
-            id: format!("{}_{}", n1.id, n2.id),
+            id: String::new(),
             left: Some(Box::new(n1)),
             right: Some(Box::new(n2)),
+            stats: None,
+            chained: false,
         }
     }
 
@@ -30,8 +122,9 @@ impl TreeNode {
         let txps: Vec<&str> = s.split('_').collect();
         let mut root = TreeNode::create_leaf(txps[0].to_string());
 
-        for i in txps.iter().skip(1) {
+        for (j, i) in txps.iter().enumerate().skip(1) {
             let other = TreeNode::create_leaf(i.to_string());
+            root.chained = j > 1;
             root = TreeNode::create_group(root, other);
         }
         root
@@ -48,174 +141,376 @@ impl TreeNode {
         root
     }
 
-    // Might Borrow the implementation defined in
-    // https://sachanganesh.com/programming/graph-tree-traversals-in-rust/
-    pub fn traverse_tree(&self) {
-        if self.left.is_some() {
-            println!("root is {}", self.id);
-            let d = self.left.as_ref().unwrap();
-            println!("left is {}", d.id);
-            self.left.as_ref().unwrap().traverse_tree();
+    pub fn is_leaf(&self) -> bool {
+        self.left.is_none() && self.right.is_none()
+    }
+
+    /// Returns the nodes of the tree in postorder, without recursing
+    pub fn postorder(&self) -> Vec<&TreeNode> {
+        let mut order = Vec::new();
+        let mut stack = vec![(self, false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded || node.is_leaf() {
+                order.push(node);
+            } else {
+                stack.push((node, true));
+                stack.extend(node.right.as_deref().map(|r| (r, false)));
+                stack.extend(node.left.as_deref().map(|l| (l, false)));
+            }
         }
-        if self.right.is_some() {
-            println!("root is {}", self.id);
-            let d = self.right.as_ref().unwrap();
-            println!("right is {}", d.id);
-            self.right.as_ref().unwrap().traverse_tree();
+        order
+    }
+
+    /// Returns the sorted transcripts at the leaves of the tree
+    pub fn leaves(&self) -> Vec<u32> {
+        let mut leaves: Vec<u32> = self
+            .postorder()
+            .iter()
+            .filter(|node| node.is_leaf())
+            .map(|node| {
+                node.id
+                    .parse::<u32>()
+                    .expect("leaf ids should be transcript indices")
+            })
+            .collect();
+        leaves.sort_unstable();
+        leaves
+    }
+
+    /// Returns the id of a node as the JSON collapse order of earlier
+    /// versions stores it, the ids of its leaves joined by `_` from left to
+    /// right, whether or not the node was built with it
+    pub fn legacy_id(&self) -> String {
+        if !self.id.is_empty() || self.is_leaf() {
+            return self.id.clone();
         }
+        self.postorder()
+            .iter()
+            .filter(|node| node.is_leaf())
+            .map(|node| node.id.as_str())
+            .collect::<Vec<&str>>()
+            .join("_")
     }
-}
 
-// Takes the set of all txps in the tree, txps in the child node (edge from current root) and returns the corresponding bipartition as string
-// Criteria - Each bipartition is first sorted and then returned as parent and child
-pub fn get_bipart_split(par: &HashSet<u32>, child: &str) -> String {
-    let child_set: HashSet<u32> = child
-        .split('_')
-        .map(|x| x.parse::<u32>().unwrap())
-        .collect();
-    //println!("{:?}",child_set);
-    let mut req_par: Vec<u32> = par.difference(&child_set).cloned().collect();
-    req_par.sort();
-    //println!("{:?}", req_par);
-    //https://stackoverflow.com/questions/53115999/what-is-the-idiomatic-way-of-converting-a-vec-of-references-to-a-vec-of-values
-    //https://hermanradtke.com/2015/06/22/effectively-using-iterators-in-rust.html
-    let mut child_set: Vec<u32> = child_set.iter().cloned().collect(); //Why iter not into_iter, clones
-    child_set.sort();
-    if req_par.len() == 1 && child_set.len() == 1 {
-        req_par.push(child_set[0]);
-        req_par.sort();
-        return format!("{}bp{}", req_par[0], req_par[1]);
-    }
-    let (req_par, child_set) = if req_par.len() > child_set.len() {
-        (req_par, child_set)
-    } else {
-        (child_set, req_par)
-    };
-    //vec![req_par, child_set]
-    let par_str: String = req_par
-        .iter()
-        .map(|v| v.to_string())
-        .collect::<Vec<String>>()
-        .join("_");
-    let child_str: String = child_set
-        .iter()
-        .map(|v| v.to_string())
-        .collect::<Vec<String>>()
-        .join("_");
+    /// Encodes the tree as a postorder sequence of leaf indices and
+    /// `POSTORDER_MERGE` or `POSTORDER_CHAIN` tokens, without recursing.
+    pub fn to_postorder(&self) -> Vec<u32> {
+        self.postorder()
+            .iter()
+            .map(|node| match node.is_leaf() {
+                true => node
+                    .id
+                    .parse::<u32>()
+                    .expect("leaf ids should be transcript indices"),
+                false if node.chained => POSTORDER_CHAIN,
+                false => POSTORDER_MERGE,
+            })
+            .collect()
+    }
+
+    /// Returns the statistics of the nodes in postorder, or an empty vector
+    /// if some node has none
+    pub fn postorder_stats(&self) -> Vec<NodeStats> {
+        self.postorder()
+            .iter()
+            .map(|node| node.stats)
+            .collect::<Option<Vec<NodeStats>>>()
+            .unwrap_or_default()
+    }
 
-    format!("{}bp{}", child_str, par_str)
+    /// Builds the tree back from its postorder encoding, along with the
+    /// statistics of its nodes if `stats` is not empty
+    pub fn from_postorder(order: &[u32], stats: &[NodeStats]) -> TreeNode {
+        assert!(
+            stats.is_empty() || stats.len() == order.len(),
+            "malformed postorder tree statistics"
+        );
+        let mut stack: Vec<TreeNode> = Vec::new();
+        for (i, &t) in order.iter().enumerate() {
+            let mut node = if t == POSTORDER_MERGE || t == POSTORDER_CHAIN {
+                let r = stack.pop().expect("malformed postorder tree");
+                let l = stack.pop().expect("malformed postorder tree");
+                let mut node = TreeNode::create_group(l, r);
+                node.chained = t == POSTORDER_CHAIN;
+                node
+            } else {
+                TreeNode::create_leaf(t.to_string())
+            };
+            node.stats = stats.get(i).copied();
+            stack.push(node);
+        }
+        assert!(stack.len() == 1, "malformed postorder tree");
+        stack.pop().unwrap()
+    }
 }
 
-// pub fn bipart_splits_writer(split_file: &mut File, split: String, ) -> Result<bool,io::Error> {
-//     writeln!
+/// A node of a `TreeArena`, referring to its children by their index in
+/// the arena
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaNode {
+    /// the transcript of a leaf, unused for internal nodes
+    pub txp: u32,
+    pub children: Option<(usize, usize)>,
+    pub stats: Option<NodeStats>,
+    /// see `TreeNode::chained`
+    pub chained: bool,
+}
 
-// }
+/// The group trees of a run, stored in a single arena in which merging two
+/// trees adds a single node instead of copying them. `roots` holds the root
+/// of each tree; while grouping, `roots[i]` is the tree of the group of
+/// transcript `i`.
+#[derive(Debug, Clone, Default)]
+pub struct TreeArena {
+    pub nodes: Vec<ArenaNode>,
+    pub roots: Vec<usize>,
+}
 
-pub fn compute_bipart_count(
-    node: &TreeNode,
-    bp_map: &mut HashMap<String, u32>,
-    dir_bp_map: &mut HashMap<String, u32>,
-    root_set: &HashSet<u32>,
-    g_bipart: &mut Vec<String>,
-) {
-    if node.left.is_some() {
-        //println!("root is {}", node.id);
-        let split = get_bipart_split(root_set, &node.left.as_ref().unwrap().id);
-        if !dir_bp_map.contains_key(&split) {
-            g_bipart.push(split.clone());
-            dir_bp_map.insert(split.clone(), 1);
-            let count = bp_map.entry(split).or_insert(0);
-            *count += 1;
+impl TreeArena {
+    /// Creates an arena holding a leaf for each of `ntxps` transcripts, the
+    /// tree of its own group
+    pub fn with_leaves(ntxps: usize) -> TreeArena {
+        let mut arena = TreeArena::default();
+        for t in 0..ntxps {
+            let leaf = arena.push_leaf(t as u32);
+            arena.roots.push(leaf);
         }
-        //println!("left is {}", d.id);
-        compute_bipart_count(
-            node.left.as_ref().unwrap(),
-            bp_map,
-            dir_bp_map,
-            root_set,
-            g_bipart,
-        );
+        arena
+    }
+
+    fn push_leaf(&mut self, txp: u32) -> usize {
+        self.nodes.push(ArenaNode {
+            txp,
+            children: None,
+            stats: None,
+            chained: false,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn push_group(&mut self, left: usize, right: usize) -> usize {
+        self.nodes.push(ArenaNode {
+            txp: 0,
+            children: Some((left, right)),
+            stats: None,
+            chained: false,
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Merges the tree of group `target` into that of group `source`
+    pub fn merge(&mut self, source: usize, target: usize) {
+        self.roots[source] = self.push_group(self.roots[source], self.roots[target]);
+    }
+
+    /// Returns the root node of the tree of group `i`
+    pub fn root_mut(&mut self, i: usize) -> &mut ArenaNode {
+        let root = self.roots[i];
+        &mut self.nodes[root]
     }
-    if node.right.is_some() {
-        //println!("root is {}", node.id);
-        let split = get_bipart_split(root_set, &node.right.as_ref().unwrap().id);
-        if !dir_bp_map.contains_key(&split) {
-            g_bipart.push(split.clone());
-            dir_bp_map.insert(split.clone(), 1);
-            let count = bp_map.entry(split).or_insert(0);
-            *count += 1;
+
+    pub fn is_leaf(&self, v: usize) -> bool {
+        self.nodes[v].children.is_none()
+    }
+
+    /// Returns the nodes below `root` in postorder, without recursing
+    pub fn postorder(&self, root: usize) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut stack = vec![(root, false)];
+        while let Some((v, expanded)) = stack.pop() {
+            match self.nodes[v].children {
+                Some((l, r)) if !expanded => {
+                    stack.push((v, true));
+                    stack.push((r, false));
+                    stack.push((l, false));
+                }
+                _ => order.push(v),
+            }
         }
-        compute_bipart_count(
-            node.right.as_ref().unwrap(),
-            bp_map,
-            dir_bp_map,
-            root_set,
-            g_bipart,
+        order
+    }
+
+    /// Returns the sorted transcripts at the leaves below `root`
+    pub fn leaves(&self, root: usize) -> Vec<u32> {
+        let mut leaves: Vec<u32> = self
+            .postorder(root)
+            .into_iter()
+            .filter(|v| self.is_leaf(*v))
+            .map(|v| self.nodes[v].txp)
+            .collect();
+        leaves.sort_unstable();
+        leaves
+    }
+
+    /// Encodes the tree below `root` as `TreeNode::to_postorder` does
+    pub fn to_postorder(&self, root: usize) -> Vec<u32> {
+        self.postorder(root)
+            .into_iter()
+            .map(|v| match self.nodes[v] {
+                ArenaNode {
+                    children: None,
+                    txp,
+                    ..
+                } => txp,
+                ArenaNode { chained: true, .. } => POSTORDER_CHAIN,
+                _ => POSTORDER_MERGE,
+            })
+            .collect()
+    }
+
+    /// Returns the statistics of the nodes below `root` in postorder, or an
+    /// empty vector if some node has none
+    pub fn postorder_stats(&self, root: usize) -> Vec<NodeStats> {
+        self.postorder(root)
+            .into_iter()
+            .map(|v| self.nodes[v].stats)
+            .collect::<Option<Vec<NodeStats>>>()
+            .unwrap_or_default()
+    }
+
+    /// Adds a tree given by its postorder encoding as a new root
+    pub fn push_postorder(&mut self, order: &[u32], stats: &[NodeStats]) -> usize {
+        assert!(
+            stats.is_empty() || stats.len() == order.len(),
+            "malformed postorder tree statistics"
         );
+        let mut stack: Vec<usize> = Vec::new();
+        for (i, &t) in order.iter().enumerate() {
+            let v = if t == POSTORDER_MERGE || t == POSTORDER_CHAIN {
+                let r = stack.pop().expect("malformed postorder tree");
+                let l = stack.pop().expect("malformed postorder tree");
+                let v = self.push_group(l, r);
+                self.nodes[v].chained = t == POSTORDER_CHAIN;
+                v
+            } else {
+                self.push_leaf(t)
+            };
+            self.nodes[v].stats = stats.get(i).copied();
+            stack.push(v);
+        }
+        assert!(stack.len() == 1, "malformed postorder tree");
+        self.roots.push(stack[0]);
+        stack[0]
     }
+
+    /// Adds a tree as a new root
+    pub fn push_tree(&mut self, tree: &TreeNode) -> usize {
+        let stats = tree.postorder_stats();
+        self.push_postorder(&tree.to_postorder(), &stats)
+    }
+
+    /// Maps the sorted transcripts of each tree to its root
+    pub fn group_roots(&self) -> HashMap<Vec<u32>, usize> {
+        self.roots.iter().map(|r| (self.leaves(*r), *r)).collect()
+    }
+}
+
+/// Returns the id of a group of transcripts, given sorted, as used in the
+/// outputs
+pub fn group_id(txps: &[u32]) -> String {
+    txps.iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<String>>()
+        .join("_")
 }
 
-pub fn compute_bipart_count2(
-    node: &TreeNode,
-    bp_map: &mut HashMap<String, u32>,
-    dir_bp_map: &mut HashMap<String, u32>,
-) {
-    if node.left.is_some() {
-        //println!("root is {}", node.id);
-        //let split = get_bipart_split(root_set, &node.left.as_ref().unwrap().id);
-        let bpart = sort_group_id(&node.left.as_ref().unwrap().id);
-        if dir_bp_map.contains_key(&bpart) {
-            println!("bpart repeats in left");
+/// Counts the bipartitions of the tree of `arena` below `root` into
+/// `dir_bp_map`, each keyed by the sorted transcripts of the clade that
+/// splits it from the rest of the tree, and returns the transcripts of the
+/// tree. The clades are merged bottom-up in a single postorder pass.
+pub fn compute_bipart_count(
+    arena: &TreeArena,
+    root: usize,
+    dir_bp_map: &mut HashMap<Vec<u32>, u32>,
+) -> Vec<u32> {
+    // the sorted transcripts below the finished nodes whose parent is yet
+    // to be reached
+    let mut below: Vec<Vec<u32>> = Vec::new();
+    for v in arena.postorder(root) {
+        if arena.is_leaf(v) {
+            below.push(vec![arena.nodes[v].txp]);
+            continue;
         }
-        //    g_bipart.push(split.clone());
-        dir_bp_map.insert(bpart.clone(), 1);
-        let count = bp_map.entry(bpart).or_insert(0);
-        *count += 1;
-
-        //println!("left is {}", d.id);
-        //compute_bipart_count(node.left.as_ref().unwrap(), bp_map, dir_bp_map, root_set, g_bipart);
-        compute_bipart_count2(node.left.as_ref().unwrap(), bp_map, dir_bp_map);
-    }
-    if node.right.is_some() {
-        //println!("root is {}", node.id);
-        //let split = get_bipart_split(root_set, &node.right.as_ref().unwrap().id);
-        let bpart = sort_group_id(&node.right.as_ref().unwrap().id);
-        if dir_bp_map.contains_key(&bpart) {
-            println!("bpart repeats in right");
+        let (l, r) = arena.nodes[v].children.unwrap();
+        let right = below.pop().unwrap();
+        let left = below.pop().unwrap();
+        let merged = merge_sorted(&left, &right);
+        for (side, child, bpart) in [("left", l, left), ("right", r, right)] {
+            // the chains resolving a multifurcation are not bipartitions
+            if !arena.nodes[child].chained {
+                if dir_bp_map.contains_key(&bpart) {
+                    warn!("bpart {} repeats in {}", group_id(&bpart), side);
+                }
+                dir_bp_map.insert(bpart, 1);
+            }
         }
-        //g_bipart.push(split.clone());
-        dir_bp_map.insert(bpart.clone(), 1);
-        let count = bp_map.entry(bpart).or_insert(0);
-        *count += 1;
-
-        //compute_bipart_count(node.right.as_ref().unwrap(), bp_map, dir_bp_map, root_set, g_bipart);
-        compute_bipart_count2(node.right.as_ref().unwrap(), bp_map, dir_bp_map);
+        below.push(merged);
     }
+    below.pop().unwrap()
 }
 
-pub fn sort_group_id(group: &str) -> String {
-    let mut tps: Vec<u32> = group
-        .split('_')
-        .map(|x| x.parse::<u32>().unwrap())
-        .collect();
-    tps.sort();
-    let group = tps
-        .iter()
-        .map(|x| x.to_string())
-        .collect::<Vec<String>>()
-        .join("_");
-    group
+fn merge_sorted(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] <= b[j] {
+            merged.push(a[i]);
+            i += 1;
+        } else {
+            merged.push(b[j]);
+            j += 1;
+        }
+    }
+    merged.extend_from_slice(&a[i..]);
+    merged.extend_from_slice(&b[j..]);
+    merged
 }
 
-pub fn get_binary_rooted_newick_string(node: &TreeNode) -> String {
-    if node.left.is_none() && node.right.is_none() {
-        node.id.clone()
+/// Quotes a leaf label if it contains characters that are not safe to
+/// write unquoted in a Newick string (e.g. the `|` in GENCODE headers)
+pub fn newick_label(name: &str) -> String {
+    if name
+        .chars()
+        .any(|c| c.is_whitespace() || "()[]':;,|".contains(c))
+    {
+        format!("'{}'", name.replace('\'', "''"))
     } else {
-        let l = get_binary_rooted_newick_string(node.left.as_ref().unwrap());
-        let r = get_binary_rooted_newick_string(node.right.as_ref().unwrap());
-        format!("({},{})", l, r)
+        name.to_string()
+    }
+}
+
+/// Returns the label of a leaf, its transcript name if `tnames` is given
+/// and its index otherwise
+pub fn leaf_label(txp: u32, tnames: Option<&[String]>) -> String {
+    match tnames {
+        Some(names) => newick_label(&names[txp as usize]),
+        None => txp.to_string(),
+    }
+}
+
+/// Returns the branch length and NHX tags of a node with statistics
+/// `stats`, requested by `opts`, given the statistics of its parent
+pub fn node_annotation(
+    stats: Option<&NodeStats>,
+    parent: Option<&NodeStats>,
+    opts: &NewickOptions,
+) -> String {
+    let mut annot = String::new();
+    if let Some(stats) = stats {
+        if let (true, Some(p)) = (opts.branch_lengths, parent) {
+            // a negative reduction is written as a zero length branch
+            annot.push_str(&format!(":{:.6}", (stats.infrv - p.infrv).max(0.0)));
+        }
+        if opts.nhx {
+            annot.push_str(&format!(
+                "[&&NHX:infRV={:.6}:mean={:.4}]",
+                stats.infrv, stats.mean
+            ));
+        }
     }
+    annot
 }
+
 // fn main () {
 //     let x = TreeNode {id:"12".to_string(), left: None, right: None};
 //     // let y = TreeNode {id:"12".to_string(), left: None, right: None};
@@ -261,3 +556,128 @@ pub fn get_binary_rooted_newick_string(node: &TreeNode) -> String {
 //     //     r: Some(Box::new(Node { val: "z", l: None, r: None })),
 //     // });
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const M: u32 = POSTORDER_MERGE;
+    const C: u32 = POSTORDER_CHAIN;
+
+    fn stats(n: usize) -> Vec<NodeStats> {
+        (0..n)
+            .map(|i| NodeStats {
+                infrv: i as f64,
+                mean: 10.0 * i as f64,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tree_node_round_trips_postorder() {
+        // ((2,0,1),3), where (2,0,1) is a chain
+        let order = vec![2, 0, C, 1, M, 3, M];
+        let tree = TreeNode::from_postorder(&order, &stats(7));
+        assert_eq!(tree.to_postorder(), order);
+        assert_eq!(tree.postorder_stats(), stats(7));
+        assert!(tree.left.as_ref().unwrap().left.as_ref().unwrap().chained);
+
+        let tree = TreeNode::from_postorder(&order, &[]);
+        assert_eq!(tree.to_postorder(), order);
+        assert!(tree.postorder_stats().is_empty());
+    }
+
+    #[test]
+    fn arena_round_trips_postorder() {
+        let mut arena = TreeArena::default();
+        let a = arena.push_postorder(&[4, 2, M, 7, M], &stats(5));
+        let b = arena.push_postorder(&[1, 0, C, 3, M], &[]);
+        let c = arena.push_postorder(&[5], &[]);
+        assert_eq!(arena.roots, vec![a, b, c]);
+        assert_eq!(arena.to_postorder(a), vec![4, 2, M, 7, M]);
+        assert_eq!(arena.postorder_stats(a), stats(5));
+        assert_eq!(arena.to_postorder(b), vec![1, 0, C, 3, M]);
+        assert!(arena.postorder_stats(b).is_empty());
+        assert_eq!(arena.leaves(a), vec![2, 4, 7]);
+        assert_eq!(arena.to_postorder(c), vec![5]);
+
+        let tree = TreeNode::from_postorder(&arena.to_postorder(a), &arena.postorder_stats(a));
+        let d = arena.push_tree(&tree);
+        assert_eq!(arena.to_postorder(d), arena.to_postorder(a));
+        assert_eq!(arena.postorder_stats(d), stats(5));
+    }
+
+    #[test]
+    fn arena_merges_groups() {
+        let mut arena = TreeArena::with_leaves(4);
+        arena.merge(2, 3);
+        arena.merge(0, 2);
+        assert_eq!(arena.to_postorder(arena.roots[0]), vec![0, 2, 3, M, M]);
+        assert_eq!(arena.to_postorder(arena.roots[1]), vec![1]);
+        let groups = arena.group_roots();
+        assert_eq!(groups[&vec![0, 2, 3]], arena.roots[0]);
+        assert_eq!(groups[&vec![1]], 1);
+    }
+
+    #[test]
+    fn encodes_deep_trees_without_recursing() {
+        let n = 200_000;
+        let mut order = vec![0];
+        for t in 1..n {
+            order.push(t);
+            order.push(M);
+        }
+        let mut arena = TreeArena::default();
+        let root = arena.push_postorder(&order, &[]);
+        assert_eq!(arena.to_postorder(root), order);
+        assert_eq!(arena.leaves(root).len(), n as usize);
+    }
+
+    #[test]
+    fn counts_bipartitions_bottom_up() {
+        let mut arena = TreeArena::default();
+        // (((3,1),(0,2,4)),5) where (0,2,4) is a chain
+        let root = arena.push_postorder(&[3, 1, M, 0, 2, C, 4, M, M, 5, M], &[]);
+        let mut bparts = HashMap::new();
+        let leaves = compute_bipart_count(&arena, root, &mut bparts);
+        assert_eq!(leaves, vec![0, 1, 2, 3, 4, 5]);
+        let mut keys: Vec<Vec<u32>> = bparts.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                vec![0],
+                vec![0, 1, 2, 3, 4],
+                vec![0, 2, 4],
+                vec![1],
+                vec![1, 3],
+                vec![2],
+                vec![3],
+                vec![4],
+                vec![5],
+            ]
+        );
+        // the chained node is left out
+        assert!(!bparts.contains_key(&vec![0, 2]));
+    }
+
+    #[test]
+    fn builds_group_trees_without_ids() {
+        let tree = TreeNode::from_postorder(&[2, 0, M, 1, M], &[]);
+        assert!(tree.id.is_empty());
+        assert_eq!(tree.legacy_id(), "2_0_1");
+        assert_eq!(tree.leaves(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "malformed postorder tree")]
+    fn rejects_merges_without_children() {
+        TreeArena::default().push_postorder(&[0, M], &[]);
+    }
+
+    #[test]
+    #[should_panic(expected = "malformed postorder tree")]
+    fn rejects_unmerged_subtrees() {
+        TreeNode::from_postorder(&[0, 1], &[]);
+    }
+}
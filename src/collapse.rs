@@ -1,202 +1,129 @@
+use log::{debug, info};
 use petgraph::unionfind::UnionFind;
-use serde::Deserialize;
 use std::collections::{HashMap, HashSet};
 use std::fs::*;
+use std::io;
 use std::io::Write;
-use std::io::{self, BufReader};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
 extern crate serde_json;
 extern crate serde_pickle;
-extern crate serde_stacker;
 
 use std::ffi::CString;
 use std::os::raw::{c_char, c_int};
 
-use crate::binary_tree::{get_binary_rooted_newick_string, sort_group_id, TreeNode};
-use crate::salmon_types::{ConsensusFileList, FileList};
+use rand::distributions::{Distribution, Uniform};
+use rand::seq::index::sample;
+use rand_core::SeedableRng;
+use rand_pcg::Pcg64;
+
+use crate::binary_tree::{group_id, NewickOptions, TreeArena};
+use crate::compare::TreeClades;
+use crate::cut::FlatTree;
+use crate::nary_tree::NaryNode;
+use crate::newick::{parse_newick_trees, relabel_newick};
+use crate::salmon_types::ConsensusFileList;
+use crate::util::{names_writer, progress_bar, txp_names};
 
 extern "C" {
     pub fn run_cons(argsc: c_int, argsv: *const *const c_char) -> c_int;
 }
 
-#[allow(unused_variables)]
-fn create_union_find(g: &[String], ntxps: usize) -> UnionFind<usize> {
+// consense keeps its state in globals, so only one runs at a time
+static CONSENSE: Mutex<()> = Mutex::new(());
+
+// The union of the groups of transcripts `groups`
+fn create_union_find(groups: &[Vec<u32>], ntxps: usize) -> UnionFind<usize> {
     let mut unionfind_struct = UnionFind::new(ntxps);
-    let mut visited: Vec<i32> = vec![-1; ntxps];
-    let mut count = 0;
-    for group in g.iter() {
-        let g_set: Vec<usize> = group
-            .clone()
-            .split('_')
-            .map(|x| x.parse::<usize>().unwrap())
-            .collect();
-        let source = g_set[0];
-        if visited[source] == -1 {
-            count += 1;
-            visited[source] = 0
-        }
-        for t in g_set.iter().skip(1) {
-            unionfind_struct.union(source, *t);
-            if visited[*t] == -1 {
-                count += 1;
-                visited[*t] = 0
-            }
+    for g in groups.iter() {
+        for t in g.iter().skip(1) {
+            unionfind_struct.union(g[0] as usize, *t as usize);
         }
     }
-    // if count != ntxps {
-    //     panic!("The number of expected transcripts {} do not match the counted transcripts from the groups{}", ntxps, count);
-    // }
     unionfind_struct
 }
 
-fn get_merged_bparts(
-    groups: &HashMap<usize, Vec<usize>>,
-    all_groups_bpart: &HashMap<String, HashMap<String, u32>>,
-    uf: &UnionFind<usize>,
-) -> HashMap<String, HashMap<String, u32>> {
-    let all_groups: Vec<String> = all_groups_bpart.keys().cloned().collect();
-    let mut merged_bparts: HashMap<String, HashMap<String, u32>> = HashMap::new();
-    for old_g in all_groups.iter() {
-        let f_txp = old_g
-            .clone()
-            .split('_')
-            .map(|x| x.parse::<usize>().unwrap())
-            .collect::<Vec<usize>>()[0];
-        let g_ind = uf.find(f_txp); //the vertex index
-        let strings: Vec<String> = groups[&g_ind].iter().map(|n| n.to_string()).collect();
-        let m_group = strings.join("_").to_string();
-        let m_bpart_key = merged_bparts
-            .entry(sort_group_id(&m_group.clone()))
-            .or_default();
-
-        for (b_part, count) in all_groups_bpart.get(&old_g.clone()).unwrap().iter() {
-            let c_count = m_bpart_key.entry(b_part.clone()).or_insert(0);
-            *c_count += count;
-        }
-    }
-    merged_bparts
+// The groups of the samples, merged where they share transcripts, along
+// with the groups they are merged from
+fn merged_groups(
+    samp_group_trees: &[&(TreeArena, HashMap<Vec<u32>, usize>)],
+    ntxps: usize,
+) -> HashMap<Vec<u32>, Vec<Vec<u32>>> {
+    let mut all_groups: Vec<Vec<u32>> = samp_group_trees
+        .iter()
+        .flat_map(|(_, roots)| roots.keys().cloned())
+        .collect::<HashSet<Vec<u32>>>()
+        .into_iter()
+        .collect();
+    all_groups.sort_unstable();
+    let g_union = create_union_find(&all_groups, ntxps);
+    find_groups_in_merged(&all_groups, &g_union)
 }
 
-#[allow(dead_code)]
+// Maps each merged group, the sorted transcripts of the groups of
+// `all_groups` sharing a root in `uf`, to these groups
 fn find_groups_in_merged(
-    groups: &HashMap<usize, Vec<usize>>,
-    all_groups: &[String],
+    all_groups: &[Vec<u32>],
     uf: &UnionFind<usize>,
-) -> HashMap<String, Vec<String>> {
-    // Given a grouped hashmap that contains all child txps under a main txp (together will form a (merged) group),
-    // vector of all keys of a group and a union find, returns a hashmap containing merged group and old groups
-    let mut merged_groups: HashMap<String, Vec<String>> = HashMap::new();
-    for (j, old_g) in all_groups.iter().enumerate() {
-        let f_txp = old_g
-            .clone()
-            .split('_')
-            .map(|x| x.parse::<usize>().unwrap())
-            .collect::<Vec<usize>>()[0];
-        let g_ind = uf.find(f_txp); //the vertex index
-        let strings: Vec<String> = groups[&g_ind].iter().map(|n| n.to_string()).collect();
-        let m_group = strings.join("_").to_string();
-        merged_groups
-            .entry(m_group)
+) -> HashMap<Vec<u32>, Vec<Vec<u32>>> {
+    let mut by_root: HashMap<usize, Vec<Vec<u32>>> = HashMap::new();
+    for g in all_groups.iter() {
+        by_root
+            .entry(uf.find(g[0] as usize))
             .or_default()
-            .push(all_groups[j].clone());
-    }
-    merged_groups
-}
-
-#[allow(dead_code)]
-pub fn merge_groups(
-    all_groups_bpart: &HashMap<String, HashMap<String, u32>>,
-    ntxps: usize,
-) -> HashMap<String, HashMap<String, u32>> {
-    let all_groups: Vec<String> = all_groups_bpart.keys().cloned().collect();
-    let g_union = create_union_find(&all_groups, ntxps);
-    let mut groups = HashMap::new();
-    for i in 0..ntxps {
-        let root = g_union.find(i);
-        if root != i {
-            groups.entry(root).or_insert_with(|| vec![root]).push(i);
-        }
+            .push(g.clone());
     }
-    get_merged_bparts(&groups, all_groups_bpart, &g_union)
+    by_root
+        .into_values()
+        .map(|old_groups| {
+            let mut merged: Vec<u32> = old_groups.iter().flatten().copied().collect();
+            merged.sort_unstable();
+            merged.dedup();
+            (merged, old_groups)
+        })
+        .collect()
 }
 
-fn comp_diff(m_group: &str, oth_groups: &[String]) -> Vec<usize> {
-    let par_set: HashSet<usize> = m_group
-        .split('_')
-        .map(|x| x.parse::<usize>().unwrap())
-        .collect();
-    let mut child_set: HashSet<usize> = HashSet::new();
-    for g in oth_groups.iter() {
-        let c: HashSet<usize> = g.split('_').map(|x| x.parse::<usize>().unwrap()).collect();
-        child_set.extend(&c);
-    }
-    let diff: Vec<usize> = par_set.difference(&child_set).cloned().collect();
-    diff
+// The transcripts of `m_group` in none of `oth_groups`
+fn comp_diff(m_group: &[u32], oth_groups: &[&Vec<u32>]) -> Vec<u32> {
+    let child_set: HashSet<u32> = oth_groups.iter().flat_map(|g| g.iter().copied()).collect();
+    m_group
+        .iter()
+        .copied()
+        .filter(|t| !child_set.contains(t))
+        .collect()
 }
 
 fn get_group_trees(
-    merged_group: &String,
-    groups: &[String],
-    samp_group_trees: &[HashMap<String, TreeNode>],
+    merged_group: &[u32],
+    groups: &[Vec<u32>],
+    samp_group_trees: &[&(TreeArena, HashMap<Vec<u32>, usize>)],
 ) -> (String, Vec<String>) {
     // Returns a tuple that contains merged group and total sample count along with child group and the number of sample that child group appears in
     let mut samp_nwk: Vec<String> = Vec::new();
-    let mut g_inf = String::from("");
-    for (_i, samp_hash) in samp_group_trees.iter().enumerate() {
-        let mut g_vec: Vec<String> = Vec::new();
-        let mut s_trees: Vec<String> = Vec::new();
+    let mut g_inf = group_id(merged_group);
+    for (_i, (arena, samp_hash)) in samp_group_trees.iter().enumerate() {
+        let mut g_vec: Vec<&Vec<u32>> = Vec::new();
+        // the trees of the sample are joined, along with the transcripts
+        // they miss, under a multifurcating root
+        let mut s_trees: Vec<NaryNode> = Vec::new();
         for g in groups.iter() {
-            if samp_hash.contains_key(g) {
-                g_vec.push(g.clone());
-                //println!("{}\t{:?}",g, samp_group_trees[_i].get(g).unwrap().traverse_tree());
-                s_trees.push(get_binary_rooted_newick_string(
-                    samp_group_trees[_i].get(g).unwrap(),
-                ));
+            if let Some(root) = samp_hash.get(g) {
+                g_vec.push(g);
+                s_trees.push(NaryNode::from_arena(arena, *root));
             }
         }
-        let l = s_trees.len();
-        let mut cur_nwk_trees = match l {
-            0 => "(".to_string(),
-            1 => s_trees[0].clone(),
-            _ => format!("({}", s_trees.join(",")),
-        };
-
-        // let mut cur_nwk_trees = "".to_string();
-        // if length(s_trees) <= 1 {
-        //     cur_nwk_trees.insert_str(0, "(");
-        //     if length(s_trees) == 1 {
-        //         cur_nwk_trees.push_str(&format!("{},",s_trees[0]));
-        //     }
-        // }
-        // let mut cur_nwk_trees = format!("({}",s_trees.join(","));
         let diff = comp_diff(merged_group, &g_vec);
-        if !diff.is_empty() {
-            if l == 1 {
-                cur_nwk_trees.insert(0, '(');
-            }
-            if l > 0 {
-                for v in diff.iter() {
-                    cur_nwk_trees.push_str(&format!(",{}", v));
-                }
-            } else {
-                cur_nwk_trees.push_str(&format!("{}", diff[0].clone()));
-                for v in diff.iter().skip(1) {
-                    cur_nwk_trees.push_str(&format!(",{}", v));
-                }
-            }
-        }
-
-        if l != 1 || !diff.is_empty() {
-            cur_nwk_trees.push_str(");");
-        } else {
-            cur_nwk_trees.push(';');
-        }
-        samp_nwk.push(cur_nwk_trees.clone());
-        let gs = g_vec.join(",");
-        g_inf.push_str(&format!("\t{}\t{}", _i, gs));
+        s_trees.extend(diff.iter().map(|v| NaryNode::create_leaf(*v)));
+        let cur_nwk_trees = match s_trees.len() {
+            1 => s_trees[0].to_newick_string(&NewickOptions::default()),
+            _ => NaryNode::create_group(s_trees).to_newick_string(&NewickOptions::default()),
+        };
+        samp_nwk.push(cur_nwk_trees);
+        let gs: Vec<String> = g_vec.iter().map(|g| group_id(g)).collect();
+        g_inf.push_str(&format!("\t{}\t{}", _i, gs.join(",")));
     }
-    //    println!("{}\t{}", merged_group.clone(), count);
-    g_inf.insert_str(0, &merged_group.to_string());
     (g_inf, samp_nwk)
 }
 
@@ -227,7 +154,10 @@ fn get_cons(out: &String, samp_trees: &[String]) -> String {
 
     let arg_ptrs: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
     let args_len: c_int = arg_ptrs.len() as c_int;
-    unsafe { run_cons(args_len, arg_ptrs.as_ptr()) };
+    {
+        let _consense = CONSENSE.lock().unwrap();
+        unsafe { run_cons(args_len, arg_ptrs.as_ptr()) };
+    }
 
     // // println!("{:?}", samp_trees);
     // let (_code, _output, _error) = run_script::run_script!(
@@ -252,47 +182,41 @@ fn get_cons(out: &String, samp_trees: &[String]) -> String {
     cons_nwk
 }
 
-pub fn use_phylip(dir_paths: &[&str], out: &String, all_groups: &[String], ntxps: usize) {
-    let g_union = create_union_find(all_groups, ntxps);
-    let mut groups = HashMap::new();
-    for i in 0..ntxps {
-        let root = g_union.find(i);
-        if root != i {
-            groups.entry(root).or_insert_with(|| vec![root]).push(i);
+/// Computes the consensus of the group trees of the samples, written to the
+/// files of `file_list_out`, along with the trees of each merged group in
+/// each sample to `mgroup_nwk_files`. PHYLIP works in the directory `out`.
+pub fn use_phylip(
+    samp_group_trees: &[&(TreeArena, HashMap<Vec<u32>, usize>)],
+    mgroup_nwk_files: &[PathBuf],
+    out: &String,
+    file_list_out: &ConsensusFileList,
+    ntxps: usize,
+    tnames: &[String],
+    leaf_names: bool,
+) -> Vec<String> {
+    // PHYLIP truncates long labels, so the trees passed to it are always
+    // labelled by transcript index and relabelled when written out
+    let label = |nwk: String| -> String {
+        if leaf_names {
+            relabel_newick(&nwk, tnames)
+                .unwrap_or_else(|e| panic!("could not relabel the tree {}: {}", nwk, e))
+        } else {
+            nwk
         }
-    }
-    let mg = find_groups_in_merged(&groups, all_groups, &g_union); //merged groups
-    println!("Length of groups after merging {}", mg.len());
-
-    println!("Reading group trees");
-    let mut samp_group_trees: Vec<HashMap<String, TreeNode>> = Vec::new(); //Vector containing group trees from each sample
-    let mut msamp_nwk_file: Vec<File> = Vec::new(); //Vector containing newick trees corresponding to each group
-                                                    // Storing group trees in each sample in an array along with ....
-    for dname in dir_paths.iter() {
-        let compo: Vec<&str> = dname.rsplit('/').collect();
-        let experiment_name = compo[0];
-        let mut prefix_path = out.clone();
-        prefix_path.push('/');
-        prefix_path.push_str(experiment_name);
-
-        let file_list_out = FileList::new(prefix_path);
-        msamp_nwk_file.push(
-            File::create(file_list_out.mgroup_nwk_file).expect("Could not open mgroup nwk file"),
-        );
-        let file = File::open(file_list_out.collapse_order_file);
-        let reader = BufReader::new(file.unwrap());
+    };
+    let mg = merged_groups(samp_group_trees, ntxps);
+    info!("Length of groups after merging {}", mg.len());
 
-        let mut deserializer = serde_json::Deserializer::from_reader(reader);
-        deserializer.disable_recursion_limit();
-        samp_group_trees.push(HashMap::deserialize(&mut deserializer).unwrap());
-        //Pushing hashmap containing trees corresponding to each group
-    }
-    println!("Finished reading group trees");
-    let file_list_out = ConsensusFileList::new(out.clone());
-    let mut mg_file =
-        File::create(file_list_out.merged_groups_file).expect("could not create merged group file");
+    //Vector containing newick trees corresponding to each group
+    let mut msamp_nwk_file: Vec<File> = mgroup_nwk_files
+        .iter()
+        .map(|p| File::create(p).expect("Could not open mgroup nwk file"))
+        .collect();
+    let mut mg_file = File::create(&file_list_out.merged_groups_file)
+        .expect("could not create merged group file");
     let mut clust_nwk_file =
-        File::create(file_list_out.cons_nwk_file).expect("could not create cluster newick file");
+        File::create(&file_list_out.cons_nwk_file).expect("could not create cluster newick file");
+    names_writer(&file_list_out.tree_names_file, tnames).expect("could not write names.tsv");
 
     let inp_nwk_s = format!("{}/inp_tree.nwk", out.clone());
     // let (_code, _output, _error) =
@@ -305,16 +229,286 @@ pub fn use_phylip(dir_paths: &[&str], out: &String, all_groups: &[String], ntxps
     //      "#
     // )
     // .unwrap();
+    let pb = progress_bar(mg.len() as u64, "computing consensus");
+    let mut cons_trees = Vec::with_capacity(mg.len());
     for (merged_group, old_group) in mg {
-        let group_inf = get_group_trees(&merged_group, &old_group, &samp_group_trees); //
+        pb.inc(1);
+        let group_inf = get_group_trees(&merged_group, &old_group, samp_group_trees); //
         let _t = write_file(&mut mg_file, group_inf.0);
-        println!("Computing cluster for group {}", merged_group.clone());
+        debug!("Computing cluster for group {:?}", merged_group);
         for (_i, g) in group_inf.1.iter().enumerate() {
-            let _t = write_file(&mut msamp_nwk_file[_i], g.clone());
+            let _t = write_file(&mut msamp_nwk_file[_i], label(g.clone()));
         }
         //println!("{:?}", group_inf.1);
         //println!("{}", get_cons(out, &group_inf.1));
-        let _t = write_file(&mut clust_nwk_file, get_cons(out, &group_inf.1));
+        let cons = get_cons(out, &group_inf.1);
+        let _t = write_file(&mut clust_nwk_file, label(cons.clone()));
+        cons_trees.push(cons);
     }
+    pb.finish_and_clear();
     let (_code, _output, _error) = run_script::run_script!(&format!("rm {}", inp_nwk_s)).unwrap();
+    cons_trees
+}
+
+/// How the samples of each resample of `consensus --resample` are drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resampling {
+    /// half of the samples, rounded up, without replacement
+    Jackknife,
+    /// as many samples as there are, with replacement
+    Bootstrap,
+}
+
+impl FromStr for Resampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jackknife" => Ok(Resampling::Jackknife),
+            "bootstrap" => Ok(Resampling::Bootstrap),
+            _ => Err(format!("unknown resampling {}", s)),
+        }
+    }
+}
+
+impl Resampling {
+    /// The indices of the samples drawn out of `n` for one resample
+    pub fn draw(self, n: usize, rng: &mut Pcg64) -> Vec<usize> {
+        match self {
+            Resampling::Jackknife => {
+                let mut drawn = sample(rng, n, n.div_ceil(2)).into_vec();
+                drawn.sort_unstable();
+                drawn
+            }
+            Resampling::Bootstrap => Uniform::new(0, n).sample_iter(rng).take(n).collect(),
+        }
+    }
+}
+
+/// The consensus of each merged group of the samples, labelled by
+/// transcript index, without writing any output
+pub fn consensus_of(
+    samp_group_trees: &[&(TreeArena, HashMap<Vec<u32>, usize>)],
+    out: &String,
+    ntxps: usize,
+) -> Vec<String> {
+    let cons_trees = merged_groups(samp_group_trees, ntxps)
+        .iter()
+        .map(|(merged_group, old_group)| {
+            get_cons(
+                out,
+                &get_group_trees(merged_group, old_group, samp_group_trees).1,
+            )
+        })
+        .collect();
+    let (_code, _output, _error) =
+        run_script::run_script!(&format!("rm {}/inp_tree.nwk", out)).unwrap();
+    cons_trees
+}
+
+// The sorted transcripts of the clades of trees labelled by transcript
+// index, their roots included
+fn clades_of(nwk: &str) -> Vec<Vec<u32>> {
+    let names = HashMap::new();
+    let trees = parse_newick_trees(nwk)
+        .unwrap_or_else(|e| panic!("could not parse the consensus tree {}: {}", nwk, e));
+    let mut clades = Vec::new();
+    for tree in trees.iter() {
+        let tree_clades = TreeClades::new(&FlatTree::from_newick(tree), &names)
+            .expect("the consensus trees are labelled by transcript index");
+        let mut root: Vec<usize> = tree_clades.leaves.into_iter().collect();
+        root.sort_unstable();
+        for clade in tree_clades.clades.iter().chain(std::iter::once(&root)) {
+            clades.push(clade.iter().map(|t| *t as u32).collect());
+        }
+    }
+    clades
+}
+
+/// The fraction of `nresamples` resamples of the samples whose consensus
+/// holds each clade of the consensus trees `cons_trees`, by tree
+pub fn clade_support(
+    cons_trees: &[String],
+    samp_group_trees: &[&(TreeArena, HashMap<Vec<u32>, usize>)],
+    resampling: Resampling,
+    nresamples: usize,
+    seed: u64,
+    out: &String,
+    ntxps: usize,
+) -> Vec<Vec<(Vec<u32>, f64)>> {
+    let clades: Vec<Vec<Vec<u32>>> = cons_trees.iter().map(|t| clades_of(t)).collect();
+    let mut counts: HashMap<&[u32], usize> = clades.iter().flatten().map(|c| (&c[..], 0)).collect();
+    let mut rng = Pcg64::seed_from_u64(seed);
+    for r in 0..nresamples {
+        let drawn: Vec<_> = resampling
+            .draw(samp_group_trees.len(), &mut rng)
+            .into_iter()
+            .map(|i| samp_group_trees[i])
+            .collect();
+        info!(
+            "resample {} of {}: consensus of {} samples",
+            r + 1,
+            nresamples,
+            drawn.len()
+        );
+        let found: HashSet<Vec<u32>> = consensus_of(&drawn, out, ntxps)
+            .iter()
+            .flat_map(|t| clades_of(t))
+            .collect();
+        for (clade, count) in counts.iter_mut() {
+            if found.contains(*clade) {
+                *count += 1;
+            }
+        }
+    }
+    clades
+        .iter()
+        .map(|cs| {
+            cs.iter()
+                .map(|c| (c.clone(), counts[&c[..]] as f64 / nresamples as f64))
+                .collect()
+        })
+        .collect()
+}
+
+/// Writes the support of every clade of the consensus trees, numbered by
+/// their line in `cluster_nwk.txt` from 0
+pub fn clade_support_writer(
+    f: &mut dyn Write,
+    support: &[Vec<(Vec<u32>, f64)>],
+    tnames: &[String],
+    leaf_names: bool,
+) -> Result<bool, io::Error> {
+    writeln!(f, "tree\tclade\tntxps\tsupport")?;
+    for (i, clades) in support.iter().enumerate() {
+        for (clade, freq) in clades.iter() {
+            let label = match leaf_names {
+                true => txp_names(clade, tnames),
+                false => group_id(clade),
+            };
+            writeln!(f, "{}\t{}\t{}\t{}", i, label, clade.len(), freq)?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binary_tree::POSTORDER_MERGE as M;
+
+    // The group trees of a sample, each given by its postorder encoding
+    fn sample_trees(trees: &[&[u32]]) -> (TreeArena, HashMap<Vec<u32>, usize>) {
+        let mut arena = TreeArena::default();
+        for t in trees.iter() {
+            arena.push_postorder(t, &[]);
+        }
+        let roots = arena.group_roots();
+        (arena, roots)
+    }
+
+    #[test]
+    fn merges_groups_sharing_transcripts() {
+        let a = sample_trees(&[&[0, 1, M], &[4, 3, M]]);
+        let b = sample_trees(&[&[2, 1, M]]);
+        let merged = merged_groups(&[&a, &b], 5);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[&vec![0, 1, 2]], vec![vec![0, 1], vec![1, 2]]);
+        assert_eq!(merged[&vec![3, 4]], vec![vec![3, 4]]);
+
+        let (inf, trees) = get_group_trees(&[0, 1, 2], &merged[&vec![0, 1, 2]], &[&a, &b]);
+        assert_eq!(inf, "0_1_2\t0\t0_1\t1\t1_2");
+        assert_eq!(trees, vec!["((0,1),2);", "((2,1),0);"]);
+        let (inf, trees) = get_group_trees(&[3, 4], &merged[&vec![3, 4]], &[&a, &b]);
+        assert_eq!(inf, "3_4\t0\t3_4\t1\t");
+        assert_eq!(trees, vec!["(4,3);", "(3,4);"]);
+    }
+
+    #[test]
+    fn draws_resamples_of_the_samples() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        for n in 1..8 {
+            let drawn = Resampling::Jackknife.draw(n, &mut rng);
+            assert_eq!(drawn.len(), n.div_ceil(2));
+            // without replacement, sorted
+            assert!(drawn.windows(2).all(|w| w[0] < w[1]));
+            assert!(drawn.iter().all(|i| *i < n));
+        }
+
+        let mut repeated = false;
+        for _ in 0..20 {
+            let drawn = Resampling::Bootstrap.draw(5, &mut rng);
+            assert_eq!(drawn.len(), 5);
+            assert!(drawn.iter().all(|i| *i < 5));
+            repeated |= drawn.iter().collect::<HashSet<_>>().len() < 5;
+        }
+        assert!(repeated);
+
+        for resampling in [Resampling::Jackknife, Resampling::Bootstrap] {
+            let mut a = Pcg64::seed_from_u64(7);
+            let mut b = Pcg64::seed_from_u64(7);
+            for _ in 0..5 {
+                assert_eq!(resampling.draw(6, &mut a), resampling.draw(6, &mut b));
+            }
+        }
+    }
+
+    #[test]
+    fn supports_clades_by_their_resamples() {
+        let out =
+            std::env::temp_dir().join(format!("treeterminus-collapse-{}", std::process::id()));
+        create_dir_all(&out).unwrap();
+        let out = out.to_str().unwrap().to_string();
+        let a = sample_trees(&[&[0, 1, M, 2, M], &[3, 4, M]]);
+        let b = sample_trees(&[&[1, 2, M, 0, M], &[3, 4, M]]);
+        let samples = [&a, &a, &b];
+        let cons_trees = consensus_of(&samples, &out, 5);
+        let seed = 5;
+        let support = clade_support(
+            &cons_trees,
+            &samples,
+            Resampling::Bootstrap,
+            10,
+            seed,
+            &out,
+            5,
+        );
+
+        // the clade 0_1 is held by the resamples drawing `a` more than once
+        let mut rng = Pcg64::seed_from_u64(seed);
+        let held = (0..10)
+            .filter(|_| {
+                let drawn = Resampling::Bootstrap.draw(3, &mut rng);
+                drawn.iter().filter(|i| **i < 2).count() > 1
+            })
+            .count();
+        assert!(0 < held && held < 10);
+        let mut clades: Vec<(Vec<u32>, f64)> = support.into_iter().flatten().collect();
+        clades.sort_by(|x, y| x.0.cmp(&y.0));
+        assert_eq!(
+            clades,
+            vec![
+                (vec![0, 1], held as f64 / 10.0),
+                (vec![0, 1, 2], 1.0),
+                (vec![3, 4], 1.0),
+            ]
+        );
+
+        let tnames: Vec<String> = (0..5).map(|t| format!("t{}", t)).collect();
+        let mut f = Vec::new();
+        clade_support_writer(&mut f, &[clades[..2].to_vec()], &tnames, true).unwrap();
+        assert_eq!(
+            String::from_utf8(f).unwrap(),
+            format!(
+                "tree\tclade\tntxps\tsupport\n0\tt0,t1\t2\t{}\n0\tt0,t1,t2\t3\t1\n",
+                held as f64 / 10.0
+            )
+        );
+        let mut f = Vec::new();
+        clade_support_writer(&mut f, &[clades[2..].to_vec()], &tnames, false).unwrap();
+        assert_eq!(
+            String::from_utf8(f).unwrap(),
+            "tree\tclade\tntxps\tsupport\n0\t3_4\t2\t1\n"
+        );
+    }
 }
@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::cut::{leaf_index, FlatTree};
+use crate::newick::NewickError;
+
+/// The transcripts at the leaves of a tree and its clades, each clade being
+/// the sorted transcripts below an internal node other than the root
+pub struct TreeClades {
+    pub leaves: HashSet<usize>,
+    pub clades: Vec<Vec<usize>>,
+}
+
+impl TreeClades {
+    pub fn new(tree: &FlatTree, names: &HashMap<String, usize>) -> Result<TreeClades, NewickError> {
+        let mut below: Vec<Vec<usize>> = Vec::with_capacity(tree.len());
+        let mut clades = Vec::new();
+        for v in 0..tree.len() {
+            let leaves = if tree.is_leaf(v) {
+                vec![leaf_index(&tree.labels[v], names)?]
+            } else {
+                let mut leaves: Vec<usize> = tree.children[v]
+                    .iter()
+                    .flat_map(|c| below[*c].iter().copied())
+                    .collect();
+                leaves.sort_unstable();
+                if v != tree.len() - 1 {
+                    clades.push(leaves.clone());
+                }
+                leaves
+            };
+            below.push(leaves);
+        }
+        Ok(TreeClades {
+            leaves: below.pop().unwrap_or_default().into_iter().collect(),
+            clades,
+        })
+    }
+
+    // The clades of the subtree induced by `common`, as sorted transcript
+    // indices, leaving out its root and single leaves
+    fn restricted(&self, common: &HashSet<usize>) -> HashSet<Vec<usize>> {
+        self.clades
+            .iter()
+            .map(|c| {
+                c.iter()
+                    .copied()
+                    .filter(|t| common.contains(t))
+                    .collect::<Vec<usize>>()
+            })
+            .filter(|c| c.len() > 1 && c.len() < common.len())
+            .collect()
+    }
+}
+
+/// The comparison of a tree of the first file to its best match in the
+/// second, the tree sharing the largest fraction of its leaves
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TreeComparison {
+    pub tree_a: Option<usize>,
+    pub tree_b: Option<usize>,
+    pub n_leaves_a: usize,
+    pub n_leaves_b: usize,
+    pub n_shared_leaves: usize,
+    /// clades of the trees restricted to their shared leaves
+    pub n_clades_a: usize,
+    pub n_clades_b: usize,
+    pub n_shared_clades: usize,
+}
+
+impl TreeComparison {
+    pub fn leaf_jaccard(&self) -> f64 {
+        let union = self.n_leaves_a + self.n_leaves_b - self.n_shared_leaves;
+        match union {
+            0 => 0.0,
+            _ => self.n_shared_leaves as f64 / union as f64,
+        }
+    }
+
+    /// The Robinson-Foulds distance, the number of clades found in only
+    /// one of the trees
+    pub fn rf(&self) -> usize {
+        self.n_clades_a + self.n_clades_b - 2 * self.n_shared_clades
+    }
+
+    /// The Robinson-Foulds distance divided by its maximum, 0 for trees
+    /// without clades
+    pub fn normalized_rf(&self) -> f64 {
+        match self.n_clades_a + self.n_clades_b {
+            0 => 0.0,
+            n => self.rf() as f64 / n as f64,
+        }
+    }
+
+    /// The fraction of the clades of the first tree found in the second
+    pub fn shared_clade_fraction(&self) -> f64 {
+        match self.n_clades_a {
+            0 => 1.0,
+            n => self.n_shared_clades as f64 / n as f64,
+        }
+    }
+}
+
+/// Matches every tree of `a` to the tree of `b` with which its leaf set has
+/// the highest Jaccard index, and compares them on their shared leaves.
+/// Trees without a match, on either side, are reported on their own. A
+/// transcript found in several trees of `b` counts towards the overlap with
+/// each of them.
+pub fn compare_trees(a: &[TreeClades], b: &[TreeClades]) -> Vec<TreeComparison> {
+    let mut leaf_to_b: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (j, t) in b.iter().enumerate() {
+        for l in t.leaves.iter() {
+            leaf_to_b.entry(*l).or_default().push(j);
+        }
+    }
+
+    let mut matched_b = vec![false; b.len()];
+    let mut comparisons = Vec::with_capacity(a.len());
+    for (i, ta) in a.iter().enumerate() {
+        let mut overlaps: HashMap<usize, usize> = HashMap::new();
+        for l in ta.leaves.iter() {
+            for j in leaf_to_b.get(l).into_iter().flatten() {
+                *overlaps.entry(*j).or_insert(0) += 1;
+            }
+        }
+        let best = overlaps.iter().max_by(|(j1, o1), (j2, o2)| {
+            let jac =
+                |j: usize, o: usize| o as f64 / (ta.leaves.len() + b[j].leaves.len() - o) as f64;
+            jac(**j1, **o1)
+                .partial_cmp(&jac(**j2, **o2))
+                .unwrap()
+                .then(j2.cmp(j1))
+        });
+        let mut cmp = TreeComparison {
+            tree_a: Some(i),
+            n_leaves_a: ta.leaves.len(),
+            ..Default::default()
+        };
+        if let Some((j, _)) = best {
+            let tb = &b[*j];
+            matched_b[*j] = true;
+            let common: HashSet<usize> = ta.leaves.intersection(&tb.leaves).copied().collect();
+            let ca = ta.restricted(&common);
+            let cb = tb.restricted(&common);
+            cmp.tree_b = Some(*j);
+            cmp.n_leaves_b = tb.leaves.len();
+            cmp.n_shared_leaves = common.len();
+            cmp.n_clades_a = ca.len();
+            cmp.n_clades_b = cb.len();
+            cmp.n_shared_clades = ca.intersection(&cb).count();
+        } else {
+            cmp.n_clades_a = ta.clades.len();
+        }
+        comparisons.push(cmp);
+    }
+    for (j, tb) in b.iter().enumerate() {
+        if !matched_b[j] {
+            comparisons.push(TreeComparison {
+                tree_b: Some(j),
+                n_leaves_b: tb.leaves.len(),
+                n_clades_b: tb.clades.len(),
+                ..Default::default()
+            });
+        }
+    }
+    comparisons
+}
+
+/// The comparison of the two files, over all of their trees
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ComparisonSummary {
+    pub n_trees_a: usize,
+    pub n_trees_b: usize,
+    pub n_matched: usize,
+    pub n_unmatched_a: usize,
+    pub n_unmatched_b: usize,
+    pub mean_leaf_jaccard: f64,
+    pub mean_normalized_rf: f64,
+    /// the Robinson-Foulds distance summed over the matched trees, divided
+    /// by its maximum
+    pub overall_normalized_rf: f64,
+    pub shared_clade_fraction: f64,
+}
+
+pub fn summarize(comparisons: &[TreeComparison]) -> ComparisonSummary {
+    let matched: Vec<&TreeComparison> = comparisons
+        .iter()
+        .filter(|c| c.tree_a.is_some() && c.tree_b.is_some())
+        .collect();
+    let mut summary = ComparisonSummary {
+        n_trees_a: comparisons.iter().filter(|c| c.tree_a.is_some()).count(),
+        n_trees_b: comparisons
+            .iter()
+            .filter_map(|c| c.tree_b)
+            .collect::<HashSet<usize>>()
+            .len(),
+        n_matched: matched.len(),
+        n_unmatched_a: comparisons.iter().filter(|c| c.tree_b.is_none()).count(),
+        n_unmatched_b: comparisons.iter().filter(|c| c.tree_a.is_none()).count(),
+        ..Default::default()
+    };
+    if !matched.is_empty() {
+        let n = matched.len() as f64;
+        summary.mean_leaf_jaccard = matched.iter().map(|c| c.leaf_jaccard()).sum::<f64>() / n;
+        summary.mean_normalized_rf = matched.iter().map(|c| c.normalized_rf()).sum::<f64>() / n;
+        let rf: usize = matched.iter().map(|c| c.rf()).sum();
+        let clades_a: usize = matched.iter().map(|c| c.n_clades_a).sum();
+        let clades_b: usize = matched.iter().map(|c| c.n_clades_b).sum();
+        let shared: usize = matched.iter().map(|c| c.n_shared_clades).sum();
+        if clades_a + clades_b > 0 {
+            summary.overall_normalized_rf = rf as f64 / (clades_a + clades_b) as f64;
+        }
+        summary.shared_clade_fraction = match clades_a {
+            0 => 1.0,
+            _ => shared as f64 / clades_a as f64,
+        };
+    }
+    summary
+}
+
+fn or_na(x: Option<usize>) -> String {
+    x.map_or("NA".to_string(), |x| x.to_string())
+}
+
+/// Writes the comparison of every tree as a row of a table
+pub fn comparison_writer(
+    out: &mut dyn Write,
+    comparisons: &[TreeComparison],
+) -> Result<bool, io::Error> {
+    writeln!(
+        out,
+        "tree_a\ttree_b\tn_leaves_a\tn_leaves_b\tn_shared_leaves\tleaf_jaccard\tn_clades_a\tn_clades_b\tn_shared_clades\trf\tnormalized_rf\tshared_clade_fraction"
+    )?;
+    for c in comparisons.iter() {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            or_na(c.tree_a),
+            or_na(c.tree_b),
+            c.n_leaves_a,
+            c.n_leaves_b,
+            c.n_shared_leaves,
+            c.leaf_jaccard(),
+            c.n_clades_a,
+            c.n_clades_b,
+            c.n_shared_clades,
+            c.rf(),
+            c.normalized_rf(),
+            c.shared_clade_fraction()
+        )?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newick::parse_newick_trees;
+
+    fn clades(nwk: &str) -> Vec<TreeClades> {
+        parse_newick_trees(nwk)
+            .unwrap()
+            .iter()
+            .map(|t| TreeClades::new(&FlatTree::from_newick(t), &HashMap::new()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn identical_trees_have_no_distance() {
+        let a = clades("(((0,1),2),(3,4));");
+        let c = &compare_trees(&a, &a)[0];
+        assert_eq!((c.n_clades_a, c.n_clades_b, c.n_shared_clades), (3, 3, 3));
+        assert_eq!(c.rf(), 0);
+        assert_eq!(c.normalized_rf(), 0.0);
+        assert_eq!(c.leaf_jaccard(), 1.0);
+        assert_eq!(c.shared_clade_fraction(), 1.0);
+    }
+
+    #[test]
+    fn disjoint_clades_have_the_largest_distance() {
+        let a = clades("((0,1),(2,3));");
+        let b = clades("((0,2),(1,3));");
+        let c = &compare_trees(&a, &b)[0];
+        assert_eq!(c.rf(), 4);
+        assert_eq!(c.normalized_rf(), 1.0);
+        assert_eq!(c.shared_clade_fraction(), 0.0);
+        assert_eq!(c.leaf_jaccard(), 1.0);
+    }
+
+    #[test]
+    fn compares_on_the_shared_leaves() {
+        // restricted to 0, 1 and 2, both trees have the clade (0,1)
+        let a = clades("(((0,1),2),3);");
+        let b = clades("(((0,5),1),2);");
+        let c = &compare_trees(&a, &b)[0];
+        assert_eq!(c.n_shared_leaves, 3);
+        assert_eq!(c.leaf_jaccard(), 0.6);
+        assert_eq!((c.n_clades_a, c.n_clades_b, c.n_shared_clades), (1, 1, 1));
+        assert_eq!(c.rf(), 0);
+    }
+
+    #[test]
+    fn counts_leaves_found_in_several_trees() {
+        let a = clades("(0,1);");
+        let b = clades("(0,1);\n(0,5);");
+        let comparisons = compare_trees(&a, &b);
+        assert_eq!(comparisons[0].tree_b, Some(0));
+        assert_eq!(comparisons[0].n_shared_leaves, 2);
+        assert_eq!(comparisons[0].leaf_jaccard(), 1.0);
+        // the second tree of b is left unmatched
+        assert_eq!(comparisons[1].tree_a, None);
+        assert_eq!(comparisons[1].tree_b, Some(1));
+    }
+
+    #[test]
+    fn summarizes_matched_and_unmatched_trees() {
+        let a = clades("((0,1),2);\n(7,8);");
+        let b = clades("((0,2),1);\n(4,5);");
+        let summary = summarize(&compare_trees(&a, &b));
+        assert_eq!(summary.n_trees_a, 2);
+        assert_eq!(summary.n_trees_b, 2);
+        assert_eq!(summary.n_matched, 1);
+        assert_eq!(summary.n_unmatched_a, 1);
+        assert_eq!(summary.n_unmatched_b, 1);
+        assert_eq!(summary.mean_leaf_jaccard, 1.0);
+        assert_eq!(summary.overall_normalized_rf, 1.0);
+        assert_eq!(summary.shared_clade_fraction, 0.0);
+    }
+}
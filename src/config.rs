@@ -0,0 +1,458 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use clap::ArgMatches;
+use serde_json::{Map, Value};
+
+/// How a parameter is given on the command line
+pub enum Kind {
+    /// a flag without a value, `true` or `false` in a configuration
+    Flag,
+    /// an option taking a single value
+    Value,
+    /// an option that may be repeated, a list in a configuration
+    Values,
+}
+
+/// The parameters of `group` that a configuration may set, by long name,
+/// besides its `mode`, `sample`, `mean` or `per-sample`
+pub const GROUP_PARAMS: &[(&str, Kind)] = &[
+    ("dir", Kind::Value),
+    ("out", Kind::Value),
+    ("thr", Kind::Flag),
+    ("seed", Kind::Value),
+    ("tolerance", Kind::Value),
+    ("min-spread", Kind::Value),
+    ("min-mean", Kind::Value),
+    ("eq-weight-frac", Kind::Value),
+    ("edge-count-frac", Kind::Value),
+    ("min-replicate-sum", Kind::Value),
+    ("a2t", Kind::Value),
+    ("keep-alleles", Kind::Flag),
+    ("t2g", Kind::Value),
+    ("gene-map", Kind::Value),
+    ("inf_perc", Kind::Value),
+    ("red_perc", Kind::Value),
+    ("infrv", Kind::Value),
+    ("infrv-pseudocount", Kind::Value),
+    ("infrv-offset", Kind::Value),
+    ("aggregate", Kind::Values),
+    ("normalize", Kind::Value),
+    ("cross-gene-red-perc", Kind::Value),
+    ("compress", Kind::Value),
+    ("leaf-labels", Kind::Value),
+    ("branch-lengths", Kind::Flag),
+    ("nhx", Kind::Flag),
+];
+
+/// The parameters that only `group per-sample` takes
+pub const PER_SAMPLE_PARAMS: &[(&str, Kind)] = &[
+    ("jobs", Kind::Value),
+    ("max-memory", Kind::Value),
+    ("consensus", Kind::Flag),
+];
+
+/// The parameters that `run` takes besides those of `group`
+pub const RUN_PARAMS: &[(&str, Kind)] = &[("jobs", Kind::Value), ("max-memory", Kind::Value)];
+
+/// The parameters of `consensus` that a configuration may set
+pub const CONSENSUS_PARAMS: &[(&str, Kind)] = &[
+    ("dirs", Kind::Value),
+    ("out", Kind::Value),
+    ("compress", Kind::Value),
+    ("leaf-labels", Kind::Value),
+    ("samples", Kind::Value),
+    ("resample", Kind::Value),
+    ("resample-method", Kind::Value),
+    ("seed", Kind::Value),
+];
+
+// The short names of the parameters having one
+const SHORTS: &[(&str, &str)] = &[
+    ("dir", "-d"),
+    ("dirs", "-d"),
+    ("out", "-o"),
+    ("min-spread", "-m"),
+    ("inf_perc", "-i"),
+];
+
+const MODES: &[&str] = &["sample", "mean", "per-sample"];
+
+fn params_of(subcommand: &str, mode: Option<&str>) -> Vec<&'static (&'static str, Kind)> {
+    match (subcommand, mode) {
+        ("group", Some("per-sample")) => GROUP_PARAMS.iter().chain(PER_SAMPLE_PARAMS).collect(),
+        ("group", _) => GROUP_PARAMS.iter().collect(),
+        ("run", _) => GROUP_PARAMS.iter().chain(RUN_PARAMS).collect(),
+        ("consensus", _) => CONSENSUS_PARAMS.iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads the parameters of `subcommand` from a TOML or JSON configuration.
+/// They are taken from its `[group]` or `[consensus]` table if it has one,
+/// from its `config` object if it is the parameters log of an earlier run,
+/// and from the top level otherwise.
+pub fn read_config(path: &Path, subcommand: &str) -> Result<Map<String, Value>, io::Error> {
+    let text = fs::read_to_string(path)?;
+    let value: Value = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => {
+            let t: toml::Value = toml::from_str(&text)
+                .map_err(|e| invalid(format!("could not parse {:?}: {}", path, e)))?;
+            serde_json::to_value(t)?
+        }
+        _ => serde_json::from_str(&text)?,
+    };
+    let mut top = match value {
+        Value::Object(m) => m,
+        _ => return Err(invalid(format!("{:?} does not hold a table", path))),
+    };
+    for key in [subcommand, "config"] {
+        if let Some(Value::Object(m)) = top.remove(key) {
+            return Ok(m);
+        }
+    }
+    Ok(top)
+}
+
+fn scalar(key: &str, v: &Value) -> Result<String, io::Error> {
+    match v {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(invalid(format!("the value of {:?} must be a scalar", key))),
+    }
+}
+
+// Whether the parameter `key` is among the command line arguments `args`
+fn given(key: &str, args: &[String]) -> bool {
+    let flag = format!("--{}", key);
+    let short = SHORTS.iter().find(|(k, _)| *k == key).map(|(_, s)| *s);
+    args.iter()
+        .any(|a| *a == flag || a.starts_with(&format!("{}=", flag)) || short == Some(a.as_str()))
+}
+
+/// Turns the parameters of a configuration into command line arguments,
+/// leaving out those given in `cli_args` and the switches it turns off
+pub fn config_args(
+    config: &Map<String, Value>,
+    params: &[&(&str, Kind)],
+    cli_args: &[String],
+) -> Result<Vec<String>, io::Error> {
+    let mut args = Vec::new();
+    for (key, v) in config.iter() {
+        let kind = match params.iter().find(|(name, _)| name == key) {
+            Some((_, kind)) => kind,
+            None => return Err(invalid(format!("unknown parameter {:?}", key))),
+        };
+        if given(key, cli_args) {
+            continue;
+        }
+        let flag = format!("--{}", key);
+        match (kind, v) {
+            (_, Value::Null) => {}
+            (Kind::Flag, Value::Bool(b)) => {
+                if *b && !negated(key, cli_args) {
+                    args.push(flag);
+                }
+            }
+            (Kind::Flag, _) => {
+                return Err(invalid(format!("the value of {:?} must be a boolean", key)))
+            }
+            (Kind::Values, Value::Array(vs)) => {
+                for x in vs.iter() {
+                    args.push(flag.clone());
+                    args.push(scalar(key, x)?);
+                }
+            }
+            _ => {
+                args.push(flag);
+                args.push(scalar(key, v)?);
+            }
+        }
+    }
+    Ok(args)
+}
+
+// The position of the subcommand that may take a configuration, the first
+// argument that is neither a global option nor the value of one
+fn subcommand_position(args: &[String]) -> Option<usize> {
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--log-file" => i += 2,
+            a if a.starts_with('-') => i += 1,
+            "group" | "run" | "consensus" => return Some(i),
+            _ => return None,
+        }
+    }
+    None
+}
+
+// Whether the switch `key` is turned off by `--no-<key>` in `args`
+fn negated(key: &str, args: &[String]) -> bool {
+    args.iter().any(|a| a.strip_prefix("--no-") == Some(key))
+}
+
+/// Inserts the parameters of the configuration given by `--config` right
+/// after the subcommand, but for those given on the command line, which
+/// override them. A switch set in the configuration is turned off by
+/// `--no-<flag>`. The mode of `group` is taken from the configuration when
+/// the command line does not give it.
+pub fn expand_args(args: Vec<String>) -> Result<Vec<String>, io::Error> {
+    let sub = match subcommand_position(&args) {
+        Some(i) => i,
+        None => return Ok(args),
+    };
+    let cli_mode = match args[sub].as_str() {
+        "group" => args
+            .get(sub + 1)
+            .filter(|a| MODES.contains(&a.as_str()))
+            .cloned(),
+        _ => None,
+    };
+    let mut path = None;
+    for (i, a) in args.iter().enumerate().skip(sub + 1) {
+        if a == "--config" {
+            path = args.get(i + 1).cloned();
+            break;
+        } else if let Some(p) = a.strip_prefix("--config=") {
+            path = Some(p.to_string());
+            break;
+        }
+    }
+    let mut config = match path {
+        Some(p) => read_config(Path::new(&p), &args[sub])?,
+        None => Map::new(),
+    };
+    let config_mode = match (args[sub].as_str(), config.remove("mode")) {
+        ("group", Some(Value::String(m))) if MODES.contains(&m.as_str()) => Some(m),
+        ("group", Some(m)) => return Err(invalid(format!("unknown mode {}", m))),
+        _ => None,
+    };
+    let mut expanded = args[..=sub].to_vec();
+    let rest = match cli_mode {
+        Some(_) => sub + 2,
+        None => sub + 1,
+    };
+    let mode = cli_mode.or(config_mode);
+    let params = params_of(&args[sub], mode.as_deref());
+    expanded.extend(mode);
+    expanded.extend(config_args(&config, &params, &args[rest..])?);
+    // clap does not know the negated switches
+    expanded.extend(
+        args[rest..]
+            .iter()
+            .filter(|a| {
+                !params.iter().any(|(name, kind)| {
+                    matches!(kind, Kind::Flag) && a.strip_prefix("--no-") == Some(name)
+                })
+            })
+            .cloned(),
+    );
+    Ok(expanded)
+}
+
+/// The effective parameters of a run, from which it can be configured again
+pub fn effective_config(sub_m: &ArgMatches, params: &[(&str, Kind)]) -> Value {
+    let mut config = Map::new();
+    for (name, kind) in params.iter() {
+        let v = match kind {
+            Kind::Flag => Value::Bool(sub_m.get_flag(name)),
+            Kind::Value => match sub_m.get_one::<String>(name) {
+                Some(x) => Value::String(x.to_string()),
+                None => continue,
+            },
+            Kind::Values => match sub_m.get_many::<String>(name) {
+                Some(xs) => Value::Array(xs.map(|x| Value::String(x.to_string())).collect()),
+                None => continue,
+            },
+        };
+        config.insert(name.to_string(), v);
+    }
+    Value::Object(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn write_config(name: &str, text: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("treeterminus-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let p = dir.join(name);
+        fs::write(&p, text).unwrap();
+        p
+    }
+
+    fn args(xs: &[&str]) -> Vec<String> {
+        xs.iter().map(|x| x.to_string()).collect()
+    }
+
+    // The value following `flag` in `args`, and how many times it is given
+    fn value_of<'a>(args: &'a [String], flag: &str) -> (Option<&'a str>, usize) {
+        let at: Vec<usize> = (0..args.len()).filter(|i| args[*i] == flag).collect();
+        (
+            at.last().and_then(|i| args.get(i + 1)).map(|x| x.as_str()),
+            at.len(),
+        )
+    }
+
+    #[test]
+    fn leaves_arguments_without_config_alone() {
+        let cli = args(&["treeterminus", "group", "sample", "-d", "in", "-o", "out"]);
+        assert_eq!(expand_args(cli.clone()).unwrap(), cli);
+        let cli = args(&["treeterminus", "convert", "--config", "x.toml"]);
+        assert_eq!(expand_args(cli.clone()).unwrap(), cli);
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let p = write_config(
+            "override.toml",
+            "mode = \"mean\"\nred_perc = 0.05\nseed = 3\ndir = \"cfg_in\"\nthr = true\n",
+        );
+        let p = p.to_str().unwrap();
+        let cli = args(&[
+            "treeterminus",
+            "group",
+            "--config",
+            p,
+            "--seed",
+            "7",
+            "-d",
+            "cli_in",
+        ]);
+        let expanded = expand_args(cli).unwrap();
+        assert_eq!(
+            &expanded[..3],
+            &args(&["treeterminus", "group", "mean"])[..]
+        );
+        assert_eq!(value_of(&expanded, "--seed"), (Some("7"), 1));
+        assert_eq!(value_of(&expanded, "--red_perc"), (Some("0.05"), 1));
+        assert_eq!(value_of(&expanded, "-d"), (Some("cli_in"), 1));
+        assert_eq!(value_of(&expanded, "--dir").1, 0);
+        assert_eq!(value_of(&expanded, "--thr").1, 1);
+        // the command line comes last, so that it wins with clap as well
+        assert_eq!(expanded.last().unwrap(), "cli_in");
+    }
+
+    #[test]
+    fn command_line_mode_overrides_config() {
+        let p = write_config(
+            "mode.json",
+            "{\"group\": {\"mode\": \"mean\", \"jobs\": 2}, \"consensus\": {\"seed\": 1}}",
+        );
+        let p = p.to_str().unwrap();
+        let expanded = expand_args(args(&[
+            "treeterminus",
+            "group",
+            "per-sample",
+            "--config",
+            p,
+        ]))
+        .unwrap();
+        assert_eq!(
+            &expanded[..3],
+            &args(&["treeterminus", "group", "per-sample"])[..]
+        );
+        assert_eq!(value_of(&expanded, "--jobs"), (Some("2"), 1));
+
+        // jobs is only a parameter of per-sample
+        assert!(expand_args(args(&["treeterminus", "group", "sample", "--config", p])).is_err());
+
+        let expanded = expand_args(args(&["treeterminus", "consensus", "--config", p])).unwrap();
+        assert_eq!(value_of(&expanded, "--seed"), (Some("1"), 1));
+    }
+
+    #[test]
+    fn rejects_unknown_modes_and_parameters() {
+        let p = write_config("bad_mode.toml", "mode = \"median\"\n");
+        let p = p.to_str().unwrap();
+        assert!(expand_args(args(&["treeterminus", "group", "--config", p])).is_err());
+
+        let p = write_config("bad_param.toml", "[group]\nreads = 3\n");
+        let p = p.to_str().unwrap();
+        assert!(expand_args(args(&["treeterminus", "group", "sample", "--config", p])).is_err());
+
+        let p = write_config("bad_flag.toml", "thr = \"yes\"\n");
+        let p = p.to_str().unwrap();
+        assert!(expand_args(args(&["treeterminus", "group", "sample", "--config", p])).is_err());
+    }
+
+    #[test]
+    fn expands_lists_and_parameter_logs() {
+        let p = write_config(
+            "param_log_file.json",
+            "{\"ntxps\": 40, \"config\": {\"mode\": \"sample\", \"aggregate\": [\"infrv=median\", \"score=max\"], \"nhx\": false}}",
+        );
+        let p = p.to_str().unwrap();
+        let expanded =
+            expand_args(args(&["treeterminus", "group", &format!("--config={}", p)])).unwrap();
+        assert_eq!(
+            &expanded[..3],
+            &args(&["treeterminus", "group", "sample"])[..]
+        );
+        let aggregates: Vec<&str> = expanded
+            .windows(2)
+            .filter(|w| w[0] == "--aggregate")
+            .map(|w| w[1].as_str())
+            .collect();
+        assert_eq!(aggregates, vec!["infrv=median", "score=max"]);
+        assert_eq!(value_of(&expanded, "--nhx").1, 0);
+    }
+
+    #[test]
+    fn negated_switches_override_config() {
+        let p = write_config("switches.toml", "thr = true\nnhx = true\n");
+        let p = p.to_str().unwrap();
+        let expanded = expand_args(args(&[
+            "treeterminus",
+            "group",
+            "sample",
+            "--config",
+            p,
+            "--no-thr",
+        ]))
+        .unwrap();
+        assert_eq!(value_of(&expanded, "--thr").1, 0);
+        assert_eq!(value_of(&expanded, "--no-thr").1, 0);
+        assert_eq!(value_of(&expanded, "--nhx").1, 1);
+
+        // and are dropped without a configuration
+        let expanded = expand_args(args(&["treeterminus", "group", "sample", "--no-nhx"])).unwrap();
+        assert_eq!(expanded, args(&["treeterminus", "group", "sample"]));
+        // but not those of other modes
+        let cli = args(&["treeterminus", "group", "sample", "--no-consensus"]);
+        assert_eq!(expand_args(cli.clone()).unwrap(), cli);
+    }
+
+    #[test]
+    fn finds_the_subcommand_after_the_global_options() {
+        let p = write_config("global.toml", "mode = \"mean\"\n");
+        let p = p.to_str().unwrap();
+        let expanded = expand_args(args(&[
+            "treeterminus",
+            "-q",
+            "--log-file",
+            "run",
+            "group",
+            "--config",
+            p,
+        ]))
+        .unwrap();
+        assert_eq!(
+            &expanded[..6],
+            &args(&["treeterminus", "-q", "--log-file", "run", "group", "mean"])[..]
+        );
+
+        // the value of an option is not a subcommand
+        let cli = args(&["treeterminus", "convert", "-i", "group", "--config", p]);
+        assert_eq!(expand_args(cli.clone()).unwrap(), cli);
+    }
+}
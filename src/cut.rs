@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use log::debug;
+use ndarray::prelude::*;
+
+use crate::binary_tree::NodeStats;
+use crate::newick;
+use crate::newick::{NewickError, NewickNode};
+use crate::util::{infrv_1d, Aggregation, InfRV};
+
+/// The score of a node of the tree, which the cut maximises when summed
+/// over its nodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Objective {
+    /// 1 for a node whose infRV is at most the target. A leaf above the
+    /// target scores -1 and an internal node above it the sum of the scores
+    /// of its leaves, so that its leaves are kept apart rather than merged
+    /// into a node that does not reach the target either.
+    InfRV(f64),
+    /// the count-weighted reduction in infRV over the leaves of a node,
+    /// minus lambda for every leaf merged into it
+    Gain(f64),
+}
+
+impl FromStr for Objective {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.find(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return Err(format!("objective {:?} is not <name>:<value>", s)),
+        };
+        let value = value
+            .parse::<f64>()
+            .map_err(|_| format!("invalid value {:?} for the objective {}", value, name))?;
+        match name {
+            "infrv" => Ok(Objective::InfRV(value)),
+            "gain" => Ok(Objective::Gain(value)),
+            _ => Err(format!(
+                "unknown objective {:?}, expected infrv or gain",
+                name
+            )),
+        }
+    }
+}
+
+/// The level at which the trees are flattened into groups: every node at
+/// the given depth, every largest node with at most the given number of
+/// leaves, or every largest node whose infRV is at most the given value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlattenLevel {
+    Depth(usize),
+    Size(usize),
+    InfRV(f64),
+}
+
+impl FromStr for FlattenLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.find(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return Err(format!("level {:?} is not <name>:<value>", s)),
+        };
+        let invalid = || format!("invalid value {:?} for the level {}", value, name);
+        match name {
+            "depth" => value
+                .parse::<usize>()
+                .map(FlattenLevel::Depth)
+                .map_err(|_| invalid()),
+            "size" => value
+                .parse::<usize>()
+                .map(FlattenLevel::Size)
+                .map_err(|_| invalid()),
+            "infrv" => value
+                .parse::<f64>()
+                .map(FlattenLevel::InfRV)
+                .map_err(|_| invalid()),
+            _ => Err(format!(
+                "unknown level {:?}, expected depth, size or infrv",
+                name
+            )),
+        }
+    }
+}
+
+/// A tree flattened in postorder, so that the root is the last node
+#[derive(Debug, Clone, Default)]
+pub struct FlatTree {
+    /// the labels of the leaves, empty for the internal nodes
+    pub labels: Vec<String>,
+    pub children: Vec<Vec<usize>>,
+    /// the statistics written as NHX tags, if any
+    pub nhx: Vec<Option<NodeStats>>,
+}
+
+impl FlatTree {
+    pub fn from_newick(root: &NewickNode) -> FlatTree {
+        let mut tree = FlatTree::default();
+        // indices of the finished nodes whose parent is yet to be added
+        let mut done: Vec<usize> = Vec::new();
+        let mut stack = vec![(root, false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if !node.is_leaf() && !expanded {
+                stack.push((node, true));
+                stack.extend(node.children.iter().rev().map(|c| (c, false)));
+                continue;
+            }
+            let children = done.split_off(done.len() - node.children.len());
+            done.push(tree.labels.len());
+            tree.labels.push(match node.is_leaf() {
+                true => node.name.clone(),
+                false => String::new(),
+            });
+            tree.children.push(children);
+            tree.nhx.push(node.nhx_stats());
+        }
+        tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_leaf(&self, v: usize) -> bool {
+        self.children[v].is_empty()
+    }
+
+    /// Returns the statistics of all the nodes if they were all written as
+    /// NHX tags
+    pub fn nhx_stats(&self) -> Option<Vec<NodeStats>> {
+        self.nhx.iter().copied().collect()
+    }
+
+    /// Returns the labels of the leaves below `v`
+    pub fn leaf_labels(&self, v: usize) -> Vec<&str> {
+        let mut labels = Vec::new();
+        let mut stack = vec![v];
+        while let Some(u) = stack.pop() {
+            if self.is_leaf(u) {
+                labels.push(self.labels[u].as_str());
+            } else {
+                stack.extend(self.children[u].iter().rev());
+            }
+        }
+        labels
+    }
+}
+
+/// Returns the transcript index of a leaf, labelled either by a name of
+/// `names` or by the index itself (see `newick::leaf_index`)
+pub fn leaf_index(label: &str, names: &HashMap<String, usize>) -> Result<usize, NewickError> {
+    newick::leaf_index(label, names).ok_or_else(|| NewickError {
+        msg: format!("unknown transcript {:?}", label),
+        pos: None,
+    })
+}
+
+/// Computes the statistics of every node from the inferential replicates
+/// of each sample, summing the counts of the leaves below it. Over several
+/// samples the infRV and mean families of `agg` combine them, as in the
+/// mean mode of `group`.
+pub fn gibbs_stats(
+    tree: &FlatTree,
+    names: &HashMap<String, usize>,
+    gibbs_mat_vec: &[Array2<f64>],
+    infrv_def: &InfRV,
+    agg: &Aggregation,
+) -> Result<Vec<NodeStats>, NewickError> {
+    let mut infrvs: Vec<Vec<f64>> = vec![Vec::with_capacity(gibbs_mat_vec.len()); tree.len()];
+    let mut means: Vec<Vec<f64>> = vec![Vec::with_capacity(gibbs_mat_vec.len()); tree.len()];
+    for gb in gibbs_mat_vec.iter() {
+        let mut rows: Vec<Array1<f64>> = Vec::with_capacity(tree.len());
+        #[allow(clippy::needless_range_loop)]
+        for v in 0..tree.len() {
+            let row = if tree.is_leaf(v) {
+                gb.index_axis(Axis(0), leaf_index(&tree.labels[v], names)?)
+                    .to_owned()
+            } else {
+                let mut row = Array1::<f64>::zeros(gb.shape()[1]);
+                for c in tree.children[v].iter() {
+                    row += &rows[*c];
+                }
+                row
+            };
+            infrvs[v].push(infrv_1d(row.view(), infrv_def));
+            means[v].push(row.sum() / (row.len() as f64));
+            rows.push(row);
+        }
+    }
+    Ok(infrvs
+        .iter()
+        .zip(means.iter())
+        .map(|(i, m)| NodeStats {
+            infrv: agg.infrv.of(i),
+            mean: agg.mean.of(m),
+        })
+        .collect())
+}
+
+/// Returns the statistics of the nodes of `tree`, computed from the
+/// inferential replicates if any are given and read from its NHX tags
+/// otherwise
+pub fn tree_stats(
+    tree: &FlatTree,
+    names: &HashMap<String, usize>,
+    gibbs_mat_vec: &[Array2<f64>],
+    infrv_def: &InfRV,
+    agg: &Aggregation,
+) -> Result<Vec<NodeStats>, NewickError> {
+    if gibbs_mat_vec.is_empty() {
+        Ok(tree
+            .nhx_stats()
+            .expect("the trees carry no NHX statistics, --dirs is required to compute them"))
+    } else {
+        gibbs_stats(tree, names, gibbs_mat_vec, infrv_def, agg)
+    }
+}
+
+/// Returns the nodes of a cut through `tree`, that is disjoint nodes
+/// covering all of its leaves, maximising the total score under `obj`,
+/// along with that score.
+pub fn optimal_cut(tree: &FlatTree, stats: &[NodeStats], obj: Objective) -> (Vec<usize>, f64) {
+    let n = tree.len();
+    let mut nleaves = vec![0_usize; n];
+    let mut leaf_cost = vec![0.0_f64; n];
+    let mut leaf_score = vec![0.0_f64; n];
+    let mut best = vec![0.0_f64; n];
+    let mut take = vec![false; n];
+    for v in 0..n {
+        if tree.is_leaf(v) {
+            nleaves[v] = 1;
+            leaf_cost[v] = stats[v].mean * stats[v].infrv;
+            if let Objective::InfRV(target) = obj {
+                leaf_score[v] = if stats[v].infrv <= target { 1.0 } else { -1.0 };
+            }
+        } else {
+            for c in tree.children[v].iter() {
+                nleaves[v] += nleaves[*c];
+                leaf_cost[v] += leaf_cost[*c];
+                leaf_score[v] += leaf_score[*c];
+            }
+        }
+        let score = match obj {
+            Objective::InfRV(target) => {
+                if stats[v].infrv <= target {
+                    1.0
+                } else {
+                    leaf_score[v]
+                }
+            }
+            Objective::Gain(lambda) => {
+                leaf_cost[v] - stats[v].mean * stats[v].infrv - lambda * ((nleaves[v] - 1) as f64)
+            }
+        };
+        let split: f64 = tree.children[v].iter().map(|c| best[*c]).sum();
+        // on a tie the finer cut is kept
+        if tree.is_leaf(v) || score > split {
+            take[v] = true;
+            best[v] = score;
+        } else {
+            best[v] = split;
+        }
+    }
+
+    let mut cut = Vec::new();
+    let mut stack = vec![n - 1];
+    while let Some(v) = stack.pop() {
+        if take[v] {
+            cut.push(v);
+        } else {
+            stack.extend(tree.children[v].iter().rev());
+        }
+    }
+    debug!(
+        "cut of {} nodes through a tree of {} leaves",
+        cut.len(),
+        nleaves[n - 1]
+    );
+    (cut, best[n - 1])
+}
+
+/// Returns the nodes selected by flattening `tree` at `level`, searching
+/// from the root. Leaves above the level are returned on their own. The
+/// statistics are only needed for the infRV level.
+pub fn flatten_cut(tree: &FlatTree, stats: &[NodeStats], level: FlattenLevel) -> Vec<usize> {
+    let mut nleaves = vec![0_usize; tree.len()];
+    for v in 0..tree.len() {
+        nleaves[v] = match tree.is_leaf(v) {
+            true => 1,
+            false => tree.children[v].iter().map(|c| nleaves[*c]).sum(),
+        };
+    }
+
+    let mut cut = Vec::new();
+    let mut stack = vec![(tree.len() - 1, 0_usize)];
+    while let Some((v, depth)) = stack.pop() {
+        let selected = tree.is_leaf(v)
+            || match level {
+                FlattenLevel::Depth(d) => depth >= d,
+                FlattenLevel::Size(n) => nleaves[v] <= n,
+                FlattenLevel::InfRV(x) => stats[v].infrv <= x,
+            };
+        if selected {
+            cut.push(v);
+        } else {
+            stack.extend(tree.children[v].iter().rev().map(|c| (*c, depth + 1)));
+        }
+    }
+    cut
+}
+
+/// Writes the nodes of the cut through tree `tree_id` as rows of the groups
+/// table
+pub fn cut_writer(
+    out: &mut dyn Write,
+    tree_id: usize,
+    tree: &FlatTree,
+    stats: &[NodeStats],
+    cut: &[usize],
+) -> Result<bool, io::Error> {
+    for v in cut.iter() {
+        let labels = tree.leaf_labels(*v);
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}",
+            tree_id,
+            labels.len(),
+            stats[*v].infrv,
+            stats[*v].mean,
+            labels.join(",")
+        )?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newick::parse_newick_trees;
+
+    fn flat(nwk: &str) -> FlatTree {
+        FlatTree::from_newick(&parse_newick_trees(nwk).unwrap()[0])
+    }
+
+    fn stats(infrvs: &[f64]) -> Vec<NodeStats> {
+        infrvs
+            .iter()
+            .map(|i| NodeStats {
+                infrv: *i,
+                mean: 10.0,
+            })
+            .collect()
+    }
+
+    fn cut_labels(tree: &FlatTree, cut: &[usize]) -> Vec<String> {
+        cut.iter().map(|v| tree.leaf_labels(*v).join(",")).collect()
+    }
+
+    #[test]
+    fn infrv_cut_merges_only_to_reach_the_target() {
+        // postorder: 0 1 (0,1) 2 3 (2,3) root
+        let tree = flat("((0,1),(2,3));");
+        let s = stats(&[2.0, 2.0, 0.5, 0.5, 0.5, 0.4, 0.3]);
+        let (cut, score) = optimal_cut(&tree, &s, Objective::InfRV(1.0));
+        assert_eq!(cut_labels(&tree, &cut), vec!["0,1", "2", "3"]);
+        assert_eq!(score, 3.0);
+    }
+
+    #[test]
+    fn infrv_cut_keeps_leaves_apart_when_no_node_passes() {
+        let tree = flat("((0,1),(2,3));");
+        let s = stats(&[2.0; 7]);
+        let (cut, score) = optimal_cut(&tree, &s, Objective::InfRV(1.0));
+        assert_eq!(cut_labels(&tree, &cut), vec!["0", "1", "2", "3"]);
+        assert_eq!(score, -4.0);
+    }
+
+    #[test]
+    fn infrv_cut_does_not_merge_into_failing_nodes() {
+        let tree = flat("((0,1),2);");
+        // only leaf 2 passes and no internal node does
+        let s = stats(&[2.0, 2.0, 2.0, 0.5, 2.0]);
+        let (cut, score) = optimal_cut(&tree, &s, Objective::InfRV(1.0));
+        assert_eq!(cut_labels(&tree, &cut), vec!["0", "1", "2"]);
+        assert_eq!(score, -1.0);
+    }
+
+    #[test]
+    fn gain_cut_trades_reduction_against_lambda() {
+        let tree = flat("(0,1);");
+        // merging reduces the count-weighted infRV from 20 to 5
+        let s = stats(&[1.0, 1.0, 0.5]);
+        let (cut, score) = optimal_cut(&tree, &s, Objective::Gain(10.0));
+        assert_eq!(cut_labels(&tree, &cut), vec!["0,1"]);
+        assert_eq!(score, 5.0);
+        let (cut, score) = optimal_cut(&tree, &s, Objective::Gain(20.0));
+        assert_eq!(cut_labels(&tree, &cut), vec!["0", "1"]);
+        assert_eq!(score, 0.0);
+    }
+
+    #[test]
+    fn flattens_by_depth_size_and_infrv() {
+        // postorder: 0 1 (0,1) 2 ((0,1),2) 3 root
+        let tree = flat("(((0,1),2),3);");
+        let s = stats(&[0.1, 0.1, 0.2, 0.1, 0.5, 0.1, 0.9]);
+        let at = |level| cut_labels(&tree, &flatten_cut(&tree, &s, level));
+        assert_eq!(at(FlattenLevel::Depth(0)), vec!["0,1,2,3"]);
+        assert_eq!(at(FlattenLevel::Depth(1)), vec!["0,1,2", "3"]);
+        assert_eq!(at(FlattenLevel::Depth(5)), vec!["0", "1", "2", "3"]);
+        assert_eq!(at(FlattenLevel::Size(2)), vec!["0,1", "2", "3"]);
+        assert_eq!(at(FlattenLevel::InfRV(0.6)), vec!["0,1,2", "3"]);
+        assert_eq!(at(FlattenLevel::InfRV(0.3)), vec!["0,1", "2", "3"]);
+    }
+
+    #[test]
+    fn parses_objectives_and_levels() {
+        assert_eq!("infrv:0.5".parse(), Ok(Objective::InfRV(0.5)));
+        assert_eq!("gain:2".parse(), Ok(Objective::Gain(2.0)));
+        assert!("gain".parse::<Objective>().is_err());
+        assert!("size:x".parse::<FlattenLevel>().is_err());
+        assert_eq!("depth:3".parse(), Ok(FlattenLevel::Depth(3)));
+    }
+
+    #[test]
+    fn unknown_leaves_are_errors() {
+        let tree = flat("((A,B),2);");
+        let names: HashMap<String, usize> = [("A".to_string(), 0), ("B".to_string(), 1)]
+            .into_iter()
+            .collect();
+        assert_eq!(leaf_index("B", &names).unwrap(), 1);
+        assert_eq!(leaf_index("2", &names).unwrap(), 2);
+        assert!(leaf_index("C", &names).is_err());
+
+        let gibbs = vec![Array2::<f64>::ones((3, 4))];
+        let agg = Aggregation::default();
+        assert!(gibbs_stats(&tree, &names, &gibbs, &InfRV::default(), &agg).is_ok());
+        let err =
+            gibbs_stats(&flat("((A,C),2);"), &names, &gibbs, &InfRV::default(), &agg).unwrap_err();
+        assert_eq!(err.msg, "unknown transcript \"C\"");
+    }
+}
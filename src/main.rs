@@ -1,26 +1,41 @@
+mod allele;
+mod annotate;
 pub mod binary_tree;
 mod collapse;
+mod compare;
+mod config;
+mod cut;
+mod nary_tree;
+mod newick;
 pub mod salmon_types;
+mod terminus;
+mod tree_json;
 mod util;
 
 extern crate serde_json;
 extern crate serde_pickle;
-extern crate serde_stacker;
 
 use std::collections::HashMap;
 use std::fs::*;
+use std::io;
 use std::io::Write;
-use std::io::{self, BufReader};
 
-use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use clap_complete::{generate, Shell};
+use log::{debug, error, info, warn, LevelFilter};
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::file::FileAppender;
+use log4rs::config::{Appender, Config, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::filter::threshold::ThresholdFilter;
 use ndarray::prelude::*;
 use num_format::{Locale, ToFormattedString};
 
 use petgraph::algo::connected_components;
 use petgraph::unionfind::UnionFind;
-use std::path::PathBuf;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
 
-use serde::Deserialize;
 use serde_json::json;
 
 use crate::salmon_types::ConsensusFileList;
@@ -34,7 +49,7 @@ static PROGRAM_NAME: &str = "treeterminus";
 /// message to the log file.
 #[allow(unused)]
 fn exit_with_error(status: i32, message: &str) {
-    //error!(target: "log_messages", "{}", message);
+    error!("{}", message);
     writeln!(
         &mut std::io::stderr(),
         "{} ERROR: {}!",
@@ -45,11 +60,101 @@ fn exit_with_error(status: i32, message: &str) {
     std::process::exit(status);
 }
 
-fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
+/// Sets up logging to stderr, and optionally to a log file, at the
+/// verbosity requested through `-v`/`-q`. Info level messages are
+/// logged by default, each `-v` raises the level by one step and `-q`
+/// restricts the console output to warnings and errors. The log file
+/// always receives at least the info level messages.
+fn setup_logging(sub_m: &ArgMatches) {
+    let level = if sub_m.get_flag("quiet") {
+        LevelFilter::Warn
+    } else {
+        match sub_m.get_count("verbose") {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+
+    util::show_progress(level >= LevelFilter::Info);
+
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(PatternEncoder::new(
+            "{d(%Y-%m-%d %H:%M:%S)} {h({l:<5})} {m}{n}",
+        )))
+        .build();
+    let mut config = Config::builder().appender(
+        Appender::builder()
+            .filter(Box::new(ThresholdFilter::new(level)))
+            .build("stderr", Box::new(stderr)),
+    );
+    let mut root = Root::builder().appender("stderr");
+    let mut root_level = level;
+
+    if let Some(log_file) = sub_m.get_one::<String>("log-file") {
+        let file = FileAppender::builder()
+            .append(false)
+            .encoder(Box::new(PatternEncoder::new(
+                "{d(%Y-%m-%d %H:%M:%S)} {l:<5} {M} - {m}{n}",
+            )))
+            .build(log_file)
+            .unwrap_or_else(|e| panic!("could not create log file {}: {}", log_file, e));
+        config = config.appender(Appender::builder().build("log_file", Box::new(file)));
+        root = root.appender("log_file");
+        root_level = root_level.max(LevelFilter::Info);
+    }
+
+    let config = config
+        .build(root.build(root_level))
+        .expect("could not build the logging configuration");
+    log4rs::init_config(config).expect("could not initialize logging");
+}
+
+/// The inputs of a sample but its inferential replicates, which a grouping
+/// overwrites, read once so that several groupings can share them
+struct SampleInput {
+    dir: String,
+    meta: salmon_types::MetaInfo,
+    eq_class: salmon_types::EqClassExperiment,
+}
+
+// Reads the equivalence classes and inferential replicates of the salmon
+// directory `dir`
+fn load_sample(dir: &str) -> Result<(SampleInput, Array2<f64>), io::Error> {
+    let file_list = salmon_types::FileList::new(dir.to_string());
+    let meta = util::parse_json(&file_list.mi_file)?;
+    let mut gibbs = Array2::<f64>::zeros((
+        meta.num_valid_targets as usize,
+        meta.num_bootstraps as usize,
+    ));
+    util::read_gibbs_array(&file_list.bootstrap_file, &meta, &mut gibbs);
+    info!("parsing eqfile {:?}", file_list.eq_file);
+    let eq_class = util::parse_eq(&file_list.eq_file)?;
+    Ok((
+        SampleInput {
+            dir: dir.to_string(),
+            meta,
+            eq_class,
+        },
+        gibbs,
+    ))
+}
+
+/// Groups the transcripts of `samples`, a single sample or all the samples
+/// of the experiment `dname` in the mean mode, with their replicates `gibbs`,
+/// writing the trees below `out`
+fn do_group(
+    sub_m: &ArgMatches,
+    mean_inf: bool,
+    dname: &str,
+    out: &str,
+    samples: &[SampleInput],
+    gibbs: Vec<Array2<f64>>,
+) -> Result<bool, io::Error> {
     //let mut groups: Vec<Vec<usize>> = Vec::new();
-    //let dir_paths : Vec<_> = sub_m.values_of("dirs").unwrap().collect();
-    let dname: String = sub_m.value_of("dir").unwrap().to_string();
-    let prefix: String = sub_m.value_of("out").unwrap().to_string();
+    //let dir_paths : Vec<_> = sub_m.get_many::<String>("dirs").unwrap().collect();
+    let prefix = out.to_string();
     create_dir_all(prefix.clone())?;
 
     // let mut unionfind_vec = Vec::<UnionFind<_>>::with_capacity(dir_paths.len()) ;
@@ -58,82 +163,78 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
     // let mut num_global_targrts = 0u32 ;
 
     let seed = sub_m
-        .value_of("seed")
+        .get_one::<String>("seed")
         .unwrap()
         .parse::<u64>()
         .expect("generate random values from the seed");
-    let min_spread = sub_m
-        .value_of("min-spread")
-        .unwrap()
-        .parse::<f64>()
-        .expect("could not convert min-spread to float value");
+    let parse_filter = |name: &str| {
+        sub_m
+            .get_one::<String>(name)
+            .unwrap()
+            .parse::<f64>()
+            .unwrap_or_else(|_| panic!("could not convert {} to float value", name))
+    };
+    let filters = util::Filters {
+        min_mean: parse_filter("min-mean"),
+        min_spread: parse_filter("min-spread"),
+        eq_weight_frac: parse_filter("eq-weight-frac"),
+        edge_count_frac: parse_filter("edge-count-frac"),
+        min_replicate_sum: parse_filter("min-replicate-sum"),
+    };
 
     let tolerance = sub_m
-        .value_of("tolerance")
+        .get_one::<String>("tolerance")
         .unwrap()
         .parse::<f64>()
         .expect("could not convert tolerance to float value");
 
-    let thr_bool = sub_m
-        .value_of("thresh")
-        .unwrap()
-        .parse::<bool>()
-        .expect("could not parse thr_bool");
-
-    let mean_inf = sub_m
-        .value_of("mean")
-        .unwrap()
-        .parse::<bool>()
-        .expect("could not parse mean");
+    let thr_bool = sub_m.get_flag("thr");
 
     let inf_perc = sub_m
-        .value_of("inf_perc")
+        .get_one::<String>("inf_perc")
         .unwrap()
         .parse::<f64>()
         .expect("could not parse inf percentile");
 
     let red_perc = sub_m
-        .value_of("red_perc")
+        .get_one::<String>("red_perc")
         .unwrap()
         .parse::<f64>()
         .expect("could not parse reduction in inferential variance");
 
-    let mut dir_paths: Vec<String> = Vec::new();
-    if mean_inf {
-        let sd = read_dir(dname.clone());
-        for f in sd.unwrap() {
-            let x = f
-                .unwrap()
-                .path()
-                .to_str()
-                .unwrap()
-                .rsplit('/')
-                .collect::<Vec<_>>()[0]
-                .to_string();
-            if x == "quant.sf" {
-                panic!("A directory above this level is required or flag mean_inf should be set to false");
-            }
-        }
-        let sal_dir_paths = read_dir(dname.clone())?
-            .map(|res| res.map(|e| e.path()))
-            .filter(|res| res.as_ref().unwrap().is_dir())
-            .collect::<Result<Vec<_>, io::Error>>()?;
+    let cross_gene_red_perc = sub_m.get_one::<String>("cross-gene-red-perc").map(|v| {
+        v.parse::<f64>()
+            .expect("could not parse cross-gene reduction in inferential variance")
+    });
 
-        for entry in sal_dir_paths.iter() {
-            dir_paths.push(entry.as_path().to_str().unwrap().to_string());
-        }
-    }
+    let compression = sub_m
+        .get_one::<String>("compress")
+        .unwrap()
+        .parse::<util::OutputCompression>()
+        .expect("could not parse compression");
 
-    println!("------input configuration------");
-    println!("seed : {}", seed);
-    println!("min-spread : {}", min_spread);
-    println!("tolerance : {}", tolerance);
-    println!("dir : {}", dname.clone());
+    let normalize = sub_m
+        .get_one::<String>("normalize")
+        .unwrap()
+        .parse::<util::Normalization>()
+        .expect("could not parse normalize");
+
+    let leaf_names = sub_m.get_one::<String>("leaf-labels").unwrap() == "name";
+    let branch_lengths = sub_m.get_flag("branch-lengths");
+    let nhx = sub_m.get_flag("nhx");
+    let keep_alleles = sub_m.get_flag("keep-alleles");
+    let infrv_def = infrv_def(sub_m);
+    let agg = aggregation(sub_m);
+
+    info!("------input configuration------");
+    info!("seed : {}", seed);
+    info!("filters : {:?}", filters);
+    info!("tolerance : {}", tolerance);
+    info!("dir : {}", dname);
     let compo: Vec<&str> = dname.rsplit('/').collect();
     //println!("{:?}",compo);
     let experiment_name = compo[0];
     let mut prefix_path = prefix;
-    let file_list = salmon_types::FileList::new(dname.to_string());
 
     let mut file_list_vec = Vec::new();
     if !mean_inf {
@@ -141,139 +242,139 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
         prefix_path.push_str(experiment_name);
     }
 
-    if mean_inf {
-        for dir in dir_paths {
-            file_list_vec.push(salmon_types::FileList::new(dir.to_string()));
-        }
-    }
-
     // create output directory
 
-    println!("output folder: {}", prefix_path.clone());
-    println!("------------------------------");
+    info!("output folder: {}", prefix_path);
+    info!("------------------------------");
     // create
     create_dir_all(prefix_path.clone())?;
     let file_list_out = salmon_types::FileList::new(prefix_path.clone());
 
-    // Load the gibbs samples
-    let mut x;
+    // Take over the gibbs samples
+    let x;
     let mut gibbs_array = Array2::<f64>::zeros((1, 1));
     let mut gibbs_array_vec = Vec::new();
     let mut x_vec = Vec::new();
-    let mut gibbs_mat_mean = Array1::<f64>::zeros(1);
+    let mut gibbs_mat_mean;
     let mut eq_class_counts: Vec<u32> = Vec::new();
-    let mut eq_class;
-    let mut l = 0;
+    let merged_eq_class;
+    let eq_class: &salmon_types::EqClassExperiment;
 
     // Think about enum representation
     if mean_inf {
         let mut eq_class_vec = Vec::new();
-        for (_i, file_list) in file_list_vec.iter().enumerate() {
-            x_vec.push(util::parse_json(&file_list.mi_file).unwrap());
-            gibbs_array_vec.push(Array2::<f64>::zeros((
-                x_vec[_i].num_valid_targets as usize,
-                x_vec[_i].num_bootstraps as usize,
-            )));
-            #[allow(unused_assignments)]
-            if _i == 0 {
-                gibbs_mat_mean = Array1::<f64>::zeros(gibbs_array_vec[0].shape()[0]);
-                x = x_vec[0].clone();
-            }
-            util::read_gibbs_array(
-                &file_list.bootstrap_file,
-                &x_vec[_i],
-                &mut gibbs_array_vec[_i],
-            );
-            gibbs_mat_mean += &gibbs_array_vec[_i].mean_axis(Axis(1)).unwrap();
-            println!("parsing eqfile {:?}", file_list.eq_file);
-            eq_class_vec.push(util::parse_eq(&file_list.eq_file).unwrap());
-
-            println!("length of eqclass {:?}", eq_class_vec[_i].neq);
-            eq_class_counts.extend(vec![0_u32; eq_class_vec[_i].neq]);
-
-            for (j, eq) in eq_class_vec[_i].classes.iter().enumerate() {
-                eq_class_counts[l + j] = eq.2;
-                if j == eq_class_vec[_i].neq - 1 {
-                    l += j;
-                }
-            }
-            l += 1;
+        for (sample, gb) in samples.iter().zip(gibbs) {
+            debug!("length of eqclass {:?}", sample.eq_class.neq);
+            eq_class_counts.extend(sample.eq_class.classes.iter().map(|eq| eq.2));
+            file_list_vec.push(salmon_types::FileList::new(sample.dir.clone()));
+            x_vec.push(sample.meta.clone());
+            gibbs_array_vec.push(gb);
+            eq_class_vec.push(&sample.eq_class);
         }
         x = x_vec[0].clone();
-        gibbs_mat_mean /= gibbs_array_vec.len() as f64;
-        eq_class = eq_class_vec[0].clone();
+        if normalize != util::Normalization::None {
+            let quants = file_list_vec
+                .iter()
+                .zip(x_vec.iter())
+                .map(|(f, mi)| util::parse_quant(&f.quant_file, mi))
+                .collect::<Result<Vec<_>, io::Error>>()?;
+            let factors = util::norm_factors(&quants, normalize)?;
+            info!("normalization factors: {:?}", factors);
+            for (gb, f) in gibbs_array_vec.iter_mut().zip(factors.iter()) {
+                *gb /= *f;
+            }
+            let samples: Vec<String> = file_list_vec
+                .iter()
+                .map(|f| f.prefix.file_name().unwrap().to_string_lossy().to_string())
+                .collect();
+            let mut nfile = File::create(&file_list_out.norm_factors_file)
+                .expect("could not create norm_factors.tsv");
+            util::norm_factors_writer(&mut nfile, &samples, &quants, &factors)?;
+        }
+        gibbs_mat_mean = util::sample_means(&gibbs_array_vec, agg.txp_mean);
+        let mut eq_class_all = eq_class_vec[0].clone();
         #[allow(clippy::needless_range_loop)]
         for j in 1..eq_class_vec.len() {
-            eq_class.neq += eq_class_vec[j].neq;
-            let prev_off = eq_class.classes.offsets[eq_class.classes.offsets.len() - 1];
+            eq_class_all.neq += eq_class_vec[j].neq;
+            let prev_off = eq_class_all.classes.offsets[eq_class_all.classes.offsets.len() - 1];
             let mut offset = vec![0_usize; eq_class_vec[j].classes.offsets.len() - 1];
             #[allow(clippy::needless_range_loop)]
             for _i in 0..offset.len() {
                 offset[_i] = eq_class_vec[j].classes.offsets[_i + 1] + prev_off;
             }
-            eq_class.classes.offsets.extend(offset.iter().copied());
-            eq_class
+            eq_class_all.classes.offsets.extend(offset.iter().copied());
+            eq_class_all
                 .classes
                 .labels
                 .extend(eq_class_vec[j].classes.labels.iter().copied());
-            eq_class
+            eq_class_all
                 .classes
                 .weights
                 .extend(eq_class_vec[j].classes.weights.iter().copied());
-            eq_class
+            eq_class_all
                 .classes
                 .counts
                 .extend(eq_class_vec[j].classes.counts.iter().copied());
         }
+        merged_eq_class = eq_class_all;
+        eq_class = &merged_eq_class;
         // println!("{}, {}, {}, {}", eq_class.neq, eq_class.classes.offsets.len(),
         //     eq_class.classes.labels.len(), eq_class.classes.weights.len());
         // println!("{}", eq_class_counts.len());
         // println!("{}", eq_class.classes.offsets[344782]);
     } else {
-        x = util::parse_json(&file_list.mi_file).unwrap();
-        gibbs_array =
-            Array2::<f64>::zeros((x.num_valid_targets as usize, x.num_bootstraps as usize));
-        util::read_gibbs_array(&file_list.bootstrap_file, &x, &mut gibbs_array);
+        if normalize != util::Normalization::None {
+            warn!("--normalize only applies to the mean mode, ignoring it for a single sample");
+        }
+        let sample = &samples[0];
+        x = sample.meta.clone();
+        gibbs_array = gibbs.into_iter().next().unwrap();
         gibbs_mat_mean = gibbs_array.mean_axis(Axis(1)).unwrap();
 
-        println!("parsing eqfile {:?}", file_list.eq_file);
-        eq_class = util::parse_eq(&file_list.eq_file).unwrap();
+        eq_class = &sample.eq_class;
         // println!("length of eqclass {:?}", eq_class.neq);
-        eq_class_counts = vec![0_u32; eq_class.neq];
-        // let mut i = 0_usize;
-        for (i, eq) in eq_class.classes.iter().enumerate() {
-            eq_class_counts[i] = eq.2;
-        }
+        eq_class_counts = eq_class.classes.iter().map(|eq| eq.2).collect();
     }
 
     // if a2g exists also dumps gene level groups
-    let allele2txp = PathBuf::from(sub_m.value_of("a2t").unwrap().to_string());
+    let allele2txp = PathBuf::from(sub_m.get_one::<String>("a2t").unwrap().to_string());
     let asemode: bool = allele2txp.as_path().is_file();
     if asemode {
-        println!(
+        info!(
             "Alleles would be collapsed according to the file: {:?}",
             allele2txp.to_str().unwrap()
         );
+    } else if keep_alleles {
+        panic!("--keep-alleles requires an allele to transcript mapping given with --a2t");
     }
 
     // if t2g exists restrict equivalence classes to gene level groups
-    let transcript2gene = PathBuf::from(sub_m.value_of("t2g").unwrap().to_string());
+    let transcript2gene = PathBuf::from(sub_m.get_one::<String>("t2g").unwrap().to_string());
     let txpmode: bool = transcript2gene.as_path().is_file();
     if txpmode {
-        println!(
+        info!(
             "Txps within a gene would be collapsed using : {:?}",
             transcript2gene.to_str().unwrap()
         );
+    } else if cross_gene_red_perc.is_some() {
+        panic!("--cross-gene-red-perc requires a transcript to gene mapping given with --t2g");
+    }
+
+    // a transcript to gene mapping used only to annotate the trees
+    let gene_map_file = PathBuf::from(sub_m.get_one::<String>("gene-map").unwrap().to_string());
+    let annotmode: bool = txpmode || gene_map_file.as_path().is_file();
+    if annotmode && !txpmode {
+        info!(
+            "Trees would be annotated with the genes of : {:?}",
+            gene_map_file.to_str().unwrap()
+        );
     }
 
     // take the transcript to gene mapping
     // this will also create a map from transcript id
     // to gene id
 
-    let mut collapse_order: Vec<binary_tree::TreeNode> = Vec::new();
-    for i in 0..eq_class.ntarget {
-        collapse_order.push(binary_tree::TreeNode::create_leaf(i.to_string()));
-    }
+    let mut collapse_order = binary_tree::TreeArena::with_leaves(eq_class.ntarget);
 
     // fill targets from eq_class
     let mut tnames: HashMap<String, usize> = HashMap::new();
@@ -287,9 +388,10 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
 
     let mut txp2allele_map: HashMap<usize, Vec<usize>> = HashMap::new();
     let mut allele2txp_map: Vec<usize> = Vec::new();
+    let mut txp_names: Vec<String> = Vec::new();
 
     if asemode {
-        util::get_map_bw_ent(
+        txp_names = util::get_map_bw_ent(
             &allele2txp,
             &mut txp2allele_map,
             &mut allele2txp_map,
@@ -304,8 +406,12 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
         }
     }
 
+    let mut gene_map = annotate::GeneMap {
+        txp_gene: Vec::new(),
+        names: Vec::new(),
+    };
     if txpmode {
-        util::get_map_bw_ent(
+        let gene_names = util::get_map_bw_ent(
             &transcript2gene,
             &mut gene2allele_map,
             &mut allele2gene_map,
@@ -318,6 +424,12 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
                 ntxps, ntarget
             );
         }
+        gene_map = annotate::GeneMap::new(gene_names, &gene2allele_map, ntarget);
+    } else if annotmode {
+        let mut gene_txps = HashMap::new();
+        let gene_names =
+            util::get_map_bw_ent(&gene_map_file, &mut gene_txps, &mut Vec::new(), &tnames);
+        gene_map = annotate::GeneMap::new(gene_names, &gene_txps, ntarget);
     }
 
     if asemode {
@@ -335,43 +447,59 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
 
     // let inf_perc = 0.25f64;
     let p = match mean_inf {
-        false => util::get_infrv_percentile(&gibbs_array, inf_perc),
-        true => {
-            let mut cum_infrv_perc: f64 = 0.0;
-            for (_i, gb) in gibbs_array_vec.iter().enumerate() {
-                let perc = util::get_infrv_percentile(gb, inf_perc);
-                if _i == 0 {
-                    cum_infrv_perc = perc;
-                } else {
-                    cum_infrv_perc = cum_infrv_perc.min(perc);
-                }
-            }
-            cum_infrv_perc
-        }
+        false => util::get_infrv_percentile(&gibbs_array, inf_perc, &infrv_def, &filters),
+        true => agg.percentile.of(&gibbs_array_vec
+            .iter()
+            .map(|gb| util::get_infrv_percentile(gb, inf_perc, &infrv_def, &filters))
+            .collect::<Vec<f64>>()),
     };
 
-    println!("the {}% of infRV was : {}", inf_perc * 100., p);
-
-    let thr = match thr_bool {
-        true => {
-            if !mean_inf {
-                util::get_threshold(&gibbs_array, p, seed, &file_list_out, red_perc)
-            } else {
-                let mut thresh = 0.0;
-                for gb in gibbs_array_vec.iter() {
-                    thresh += util::get_threshold(gb, p, seed, &file_list_out, red_perc);
-                }
-                thresh / (gibbs_array_vec.len() as f64)
-            }
+    info!("the {}% of infRV was : {}", inf_perc * 100., p);
+
+    let get_threshold = |red_perc: f64| {
+        if !mean_inf {
+            util::get_threshold(
+                &gibbs_array,
+                p,
+                seed,
+                &file_list_out,
+                red_perc,
+                compression,
+                &infrv_def,
+                &filters,
+            )
+        } else {
+            agg.threshold.of(&gibbs_array_vec
+                .iter()
+                .map(|gb| {
+                    util::get_threshold(
+                        gb,
+                        p,
+                        seed,
+                        &file_list_out,
+                        red_perc,
+                        compression,
+                        &infrv_def,
+                        &filters,
+                    )
+                })
+                .collect::<Vec<f64>>())
         }
+    };
+    let thr = match thr_bool {
+        true => get_threshold(red_perc),
         false => 1e7,
     };
-    println!("threshold: {}", thr);
-    println!("{}", eq_class.ntarget);
+    info!("threshold: {}", thr);
+    // merges across genes must pass both thresholds
+    let cross_gene_thr = cross_gene_red_perc.map(|q| get_threshold(q).min(thr));
+    if let Some(t) = cross_gene_thr {
+        info!("cross-gene threshold: {}", t);
+    }
+    debug!("number of targets: {}", eq_class.ntarget);
 
     //let dpath = Path::new(file_list_out.delta_file.clone());
-    let mut dfile =
-        File::create(file_list_out.delta_file.clone()).expect("could not create collapse.log");
+    let mut dfile = util::create_writer(&file_list_out.delta_file, compression);
     let mut unionfind_struct: UnionFind<usize> = UnionFind::new(eq_class.ntarget);
     let mut group_order: Vec<String> = Vec::with_capacity(eq_class.ntarget);
     for i in 0..eq_class.ntarget {
@@ -380,29 +508,72 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
 
     // pass the gene to transcript mapping to the building graph phase to
     // restrict the creation of two edge between nodes from the same gene
-    let mut gcfile = File::create(file_list_out.golden_collapses_log_file.clone())
-        .expect("could not create golden collapse.log");
-    let mut allele_file = File::create(file_list_out.allele_collapses_log_file.clone())
-        .expect("could not create golden collapse.log");
+    let mut gcfile = util::create_writer(&file_list_out.golden_collapses_log_file, compression);
+    let mut allele_file =
+        util::create_writer(&file_list_out.allele_collapses_log_file, compression);
+    let mut cross_gene_file =
+        util::create_writer(&file_list_out.cross_gene_collapses_log_file, compression);
+    // the allelic ratios are taken before the alleles are merged
+    let allele_ratios = match asemode {
+        true => {
+            let gibbs_mats: Vec<&Array2<f64>> = match mean_inf {
+                true => gibbs_array_vec.iter().collect(),
+                false => vec![&gibbs_array],
+            };
+            allele::allelic_ratios(&txp2allele_map, &gibbs_mats)
+        }
+        false => Vec::new(),
+    };
+    let sample_names: Vec<String> = match mean_inf {
+        true => file_list_vec
+            .iter()
+            .map(|f| f.prefix.file_name().unwrap().to_string_lossy().to_string())
+            .collect(),
+        false => vec![experiment_name.to_string()],
+    };
+    let mut filter_counts = util::FilterCounts {
+        low_sum_txps: match mean_inf {
+            true => gibbs_array_vec
+                .iter()
+                .map(|gb| util::count_low_sum(gb, filters.min_replicate_sum))
+                .collect(),
+            false => vec![util::count_low_sum(&gibbs_array, filters.min_replicate_sum)],
+        },
+        ..Default::default()
+    };
+    // with --keep-alleles the alleles are not merged up front
+    let no_alleles = HashMap::new();
+
     let mut gr = util::eq_experiment_to_graph(
-        &eq_class,
+        eq_class,
         &mut gibbs_array,
         &mut gibbs_array_vec,
         &eq_class_counts,
         tolerance,
         thr,
         p,
-        min_spread,
         &mut dfile,
         &mut unionfind_struct,
         &allele2gene_map,
-        &txp2allele_map,
+        cross_gene_thr,
+        if keep_alleles {
+            &no_alleles
+        } else {
+            &txp2allele_map
+        },
         &mut group_order,
         &mut collapse_order,
         mean_inf,
         &mut gcfile,
         &mut allele_file,
+        &infrv_def,
+        &agg,
+        &filters,
+        &mut filter_counts,
     );
+    dfile.finish()?;
+    gcfile.finish()?;
+    allele_file.finish()?;
 
     util::verify_graph(&eq_class_counts, &mut gr);
     // Go over the graph and keep collapsing
@@ -412,19 +583,18 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
 
     // connected coponents
     let num_connected_components = connected_components(&gr);
-    println!("#Connected components {:?}", num_connected_components);
+    info!("#Connected components {:?}", num_connected_components);
 
     let mut num_collapses = 0_usize;
 
     //let cpath = Path::new(file_list_out.collapsed_log_file.clone());
-    let mut cfile = File::create(file_list_out.collapsed_log_file.clone())
-        .expect("could not create collapse.log");
+    let mut cfile = util::create_writer(&file_list_out.collapsed_log_file, compression);
 
     let gcomp: Vec<petgraph::prelude::NodeIndex> = gr
         .node_indices()
         .map(|x| petgraph::graph::NodeIndex::new(x.index()))
         .collect();
-    util::work_on_component(
+    let num_cross_gene = util::work_on_component(
         &eq_class_counts,
         &mut gibbs_array,
         &mut gibbs_array_vec,
@@ -438,7 +608,16 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
         &mut cfile,
         &mut collapse_order,
         mean_inf,
+        &allele2gene_map,
+        cross_gene_thr,
+        &mut cross_gene_file,
+        &infrv_def,
+        &agg,
+        &filters,
+        &mut filter_counts,
     );
+    cfile.finish()?;
+    cross_gene_file.finish()?;
 
     // //write down the groups
     let mut groups = HashMap::new();
@@ -451,35 +630,69 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
     }
 
     // println!("Number of collapsed transcripts from conn components with 2 {}", num_collapses_2.to_formatted_string(&Locale::en));
-    println!(
+    info!(
         "Number of collapses {}",
         num_collapses.to_formatted_string(&Locale::en)
     );
+    if cross_gene_thr.is_some() {
+        info!(
+            "Number of cross-gene collapses {}",
+            num_cross_gene.to_formatted_string(&Locale::en)
+        );
+    }
 
+    let mut group_config = config::effective_config(sub_m, config::GROUP_PARAMS);
+    group_config["mode"] = json!(if mean_inf { "mean" } else { "sample" });
+    group_config["dir"] = json!(dname);
+    group_config["out"] = json!(out);
     let params = json!({
         "seed":seed,
         "tolerance":tolerance,
         "mean_inf":mean_inf,
         "thr_bool":thr_bool,
-        "inp_dir":dname.clone(),
+        "inp_dir":dname,
         "out_dir":prefix_path.clone(),
         "allele_mode":asemode,
+        "keep_alleles":keep_alleles,
         "txp_mode":txpmode,
+        "gene_map":gene_map_file.clone(),
         "inf_perc":inf_perc,
         "red_perc":red_perc,
+        "infrv":sub_m.get_one::<String>("infrv").unwrap(),
+        "infrv_pseudocount":infrv_def.pseudocount,
+        "infrv_offset":infrv_def.offset,
+        "cross_gene_red_perc":cross_gene_red_perc,
+        "cross_gene_thr":cross_gene_thr,
+        "compress":compression.name(),
+        "normalize":normalize.name(),
+        "aggregate":{
+            "infrv":agg.infrv.name(),
+            "mean":agg.mean.name(),
+            "spread":agg.spread.name(),
+            "score":agg.score.name(),
+            "threshold":agg.threshold.name(),
+            "percentile":agg.percentile.name(),
+            "txp_mean":agg.txp_mean.name(),
+        },
+        "leaf_labels":sub_m.get_one::<String>("leaf-labels").unwrap(),
+        "branch_lengths":branch_lengths,
+        "nhx":nhx,
         "p":p,
         "thr":thr,
         "ntxps":eq_class.ntarget,
         "connected_components":num_connected_components,
         "ncollapses":num_collapses,
+        "ncross_gene_collapses":num_cross_gene,
+        "filters":filters,
+        "filtered":filter_counts,
+        "config":group_config,
     });
 
     let param_log_file =
         File::create(file_list_out.param_log_file).expect("could not create group order file");
     serde_json::to_writer(param_log_file, &params)?;
     let mut gfile = File::create(file_list_out.group_file).expect("could not create groups.txt");
-    let mut co_file = File::create(file_list_out.collapse_order_file)
-        .expect("could not create collapse order file");
+    let mut co_file = util::create_writer(&file_list_out.collapse_order_bin_file, compression);
     let nwk_path = match mean_inf {
         true => {
             let cons = ConsensusFileList::new(prefix_path.clone());
@@ -489,233 +702,1742 @@ fn do_group(sub_m: &ArgMatches) -> Result<bool, io::Error> {
     };
     let mut nwk_file = File::create(nwk_path).expect("could not create group write file");
     let _write = util::group_writer(&mut gfile, &groups);
-    let _write = util::collapse_order_writer(&mut co_file, &mut nwk_file, &groups, &collapse_order);
+    util::collapse_order_writer(
+        &mut co_file,
+        &mut nwk_file,
+        &groups,
+        &collapse_order,
+        &binary_tree::NewickOptions {
+            tnames: if leaf_names {
+                Some(&eq_class.targets)
+            } else {
+                None
+            },
+            branch_lengths,
+            nhx,
+        },
+    )?;
+    co_file.finish()?;
+    util::names_writer(&file_list_out.tree_names_file, &eq_class.targets)?;
+    if asemode {
+        let leaf_trees = allele::leaf_trees(&groups, &collapse_order);
+        let mut lfile = util::create_writer(&file_list_out.allele_leaves_file, compression);
+        allele::allele_leaves_writer(
+            &mut lfile,
+            &leaf_trees,
+            &allele2txp_map,
+            &eq_class.targets,
+            &txp_names,
+        )?;
+        lfile.finish()?;
+        let mut rfile = util::create_writer(&file_list_out.allelic_ratio_file, compression);
+        allele::allelic_ratio_writer(
+            &mut rfile,
+            &allele_ratios,
+            &leaf_trees,
+            &eq_class.targets,
+            &txp_names,
+            &sample_names,
+        )?;
+        rfile.finish()?;
+    }
+    if annotmode {
+        let mut afile = util::create_writer(&file_list_out.tree_annotation_file, compression);
+        let summary =
+            annotate::gene_annotation_writer(&mut afile, &groups, &collapse_order, &gene_map)?;
+        afile.finish()?;
+        info!(
+            "{} of {} trees cross gene boundaries, spanning up to {} genes",
+            summary.n_cross_gene, summary.n_trees, summary.max_genes
+        );
+        let sfile = File::create(&file_list_out.gene_summary_file)
+            .expect("could not create gene_summary.json");
+        serde_json::to_writer_pretty(sfile, &summary)?;
+    }
+
+    Ok(true)
+}
+
+/// Groups each sample of the experiment `dname` on its own, as `group
+/// sample` does, running up to `--jobs` samples at once, and builds their
+/// consensus trees when `--consensus` is given
+fn do_per_sample(sub_m: &ArgMatches, dname: &str) -> Result<bool, io::Error> {
+    let dir_paths = salmon_dirs(dname)?;
+    let metas = dir_paths
+        .iter()
+        .map(|dir| {
+            let file_list = salmon_types::FileList::new(dir.to_str().unwrap().to_string());
+            util::parse_json(&file_list.mi_file)
+        })
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    let pool = job_pool(sub_m, memory_limit(sub_m, metas.iter(), 0.0))?;
+    info!(
+        "grouping {} samples, {} at a time",
+        dir_paths.len(),
+        pool.current_num_threads()
+    );
+
+    let out = sub_m.get_one::<String>("out").unwrap();
+    pool.install(|| {
+        dir_paths.par_iter().try_for_each(|dir| {
+            let dir = dir.to_str().unwrap();
+            let (sample, gibbs) = load_sample(dir)?;
+            do_group(sub_m, false, dir, out, &[sample], vec![gibbs]).map(|_| ())
+        })
+    })?;
+
+    if sub_m.get_flag("consensus") {
+        let file_list = salmon_types::FileList::new(dir_paths[0].to_str().unwrap().to_string());
+        let tnames = util::parse_eq(&file_list.eq_file)?.targets;
+        consensus_trees(
+            dname,
+            out,
+            out,
+            &tnames,
+            sub_m
+                .get_one::<String>("compress")
+                .unwrap()
+                .parse::<util::OutputCompression>()
+                .expect("could not parse compression"),
+            sub_m.get_one::<String>("leaf-labels").unwrap() == "name",
+            None,
+            None,
+        )?;
+    }
+    Ok(true)
+}
+
+// The memory a sample takes to hold its replicates
+fn replicate_bytes(meta: &salmon_types::MetaInfo) -> f64 {
+    8.0 * meta.num_valid_targets as f64 * meta.num_bootstraps as f64
+}
+
+// The number of the samples of `metas` whose replicates fit in
+// `--max-memory` at once besides `resident` bytes held throughout, all of
+// them when it is not given
+fn memory_limit<'a>(
+    sub_m: &ArgMatches,
+    metas: impl ExactSizeIterator<Item = &'a salmon_types::MetaInfo>,
+    resident: f64,
+) -> usize {
+    let nsamples = metas.len();
+    let max_memory = match sub_m.get_one::<String>("max-memory") {
+        Some(m) => {
+            m.parse::<f64>()
+                .expect("could not convert max-memory to float value")
+                * 1e9
+        }
+        None => return nsamples,
+    };
+    // a sample holds its replicates in memory while it is grouped
+    let sample_memory = metas.map(replicate_bytes).fold(0.0_f64, f64::max);
+    let fitting = ((max_memory - resident) / sample_memory).max(0.0) as usize;
+    if fitting == 0 {
+        warn!(
+            "a sample needs {:.2} GB for its replicates, besides {:.2} GB held throughout, more than --max-memory",
+            sample_memory / 1e9,
+            resident / 1e9
+        );
+    }
+    nsamples.min(fitting)
+}
 
+// A pool of `--jobs` threads, all the available cores by default, but no
+// more than `limit` and at least one
+fn job_pool(sub_m: &ArgMatches, limit: usize) -> Result<rayon::ThreadPool, io::Error> {
+    let jobs = match sub_m.get_one::<String>("jobs") {
+        Some(j) => j
+            .parse::<usize>()
+            .expect("could not convert jobs to an integer"),
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs.min(limit).max(1))
+        .build()
+        .map_err(io::Error::other)
+}
+
+/// Builds the per-sample, Mean and Consensus trees of the experiment `dname`
+/// from a single reading of its samples, into the `samples`, `mean` and
+/// `consensus` directories of `--out`, listed in its `manifest.json`
+fn do_run(sub_m: &ArgMatches, dname: &str) -> Result<bool, io::Error> {
+    let out = sub_m.get_one::<String>("out").unwrap();
+    let samples_out = format!("{}/samples", out);
+    let mean_out = format!("{}/mean", out);
+    let consensus_out = format!("{}/consensus", out);
+    let compression = sub_m
+        .get_one::<String>("compress")
+        .unwrap()
+        .parse::<util::OutputCompression>()
+        .expect("could not parse compression");
+
+    let dir_paths = salmon_dirs(dname)?;
+    let pool = job_pool(sub_m, dir_paths.len())?;
+    info!(
+        "reading {} samples, {} at a time",
+        dir_paths.len(),
+        pool.current_num_threads()
+    );
+    let (samples, gibbs): (Vec<SampleInput>, Vec<Array2<f64>>) = pool
+        .install(|| {
+            dir_paths
+                .par_iter()
+                .map(|d| load_sample(d.to_str().unwrap()))
+                .collect::<Result<Vec<_>, io::Error>>()
+        })?
+        .into_iter()
+        .unzip();
+    drop(pool);
+
+    // the per-sample trees group copies of the replicates, which they
+    // overwrite, so that the mean trees can take them over afterwards; the
+    // replicates of all the samples stay in memory meanwhile
+    let resident: f64 = samples.iter().map(|s| replicate_bytes(&s.meta)).sum();
+    let pool = job_pool(
+        sub_m,
+        memory_limit(sub_m, samples.iter().map(|s| &s.meta), resident),
+    )?;
+    info!(
+        "building the per-sample trees, {} at a time",
+        pool.current_num_threads()
+    );
+    pool.install(|| {
+        samples
+            .par_iter()
+            .zip(gibbs.par_iter())
+            .try_for_each(|(sample, gb)| {
+                do_group(
+                    sub_m,
+                    false,
+                    &sample.dir,
+                    &samples_out,
+                    std::slice::from_ref(sample),
+                    vec![gb.clone()],
+                )
+                .map(|_| ())
+            })
+    })?;
+    drop(pool);
+
+    let tnames = &samples[0].eq_class.targets;
+    let names: Vec<String> = samples
+        .iter()
+        .map(|s| {
+            let compo: Vec<&str> = s.dir.rsplit('/').collect();
+            compo[0].to_string()
+        })
+        .collect();
+    info!("building the mean trees");
+    do_group(sub_m, true, dname, &mean_out, &samples, gibbs)?;
+
+    info!("building the consensus trees");
+    create_dir_all(&consensus_out)?;
+    consensus_trees(
+        dname,
+        &samples_out,
+        &consensus_out,
+        tnames,
+        compression,
+        sub_m.get_one::<String>("leaf-labels").unwrap() == "name",
+        None,
+        None,
+    )?;
+
+    let mut run_config = config::effective_config(sub_m, config::GROUP_PARAMS);
+    if let serde_json::Value::Object(m) = config::effective_config(sub_m, config::RUN_PARAMS) {
+        run_config.as_object_mut().unwrap().extend(m);
+    }
+    let sample_entries: Vec<serde_json::Value> = names
+        .iter()
+        .zip(dir_paths.iter())
+        .map(|(name, dir)| {
+            let file_list = salmon_types::FileList::new(format!("{}/{}", samples_out, name));
+            json!({
+                "name":name,
+                "dir":dir,
+                "out":file_list.prefix,
+                "trees":file_list.group_nwk_file,
+                "params":file_list.param_log_file,
+            })
+        })
+        .collect();
+    let mean_files = ConsensusFileList::new(mean_out.clone());
+    let consensus_files = ConsensusFileList::new(consensus_out.clone());
+    let manifest = json!({
+        "dir":dname,
+        "samples":sample_entries,
+        "mean":{
+            "out":mean_out,
+            "trees":mean_files.cons_nwk_file,
+            "params":salmon_types::FileList::new(mean_out.clone()).param_log_file,
+        },
+        "consensus":{
+            "out":consensus_out,
+            "trees":consensus_files.cons_nwk_file,
+        },
+        "config":run_config,
+    });
+    let manifest_file = File::create(PathBuf::from(out).join("manifest.json"))
+        .expect("could not create manifest.json");
+    serde_json::to_writer_pretty(manifest_file, &manifest)?;
     Ok(true)
 }
 
 fn do_collapse(sub_m: &ArgMatches) -> Result<bool, io::Error> {
-    let sal_dir: String = sub_m.value_of("dirs").unwrap().to_string();
+    let sal_dir: String = sub_m.get_one::<String>("dirs").unwrap().to_string();
     let _md = metadata(sal_dir.clone()).unwrap_or_else(|_| panic!("Invalid directory {}", sal_dir));
+    let prefix: String = sub_m.get_one::<String>("out").unwrap().to_string();
+    let compression = sub_m
+        .get_one::<String>("compress")
+        .unwrap()
+        .parse::<util::OutputCompression>()
+        .expect("could not parse compression");
+
+    let file_list =
+        salmon_types::FileList::new(salmon_dirs(&sal_dir)?[0].to_str().unwrap().to_string());
+    let tnames = util::parse_eq(&file_list.eq_file)?.targets;
+    let groups = match sub_m.get_one::<String>("samples") {
+        Some(spec) => Some(sample_groups(spec)?),
+        None => None,
+    };
+    let resample = sub_m.get_one::<String>("resample").map(|n| Resample {
+        resampling: sub_m
+            .get_one::<String>("resample-method")
+            .unwrap()
+            .parse::<collapse::Resampling>()
+            .expect("could not parse resample-method"),
+        nresamples: n
+            .parse::<usize>()
+            .expect("could not convert resample to an integer"),
+        seed: sub_m
+            .get_one::<String>("seed")
+            .unwrap()
+            .parse::<u64>()
+            .expect("generate random values from the seed"),
+    });
+    consensus_trees(
+        &sal_dir,
+        &prefix,
+        &prefix,
+        &tnames,
+        compression,
+        sub_m.get_one::<String>("leaf-labels").unwrap() == "name",
+        groups.as_deref(),
+        resample.as_ref(),
+    )?;
 
-    let sal_dir_paths = read_dir(sal_dir)?
-        .map(|res| res.map(|e| e.path()))
-        .filter(|res| res.as_ref().unwrap().is_dir())
-        .collect::<Result<Vec<_>, io::Error>>()?;
+    // filter based on the threshold
+
+    let params = json!({
+        "groups":groups.map(|gs| gs
+            .iter()
+            .map(|g| json!({"name":g.name, "samples":g.samples}))
+            .collect::<Vec<_>>()),
+        "config":config::effective_config(sub_m, config::CONSENSUS_PARAMS),
+    });
+    let param_log_file = File::create(ConsensusFileList::new(prefix).param_log_file)
+        .expect("could not create the consensus parameter log");
+    serde_json::to_writer(param_log_file, &params)?;
+
+    Ok(true)
+}
 
-    let mut dir_paths: Vec<&str> = Vec::new();
-    for entry in sal_dir_paths.iter() {
-        dir_paths.push(entry.as_path().to_str().unwrap());
-    }
-    let prefix: String = sub_m.value_of("out").unwrap().to_string();
-
-    let mut bipart_counter: HashMap<String, HashMap<String, u32>> = HashMap::new();
-    let mut group_keys: Vec<String> = Vec::new();
-    // let mut l = 0; // num of groups in 1st
-    let mut ntxps = 0; // num of transcripts
-    let mut tnames: Vec<String> = Vec::new();
-    // add edges
-    for (i, dname) in dir_paths.iter().enumerate() {
-        //let dname = dname.as_path().to_str().unwrap();
-        let compo: Vec<&str> = dname.rsplit('/').collect();
-        if compo[0].starts_with('.') {
+/// Samples whose per-sample trees make up one consensus, written with the
+/// name of the group in the file names when it has one
+struct SampleGroup {
+    name: Option<String>,
+    samples: Vec<String>,
+}
+
+// Reads `--samples`, either a sample sheet whose lines give a sample and a
+// group it belongs to, or a comma-separated list of samples forming a
+// single group
+fn sample_groups(spec: &str) -> Result<Vec<SampleGroup>, io::Error> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if !Path::new(spec).is_file() {
+        let samples: Vec<String> = spec
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+        if samples.is_empty() {
+            return Err(invalid(format!("no samples in {:?}", spec)));
+        }
+        return Ok(vec![SampleGroup {
+            name: None,
+            samples,
+        }]);
+    }
+    let mut groups: Vec<SampleGroup> = Vec::new();
+    for (i, line) in read_to_string(spec)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
             continue;
         }
-        let experiment_name = compo[0];
-        println!("experiment name {}", experiment_name);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 2 || fields[1].contains('/') {
+            return Err(invalid(format!(
+                "line {} of {} does not give a sample and its group",
+                i + 1,
+                spec
+            )));
+        }
+        match groups
+            .iter_mut()
+            .find(|g| g.name.as_deref() == Some(fields[1]))
+        {
+            Some(g) => g.samples.push(fields[0].to_string()),
+            None => groups.push(SampleGroup {
+                name: Some(fields[1].to_string()),
+                samples: vec![fields[0].to_string()],
+            }),
+        }
+    }
+    if groups.is_empty() {
+        return Err(invalid(format!("{} lists no samples", spec)));
+    }
+    Ok(groups)
+}
 
-        let mut dir_bipart_counter: HashMap<String, HashMap<String, u32>> = HashMap::new(); // Storing counts of each bipartition
+/// The resamples of the samples drawn by `consensus --resample` to assess
+/// the support of the clades of the consensus trees
+struct Resample {
+    resampling: collapse::Resampling,
+    nresamples: usize,
+    seed: u64,
+}
 
-        let mut prefix_path = prefix.clone();
+/// Builds the consensus trees of the samples of `sal_dir` into `prefix`,
+/// from their per-sample trees found below `trees`, one for each of
+/// `groups` or a single one of all the samples
+#[allow(clippy::too_many_arguments)]
+fn consensus_trees(
+    sal_dir: &str,
+    trees: &str,
+    prefix: &str,
+    tnames: &[String],
+    compression: util::OutputCompression,
+    leaf_names: bool,
+    groups: Option<&[SampleGroup]>,
+    resample: Option<&Resample>,
+) -> Result<bool, io::Error> {
+    let mut names: Vec<String> = Vec::new();
+    for entry in read_dir(sal_dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if path.is_dir() && !name.starts_with('.') {
+            names.push(name);
+        }
+    }
+    let all_samples = [SampleGroup {
+        name: None,
+        samples: names.clone(),
+    }];
+    let groups = groups.unwrap_or(&all_samples);
+    for group in groups.iter() {
+        if group.samples.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                match &group.name {
+                    Some(g) => format!("group {} has no samples", g),
+                    None => format!("no samples in {}", sal_dir),
+                },
+            ));
+        }
+        for (i, sample) in group.samples.iter().enumerate() {
+            if !names.contains(sample) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("sample {} is not in {}", sample, sal_dir),
+                ));
+            }
+            if group.samples[..i].contains(sample) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("sample {} is listed twice in a group", sample),
+                ));
+            }
+        }
+    }
+
+    // the trees of each sample are read once, whatever the groups it is in
+    let mut samp_group_trees: HashMap<&str, (binary_tree::TreeArena, HashMap<Vec<u32>, usize>)> =
+        HashMap::new();
+    let ntxps = tnames.len(); // num of transcripts
+    for experiment_name in names
+        .iter()
+        .filter(|n| groups.iter().any(|g| g.samples.contains(n)))
+    {
+        info!("experiment name {}", experiment_name);
+
+        let mut dir_bipart_counter: HashMap<Vec<u32>, HashMap<Vec<u32>, u32>> = HashMap::new(); // Storing counts of each bipartition
+
+        let mut prefix_path = trees.to_string();
         prefix_path.push('/');
         prefix_path.push_str(experiment_name);
         create_dir_all(prefix_path.clone())?;
         let file_list_out = salmon_types::FileList::new(prefix_path);
-        let file = File::open(file_list_out.collapse_order_file);
-        let reader = BufReader::new(file.unwrap());
-
-        let mut deserializer = serde_json::Deserializer::from_reader(reader);
-        deserializer.disable_recursion_limit();
-
-        //println!("{:?}", deserializer);
-        let collapse_order: HashMap<String, binary_tree::TreeNode> =
-            HashMap::deserialize(&mut deserializer).unwrap(); // can be replaced by vector
-        for key in collapse_order.keys() {
-            let node = collapse_order.get(key).unwrap();
-            let req_group = binary_tree::sort_group_id(&node.id);
-            //let node_vec = group_bipart.entry(node.id.clone()).or_insert(Vec::<String>::new());
-            let dir_group_key = dir_bipart_counter
-                .entry(req_group.clone())
-                .or_default();
-            let overall_group_key = bipart_counter
-                .entry(req_group.clone())
-                .or_default();
-
-            //binary_tree::compute_bipart_count(node, &mut bipart_counter, &mut dir_bipart_counter, &node_set, node_vec);
-            group_keys.push(req_group.clone());
-            binary_tree::compute_bipart_count2(node, overall_group_key, dir_group_key);
-        }
-        if i == 0 {
-            let file_old = salmon_types::FileList::new(dname.to_string());
-            let eq_class = util::parse_eq(&file_old.eq_file).unwrap();
-            ntxps = eq_class.ntarget;
-
-            // for i in 0..eq_class.targets.len()
-            // {
-            //     tnames.push(i.to_string());
-            // }
-            tnames.extend(eq_class.targets.clone());
-        }
-        println!(
+        let collapse_order = util::read_collapse_order(&file_list_out);
+        for root in collapse_order.roots.iter() {
+            let mut bparts = HashMap::new();
+            let group = binary_tree::compute_bipart_count(&collapse_order, *root, &mut bparts);
+            dir_bipart_counter.insert(group, bparts);
+        }
+        info!(
             "Number of groups in {} are {}",
-            dname,
+            experiment_name,
             dir_bipart_counter.len()
         );
-        let mut bipart_file = File::create(file_list_out.group_bp_splits_file)
-            .expect("could not create group bp splits");
-        let _f = util::bipart_writer(&dir_bipart_counter, &mut bipart_file, &tnames);
+        let mut bipart_file = util::create_writer(&file_list_out.group_bp_splits_file, compression);
+        util::bipart_writer(&dir_bipart_counter, &mut bipart_file, tnames)?;
+        bipart_file.finish()?;
+        util::names_writer(&file_list_out.tree_names_file, tnames)?;
+        let roots = collapse_order.group_roots();
+        samp_group_trees.insert(experiment_name, (collapse_order, roots));
     }
-    let all_groups: Vec<String> = bipart_counter.keys().cloned().collect();
-    collapse::use_phylip(&dir_paths, &prefix, &all_groups, ntxps);
 
-    // filter based on the threshold
+    for group in groups.iter() {
+        let file_list_out = ConsensusFileList::new(prefix.to_string());
+        let mut mgroup_nwk_files = Vec::new();
+        for sample in group.samples.iter() {
+            let path = salmon_types::FileList::new(format!("{}/{}", trees, sample)).mgroup_nwk_file;
+            mgroup_nwk_files.push(match &group.name {
+                Some(g) => salmon_types::group_path(&path, g),
+                None => path,
+            });
+        }
+        let file_list_out = match &group.name {
+            Some(g) => {
+                info!(
+                    "computing the consensus of {} from {} samples",
+                    g,
+                    group.samples.len()
+                );
+                file_list_out.of_group(g)
+            }
+            None => file_list_out,
+        };
+        let sample_trees: Vec<_> = group
+            .samples
+            .iter()
+            .map(|s| &samp_group_trees[s.as_str()])
+            .collect();
+        let cons_trees = collapse::use_phylip(
+            &sample_trees,
+            &mgroup_nwk_files,
+            &prefix.to_string(),
+            &file_list_out,
+            ntxps,
+            tnames,
+            leaf_names,
+        );
+        if let Some(r) = resample {
+            let support = collapse::clade_support(
+                &cons_trees,
+                &sample_trees,
+                r.resampling,
+                r.nresamples,
+                r.seed,
+                &prefix.to_string(),
+                ntxps,
+            );
+            let mut support_file = File::create(&file_list_out.clade_support_file)?;
+            collapse::clade_support_writer(&mut support_file, &support, tnames, leaf_names)?;
+        }
+    }
+    Ok(true)
+}
 
+fn do_convert(sub_m: &ArgMatches) -> Result<bool, io::Error> {
+    let input = PathBuf::from(sub_m.get_one::<String>("input").unwrap());
+    let output = PathBuf::from(sub_m.get_one::<String>("output").unwrap());
+    let compression = sub_m
+        .get_one::<String>("compress")
+        .unwrap()
+        .parse::<util::OutputCompression>()
+        .expect("could not parse compression");
+
+    let to = sub_m.get_one::<String>("to").unwrap().as_str();
+    let from = sub_m
+        .get_one::<String>("from")
+        .map_or(if to == "bin" { "json" } else { "bin" }, String::as_str);
+    let trees: HashMap<String, binary_tree::TreeNode> = match from {
+        "json" => util::read_collapse_order_json(&input),
+        "bin" => util::collapse_order_to_map(&util::read_collapse_order_bin(&input)),
+        "nwk" => {
+            let names =
+                read_tree_names(&input, sub_m.get_one::<String>("names").map(String::as_str));
+            let mut trees = HashMap::new();
+            for (i, t) in newick::read_newick_file(&input)?.iter().enumerate() {
+                let tree = nary_tree::NaryNode::from_newick(t, &names)
+                    .map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("tree {} of {:?}: {}", i, input, e),
+                        )
+                    })?
+                    .to_binary();
+                trees.insert(binary_tree::group_id(&tree.leaves()), tree);
+            }
+            trees
+        }
+        _ => unreachable!(),
+    };
+
+    let mut out_file = util::create_writer(&output, compression);
+    match to {
+        "bin" => {
+            let co = binary_tree::CollapseOrder {
+                version: binary_tree::COLLAPSE_ORDER_VERSION,
+                trees: trees.values().map(|t| t.to_postorder()).collect(),
+                stats: trees.values().map(|t| t.postorder_stats()).collect(),
+            };
+            info!("converted {} trees to the compact format", co.trees.len());
+            util::write_collapse_order_bin(&mut out_file, &co)?;
+        }
+        "json" => {
+            info!("converted {} trees to JSON", trees.len());
+            util::write_collapse_order_json(&mut out_file, &trees)?;
+        }
+        "nwk" => {
+            let opts = binary_tree::NewickOptions {
+                nhx: true,
+                ..Default::default()
+            };
+            for tree in trees.values() {
+                let nwk = nary_tree::NaryNode::from_binary(tree).to_newick_string(&opts);
+                writeln!(out_file, "{}", nwk)?;
+            }
+            info!("converted {} trees to Newick", trees.len());
+        }
+        _ => unreachable!(),
+    }
+    out_file.finish()?;
     Ok(true)
 }
 
-// The entry point of the program
-// that reads from the files and build
-// graphs or later produces the collapsed
-// files.
+// The infRV definition given by `--infrv`, `--infrv-pseudocount` and
+// `--infrv-offset`
+fn infrv_def(sub_m: &ArgMatches) -> util::InfRV {
+    let def = util::InfRV {
+        measure: sub_m
+            .get_one::<String>("infrv")
+            .unwrap()
+            .parse::<util::InfRVMeasure>()
+            .unwrap_or_else(|e| panic!("{}", e)),
+        pseudocount: sub_m
+            .get_one::<String>("infrv-pseudocount")
+            .unwrap()
+            .parse::<f64>()
+            .expect("could not parse the infRV pseudocount"),
+        offset: sub_m
+            .get_one::<String>("infrv-offset")
+            .unwrap()
+            .parse::<f64>()
+            .expect("could not parse the infRV offset"),
+    };
+    if def.measure == util::InfRVMeasure::LogVar && def.pseudocount <= 0.0 {
+        panic!("the logvar infRV measure requires a positive pseudocount");
+    }
+    def
+}
 
-fn main() -> io::Result<()> {
-    let matches = App::new("TreeTerminus")
-	.setting(AppSettings::ArgRequiredElseHelp)
+// The arguments defining the infRV, shared by the subcommands computing it
+fn infrv_args() -> Vec<Arg> {
+    vec![
+        Arg::new("infrv")
+            .long("infrv")
+            .value_parser(["swish", "cv", "logvar", "interval"])
+            .default_value("swish")
+            .help("measure of inferential uncertainty: the inferential relative variance of Swish (var - mean) / (mean + pseudocount), the coefficient of variation sd / (mean + pseudocount), the variance of log(count + pseudocount), or the width of the central 95% interval of the replicates / (mean + pseudocount); the offset is then added"),
+        Arg::new("infrv-pseudocount")
+            .long("infrv-pseudocount")
+            .default_value("5")
+            .help("pseudocount of the infRV measure"),
+        Arg::new("infrv-offset")
+            .long("infrv-offset")
+            .default_value("0.01")
+            .help("offset added to the infRV measure, which is also its minimum"),
+    ]
+}
+
+// The rules given by `--aggregate` for combining the statistics of the
+// samples, the defaults holding for the families not given
+fn aggregation(sub_m: &ArgMatches) -> util::Aggregation {
+    let mut agg = util::Aggregation::default();
+    for spec in sub_m.get_many::<String>("aggregate").into_iter().flatten() {
+        agg.set(spec).unwrap_or_else(|e| panic!("{}", e));
+    }
+    agg
+}
+
+fn aggregate_arg() -> Arg {
+    Arg::new("aggregate")
+        .long("aggregate")
+        .action(ArgAction::Append)
+        .help("rule combining the statistics of the samples as <family>=<rule>, repeatable; the families are infrv, mean, spread, score, threshold, percentile and txp_mean, the rules max, min, mean, median and quantile:<q>; defaults to infrv=max, mean=max, spread=max, score=mean, threshold=mean, percentile=min and txp_mean=mean")
+}
+
+fn jobs_arg() -> Arg {
+    Arg::new("jobs")
+        .long("jobs")
+        .short('j')
+        .value_parser(|j: &str| match j.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(j.to_string()),
+            _ => Err(format!("{} is not a positive number of jobs", j)),
+        })
+        .help("the number of samples grouped at once, all the available cores by default; each holds its replicates in memory, 8 bytes per transcript and replicate")
+}
+
+fn config_arg() -> Arg {
+    Arg::new("config")
+        .long("config")
+        .help("TOML or JSON file setting the parameters by their long names, in a table named after the subcommand or at the top level; the parameters log of an earlier run may be given to repeat it, and the flags on the command line override the file")
+}
+
+// Returns the salmon directory `dname`, or every salmon directory below it
+// if it is an experiment directory
+fn salmon_dirs(dname: &str) -> Result<Vec<PathBuf>, io::Error> {
+    let mut dir_paths: Vec<PathBuf> = Vec::new();
+    if PathBuf::from(dname).join("quant.sf").is_file() {
+        dir_paths.push(PathBuf::from(dname));
+    } else {
+        for entry in read_dir(dname)? {
+            let path = entry?.path();
+            if path.join("quant.sf").is_file() {
+                dir_paths.push(path);
+            }
+        }
+        dir_paths.sort();
+    }
+    if dir_paths.is_empty() {
+        panic!("no salmon directory found in {}", dname);
+    }
+    Ok(dir_paths)
+}
+
+// Loads the inputs and the replicates of each salmon directory
+fn load_samples(dir_paths: &[PathBuf]) -> Result<(Vec<SampleInput>, Vec<Array2<f64>>), io::Error> {
+    let loaded = dir_paths
+        .iter()
+        .map(|d| load_sample(d.to_str().unwrap()))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    Ok(loaded.into_iter().unzip())
+}
+
+// Loads the inferential replicates of each salmon directory
+fn load_gibbs_arrays(dir_paths: &[PathBuf]) -> Result<Vec<Array2<f64>>, io::Error> {
+    let mut gibbs_array_vec = Vec::with_capacity(dir_paths.len());
+    for dir in dir_paths.iter() {
+        let file_list = salmon_types::FileList::new(dir.to_str().unwrap().to_string());
+        let x = util::parse_json(&file_list.mi_file)?;
+        let mut gibbs_array =
+            Array2::<f64>::zeros((x.num_valid_targets as usize, x.num_bootstraps as usize));
+        util::read_gibbs_array(&file_list.bootstrap_file, &x, &mut gibbs_array);
+        gibbs_array_vec.push(gibbs_array);
+    }
+    Ok(gibbs_array_vec)
+}
+
+// Reads the index to name mapping of the leaves of the trees in
+// `trees_path`, from `names` or names.tsv next to the file if present
+fn read_tree_names(trees_path: &Path, names: Option<&str>) -> HashMap<String, usize> {
+    let names_path = match names {
+        Some(p) => PathBuf::from(p),
+        None => trees_path.with_file_name("names.tsv"),
+    };
+    match util::resolve_compressed_path(&names_path) {
+        Some(_) => util::names_reader(&names_path),
+        None => HashMap::new(),
+    }
+}
+
+// Reads the trees of a Newick file, along with the index to name mapping
+// of their leaves
+fn read_cut_trees(
+    trees_path: &str,
+    names: Option<&str>,
+) -> Result<(Vec<cut::FlatTree>, HashMap<String, usize>), io::Error> {
+    let trees_path = PathBuf::from(trees_path);
+    let names = read_tree_names(&trees_path, names);
+
+    let trees: Vec<cut::FlatTree> = newick::read_newick_file(&trees_path)?
+        .iter()
+        .map(cut::FlatTree::from_newick)
+        .collect();
+    info!("read {} trees from {:?}", trees.len(), trees_path);
+    Ok((trees, names))
+}
+
+// An error in the tree `i` of the file `path`
+fn tree_error(i: usize, path: &str, e: newick::NewickError) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("tree {} of {:?}: {}", i, path, e),
+    )
+}
+
+fn do_cut(sub_m: &ArgMatches) -> Result<bool, io::Error> {
+    let objective = sub_m
+        .get_one::<String>("objective")
+        .unwrap()
+        .parse::<cut::Objective>()
+        .unwrap_or_else(|e| panic!("{}", e));
+    let trees_path = sub_m.get_one::<String>("trees").unwrap();
+    let (trees, names) = read_cut_trees(
+        trees_path,
+        sub_m.get_one::<String>("names").map(String::as_str),
+    )?;
+    let gibbs_array_vec = match sub_m.get_one::<String>("dirs") {
+        Some(dname) => load_gibbs_arrays(&salmon_dirs(dname)?)?,
+        None => Vec::new(),
+    };
+    let infrv_def = infrv_def(sub_m);
+    let agg = aggregation(sub_m);
+
+    let mut out_file = File::create(sub_m.get_one::<String>("output").unwrap())?;
+    writeln!(out_file, "tree\tn_leaves\tinfRV\tmean\tleaves")?;
+    let pb = util::progress_bar(trees.len() as u64, "cutting trees");
+    let mut ngroups = 0;
+    let mut total = 0.0;
+    for (i, tree) in trees.iter().enumerate() {
+        let stats = cut::tree_stats(tree, &names, &gibbs_array_vec, &infrv_def, &agg)
+            .map_err(|e| tree_error(i, trees_path, e))?;
+        let (nodes, score) = cut::optimal_cut(tree, &stats, objective);
+        cut::cut_writer(&mut out_file, i, tree, &stats, &nodes)?;
+        ngroups += nodes.len();
+        total += score;
+        pb.inc(1);
+    }
+    pb.finish_and_clear();
+    info!(
+        "cut {} trees into {} groups, total score {}",
+        trees.len(),
+        ngroups,
+        total
+    );
+    Ok(true)
+}
+
+fn do_flatten(sub_m: &ArgMatches) -> Result<bool, io::Error> {
+    let level = sub_m
+        .get_one::<String>("level")
+        .unwrap()
+        .parse::<cut::FlattenLevel>()
+        .unwrap_or_else(|e| panic!("{}", e));
+    let prefix = PathBuf::from(sub_m.get_one::<String>("out").unwrap());
+    let trees_path = sub_m.get_one::<String>("trees").unwrap();
+    let (trees, names) = read_cut_trees(
+        trees_path,
+        sub_m.get_one::<String>("names").map(String::as_str),
+    )?;
+    let dir_paths = salmon_dirs(sub_m.get_one::<String>("dirs").unwrap())?;
+    let gibbs_array_vec = load_gibbs_arrays(&dir_paths)?;
+    let infrv_def = infrv_def(sub_m);
+    let agg = aggregation(sub_m);
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (i, tree) in trees.iter().enumerate() {
+        let stats = match level {
+            cut::FlattenLevel::InfRV(_) => {
+                cut::tree_stats(tree, &names, &gibbs_array_vec, &infrv_def, &agg)
+                    .map_err(|e| tree_error(i, trees_path, e))?
+            }
+            _ => Vec::new(),
+        };
+        for v in cut::flatten_cut(tree, &stats, level) {
+            let leaves = tree.leaf_labels(v);
+            if leaves.len() > 1 {
+                groups.push(
+                    leaves
+                        .iter()
+                        .map(|l| cut::leaf_index(l, &names))
+                        .collect::<Result<_, _>>()
+                        .map_err(|e| tree_error(i, trees_path, e))?,
+                );
+            }
+        }
+    }
+    info!(
+        "flattened {} trees into {} groups of {} transcripts",
+        trees.len(),
+        groups.len(),
+        groups.iter().map(|g| g.len()).sum::<usize>()
+    );
+
+    for (dir, gibbs_array) in dir_paths.iter().zip(gibbs_array_vec.iter()) {
+        let file_list = salmon_types::FileList::new(dir.to_str().unwrap().to_string());
+        let x = util::parse_json(&file_list.mi_file)?;
+        let out_dir = prefix.join(dir.file_name().unwrap());
+        info!("writing the collapsed quantification to {:?}", out_dir);
+        terminus::write_collapsed(&file_list, &x, gibbs_array, &groups, &out_dir)?;
+    }
+    Ok(true)
+}
+
+fn do_compare(sub_m: &ArgMatches) -> Result<bool, io::Error> {
+    let clades = |path: &str| {
+        let (trees, names) =
+            read_cut_trees(path, sub_m.get_one::<String>("names").map(String::as_str))?;
+        trees
+            .iter()
+            .enumerate()
+            .map(|(i, t)| compare::TreeClades::new(t, &names).map_err(|e| tree_error(i, path, e)))
+            .collect::<Result<Vec<_>, io::Error>>()
+    };
+    let a = clades(sub_m.get_one::<String>("first").unwrap())?;
+    let b = clades(sub_m.get_one::<String>("second").unwrap())?;
+
+    let comparisons = compare::compare_trees(&a, &b);
+    let mut out_file = File::create(sub_m.get_one::<String>("output").unwrap())?;
+    compare::comparison_writer(&mut out_file, &comparisons)?;
+
+    let summary = compare::summarize(&comparisons);
+    info!(
+        "matched {} of {} and {} trees ({} and {} unmatched)",
+        summary.n_matched,
+        summary.n_trees_a,
+        summary.n_trees_b,
+        summary.n_unmatched_a,
+        summary.n_unmatched_b
+    );
+    info!("mean leaf Jaccard index : {}", summary.mean_leaf_jaccard);
+    info!(
+        "mean normalized RF distance : {}",
+        summary.mean_normalized_rf
+    );
+    info!(
+        "overall normalized RF distance : {}",
+        summary.overall_normalized_rf
+    );
+    info!("shared clade fraction : {}", summary.shared_clade_fraction);
+    if let Some(p) = sub_m.get_one::<String>("summary") {
+        serde_json::to_writer_pretty(File::create(p)?, &summary)?;
+    }
+    Ok(true)
+}
+
+// Accepts the salmon directory of a single sample
+fn sample_dir(d: &str) -> Result<String, String> {
+    if Path::new(d).join("quant.sf").is_file() {
+        Ok(d.to_string())
+    } else {
+        Err(format!(
+            "{} is not a salmon directory, as it holds no quant.sf; `group mean` takes a directory of salmon directories",
+            d
+        ))
+    }
+}
+
+// Accepts a directory whose subdirectories are the salmon directories of
+// the samples
+fn experiment_dir(d: &str) -> Result<String, String> {
+    if Path::new(d).join("quant.sf").is_file() {
+        return Err(format!(
+            "{} is the salmon directory of a single sample; `group sample` groups it alone",
+            d
+        ));
+    }
+    let entries = read_dir(d).map_err(|e| format!("could not read {}: {}", d, e))?;
+    let mut nsamples = 0;
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        if !path.join("quant.sf").is_file() {
+            return Err(format!("{} is not a salmon directory", path.display()));
+        }
+        nsamples += 1;
+    }
+    if nsamples == 0 {
+        return Err(format!("{} holds no salmon directories", d));
+    }
+    Ok(d.to_string())
+}
+
+// The arguments shared by the modes of `group`
+fn group_args() -> Vec<Arg> {
+    let mut args = vec![
+        config_arg(),
+        Arg::new("min-spread")
+            .long("min-spread")
+            .short('m')
+            .default_value("0.1")
+            .help("the minimum spread a transcript must exhibit to enable an \
+                  attached edge to be a collapse candidate"),
+        Arg::new("min-mean")
+            .long("min-mean")
+            .default_value("1.0")
+            .help("the mean count a transcript must exceed to enable an \
+                  attached edge to be a collapse candidate"),
+        Arg::new("eq-weight-frac")
+            .long("eq-weight-frac")
+            .default_value("0.1")
+            .help("transcripts of an equivalence class of n transcripts are \
+                  only linked when their weight is at least this fraction of 1/n"),
+        Arg::new("edge-count-frac")
+            .long("edge-count-frac")
+            .default_value("1.0")
+            .help("an edge is kept, and its endpoints merged, only when its \
+                  equivalence class count exceeds this fraction of the smaller \
+                  mean count of its endpoints"),
+        Arg::new("min-replicate-sum")
+            .long("min-replicate-sum")
+            .default_value("1.0")
+            .help("the sum over the inferential replicates a transcript must \
+                  exceed to be used for the infRV percentile and threshold"),
+        Arg::new("tolerance")
+            .long("tolerance")
+            .default_value("0.001")
+            .help("The allowable difference between the weights of transcripts \
+                  in same equivalence classes to treat them as identical"),
+        Arg::new("seed")
+            .long("seed")
+            .default_value("10")
+            .help("seed for random generator"),
+        Arg::new("a2t")
+            .long("a2t")
+            .default_value("")
+            .help("Mapping allele to transcript"),
+        Arg::new("keep-alleles")
+            .long("keep-alleles")
+            .action(ArgAction::SetTrue)
+            .help("with --a2t, do not merge the alleles of a transcript up front, building the trees over the alleles so that those of a transcript only join each other when their reduction in inferential variance allows it"),
+        Arg::new("t2g")
+            .long("t2g")
+            .default_value("")
+            .help("Mapping transcript to gene"),
+        Arg::new("gene-map")
+            .long("gene-map")
+            .default_value("")
+            .help("Mapping transcript to gene used only to annotate the trees with their genes, without restricting the collapses as --t2g does (ignored when --t2g is given)"),
+        Arg::new("thr")
+            .long("thr")
+            .action(ArgAction::SetTrue)
+            .help("only collapse when the reduction in inferential variance passes the threshold given by --red_perc"),
+        Arg::new("out")
+            .long("out")
+            .short('o')
+            .required(true)
+            .help("prefix where output would be written"),
+        Arg::new("inf_perc")
+            .long("inf_perc")
+            .short('i')
+            .default_value("0")
+            .help("inferential variance percentile threshold that determines whether a transcript will be considered for grouping [0-1]"),
+        Arg::new("red_perc")
+            .long("red_perc")
+            .default_value("0.025")
+            .help("Reduction in inferential variance percentile threshold that determines to detemine if transcripts/groups should be aggregated [0,1]"),
+    ];
+    args.extend(infrv_args());
+    args.extend([
+        aggregate_arg(),
+        Arg::new("normalize")
+            .long("normalize")
+            .value_parser(["none", "total", "mor"])
+            .default_value("none")
+            .help("in the mean mode, divide the replicates of each sample by its library size factor, from the total NumReads of quant.sf or their median of ratios, before computing infRV, means and spreads; the factors are written to norm_factors.tsv"),
+        Arg::new("cross-gene-red-perc")
+            .long("cross-gene-red-perc")
+            .help("with --t2g, allow transcripts/groups of different genes to be aggregated when their reduction in inferential variance passes this stricter percentile threshold [0,1]; such collapses are logged to cross_gene_collapses.log"),
+        Arg::new("compress")
+            .long("compress")
+            .value_parser(["none", "gzip", "zstd"])
+            .default_value("none")
+            .help("compression applied to the large output files (logs and collapse order)"),
+        Arg::new("leaf-labels")
+            .long("leaf-labels")
+            .value_parser(["index", "name"])
+            .default_value("index")
+            .help("label the leaves of the output trees by transcript index or by transcript name; the mapping is written to names.tsv"),
+        Arg::new("branch-lengths")
+            .long("branch-lengths")
+            .action(ArgAction::SetTrue)
+            .help("write the reduction in inferential relative variance at each merge as the branch lengths of the output trees"),
+        Arg::new("nhx")
+            .long("nhx")
+            .action(ArgAction::SetTrue)
+            .help("annotate the nodes of the output trees with their inferential relative variance and mean count as NHX tags"),
+    ]);
+    args
+}
+
+// The command line interface, also used to generate the shell completions
+// and man pages
+fn cli() -> Command {
+    Command::new(PROGRAM_NAME)
+        .arg_required_else_help(true)
+        // the flags on the command line override those of --config
+        .args_override_self(true)
         .version("0.3.0")
         .author("Singh et al.")
-        // .about("Data-driven grouping of transcripts to reduce inferential uncertainty")
+        .about("Data-driven grouping of transcripts to reduce inferential uncertainty")
+        .arg(
+            Arg::new("verbose")
+                .long("verbose")
+                .short('v')
+                .action(ArgAction::Count)
+                .global(true)
+                .conflicts_with("quiet")
+                .help("increase the logging verbosity (-v for debug, -vv for trace messages)")
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .short('q')
+                .action(ArgAction::SetTrue)
+                .global(true)
+                .help("only log warnings and errors")
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .global(true)
+                .help("also write the log messages to this file")
+        )
         .subcommand(
-            SubCommand::with_name("group")
-            .about("perform per-sample grouping of transcripts; required prior to consensus collapse.")
+            Command::new("group")
+            .about("perform grouping of transcripts into trees; per-sample trees are required prior to consensus collapse.")
+            .subcommand_required(true)
+            .subcommand(
+                Command::new("sample")
+                .about("build the trees of a single sample, written to <out>/<sample>/group_nwk.txt")
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .short('d')
+                        .required(true)
+                        .value_parser(sample_dir)
+                        .help("salmon directory of the sample")
+                )
+                .args(group_args())
+            )
+            .subcommand(
+                Command::new("mean")
+                .about("build the Mean trees of all the samples of an experiment from their combined statistics, written to <out>/cluster_nwk.txt")
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .short('d')
+                        .required(true)
+                        .value_parser(experiment_dir)
+                        .help("directory holding the salmon directory of each sample")
+                )
+                .args(group_args())
+            )
+            .subcommand(
+                Command::new("per-sample")
+                .about("build the trees of every sample of an experiment on its own, several samples at a time, as `group sample` would one by one")
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .short('d')
+                        .required(true)
+                        .value_parser(experiment_dir)
+                        .help("directory holding the salmon directory of each sample")
+                )
+                .args(group_args())
+                .arg(jobs_arg())
+                .arg(
+                    Arg::new("max-memory")
+                        .long("max-memory")
+                        .help("lower the number of jobs so that the replicates of the samples grouped at once fit in this many gigabytes")
+                )
+                .arg(
+                    Arg::new("consensus")
+                        .long("consensus")
+                        .action(ArgAction::SetTrue)
+                        .help("build the consensus trees of the experiment once its samples are grouped")
+                )
+            )
+        )
+        .subcommand(
+            Command::new("run")
+            .about("build the per-sample, Mean and Consensus trees of an experiment from a single reading of its samples, written to <out>/samples, <out>/mean and <out>/consensus and listed in <out>/manifest.json")
             .arg(
-                Arg::with_name("dir")
+                Arg::new("dir")
                     .long("dir")
-                    .short("d")
+                    .short('d')
                     .required(true)
-                    .takes_value(true)
-                    .help("directory to read input from")
+                    .value_parser(experiment_dir)
+                    .help("directory holding the salmon directory of each sample")
             )
+            .args(group_args())
+            .arg(jobs_arg())
             .arg(
-                Arg::with_name("min-spread")
-                    .long("min-spread")
-                    .short("m")
-                    .takes_value(true)
-                    .default_value("0.1")
-                    .help("the minimum spread a transcript must exhibit to enable an \
-                          attached edge to be a collapse candidate")
+                Arg::new("max-memory")
+                    .long("max-memory")
+                    .help("lower the number of per-sample jobs so that the replicates of all the samples, held throughout, and a copy for each sample grouped at once fit in this many gigabytes")
             )
+        )
+        .subcommand(
+            Command::new("consensus")
+            .about("Produce a set of consensus trees from the individual per-sample trees obtained for an RNA-Seq experiment after running the group step.")
+            .arg(config_arg())
             .arg(
-                Arg::with_name("tolerance")
-                    .long("tolerance")
-                    .takes_value(true)
-                    .default_value("0.001")
-                    .help("The allowable difference between the weights of transcripts \
-                          in same equivalence classes to treat them as identical")
+                Arg::new("dirs")
+                    .long("dirs")
+                    .short('d')
+                    .required(true)
+                    .help("direcotories to read the group files from")
             )
             .arg(
-                Arg::with_name("seed")
-                    .long("seed")
-                    .takes_value(true)
-                    .default_value("10")
-                    .help("seed for random generator")
+                Arg::new("out")
+                    .long("out")
+                    .short('o')
+                    .required(true)
+                    .requires("dirs")
+                    .help("prefix where output would be written")
             )
             .arg(
-                Arg::with_name("a2t")
-                    .long("a2t")
-                    .takes_value(true)
-                    .default_value("")
-                    .help("Mapping allele to transcript")
+                Arg::new("compress")
+                .long("compress")
+                .value_parser(["none", "gzip", "zstd"])
+                .default_value("none")
+                .help("compression applied to the bipartition split files")
             )
             .arg(
-                Arg::with_name("t2g")
-                    .long("t2g")
-                    .takes_value(true)
-                    .default_value("")
-                    .help("Mapping transcript to gene")
+                Arg::new("leaf-labels")
+                .long("leaf-labels")
+                .value_parser(["index", "name"])
+                .default_value("index")
+                .help("label the leaves of the output trees by transcript index or by transcript name; the mapping is written to names.tsv")
             )
             .arg(
-                Arg::with_name("thresh")
-                    .long("thr")
-                    .takes_value(true)
-                    .default_value("false")
-                    .help("should a threshold be used for collapsing")
+                Arg::new("samples")
+                .long("samples")
+                .help("the samples to build the consensus of, as a comma-separated list, or a sample sheet whose lines give a sample and its group, to build the consensus of each group into cluster_nwk.<group>.txt")
             )
             .arg(
-                Arg::with_name("out")
-                    .long("out")
-                    .short("o")
+                Arg::new("resample")
+                .long("resample")
+                .value_parser(|n: &str| match n.parse::<usize>() {
+                    Ok(k) if k > 0 => Ok(n.to_string()),
+                    _ => Err(format!("{} is not a positive number of resamples", n)),
+                })
+                .help("build the consensus of this many resamples of the samples and write the fraction of them holding each clade of the consensus trees to clade_support.tsv")
+            )
+            .arg(
+                Arg::new("resample-method")
+                .long("resample-method")
+                .value_parser(["jackknife", "bootstrap"])
+                .default_value("jackknife")
+                .help("draw half of the samples without replacement (jackknife) or as many as there are with replacement (bootstrap) for each resample")
+            )
+            .arg(
+                Arg::new("seed")
+                .long("seed")
+                .default_value("10")
+                .help("seed for the random generator drawing the resamples")
+            )
+        )
+        .subcommand(
+            Command::new("convert")
+            .about("Convert a collapse order between the compact format (collapse_order.bin), the JSON format (collapse_order.json) of earlier versions and binary Newick trees, e.g. from other tools.")
+            .arg(
+                Arg::new("input")
+                    .long("input")
+                    .short('i')
                     .required(true)
-                    .takes_value(true)
-                    .requires("dir")
-                    .help("prefix where output would be written")
+                    .help("collapse order to convert, possibly compressed")
+            )
+            .arg(
+                Arg::new("output")
+                    .long("output")
+                    .short('o')
+                    .required(true)
+                    .help("path of the converted collapse order")
             )
             .arg(
-                Arg::with_name("mean")
-                    .long("mean_inf")
-                    .takes_value(true)
-                    .default_value("true")
-                    .help("mean infrv for tree construction")
+                Arg::new("to")
+                    .long("to")
+                    .required(true)
+                    .value_parser(["bin", "json", "nwk"])
+                    .help("format to convert the collapse order to")
+            )
+            .arg(
+                Arg::new("from")
+                    .long("from")
+                    .value_parser(["bin", "json", "nwk"])
+                    .help("format of the input [default: json when converting to bin, bin otherwise]")
             )
             .arg(
-                Arg::with_name("inf_perc")
-                .long("inf_perc")
-                .short("i")
-                .takes_value(true)
-                .default_value("0")
-                .help("inferential variance percentile threshold that determines whether a transcript will be considered for grouping [0-1]")
+                Arg::new("names")
+                    .long("names")
+                    .help("index to name mapping of the transcripts, for Newick trees labelled by name [default: names.tsv next to the input]")
             )
             .arg(
-                Arg::with_name("red_perc")
-                .long("red_perc")
-                .takes_value(true)
-                .default_value("0.025")
-                .help("Reduction in inferential variance percentile threshold that determines to detemine if transcripts/groups should be aggregated [0,1]")
+                Arg::new("compress")
+                .long("compress")
+                .value_parser(["none", "gzip", "zstd"])
+                .default_value("none")
+                .help("compression applied to the converted collapse order")
             )
         )
         .subcommand(
-            SubCommand::with_name("consensus")
-            .about("Produce a set of consensus trees from the individual per-sample trees obtained for an RNA-Seq experiment after running the group step.")
+            Command::new("cut")
+            .about("Find an optimal cut through the trees produced by group or consensus, selecting disjoint nodes that cover all the transcripts, and write them as a table of groups.")
+            .arg(
+                Arg::new("trees")
+                    .long("trees")
+                    .short('t')
+                    .required(true)
+                    .help("Newick file of the trees to cut, such as group_nwk.txt or cluster_nwk.txt")
+            )
             .arg(
-                Arg::with_name("dirs")
+                Arg::new("dirs")
                     .long("dirs")
-                    .short("d")
+                    .short('d')
+                    .help("salmon directory, or directory of salmon directories, used to compute the statistics of the nodes; not needed for trees written with --nhx")
+            )
+            .arg(
+                Arg::new("names")
+                    .long("names")
+                    .help("index to name mapping of the transcripts, for trees labelled by name [default: names.tsv next to the trees]")
+            )
+            .arg(
+                Arg::new("objective")
+                    .long("objective")
+                    .default_value("gain:0")
+                    .help("score maximised by the cut: infrv:<target> scores 1 for nodes whose infRV is at most target and -1 otherwise, gain:<lambda> scores the count-weighted reduction in infRV minus lambda per merged transcript")
+            )
+            .args(infrv_args())
+            .arg(aggregate_arg())
+            .arg(
+                Arg::new("output")
+                    .long("output")
+                    .short('o')
                     .required(true)
-               //     .multiple(true)
-                    .takes_value(true)
-                    .help("direcotories to read the group files from")
+                    .help("path of the table of selected groups")
+            )
+        )
+        .subcommand(
+            Command::new("flatten")
+            .about("Flatten the trees produced by group or consensus into Terminus-style groups, and write the collapsed quantification of each sample as Terminus's collapse step does.")
+            .arg(
+                Arg::new("trees")
+                    .long("trees")
+                    .short('t')
+                    .required(true)
+                    .help("Newick file of the trees to flatten, such as group_nwk.txt or cluster_nwk.txt")
+            )
+            .arg(
+                Arg::new("dirs")
+                    .long("dirs")
+                    .short('d')
+                    .required(true)
+                    .help("salmon directory, or directory of salmon directories, to collapse")
+            )
+            .arg(
+                Arg::new("names")
+                    .long("names")
+                    .help("index to name mapping of the transcripts, for trees labelled by name [default: names.tsv next to the trees]")
             )
             .arg(
-                Arg::with_name("out")
+                Arg::new("level")
+                    .long("level")
+                    .required(true)
+                    .help("level at which the trees are flattened: depth:<d> groups the nodes at depth d, size:<n> the largest nodes with at most n transcripts, infrv:<x> the largest nodes with infRV at most x")
+            )
+            .arg(
+                Arg::new("out")
                     .long("out")
-                    .short("o")
+                    .short('o')
                     .required(true)
-                    .takes_value(true)
-                    .requires("dirs")
-                    .help("prefix where output would be written")
+                    .help("prefix where a collapsed directory is written for each sample")
+            )
+            .args(infrv_args())
+            .arg(aggregate_arg())
+        )
+        .subcommand(
+            Command::new("compare")
+            .about("Compare two sets of trees, such as per-sample trees and the Mean or Consensus trees, matching the trees by their leaves and reporting Robinson-Foulds distances and clade overlaps.")
+            .arg(
+                Arg::new("first")
+                    .required(true)
+                    .index(1)
+                    .help("first Newick file of trees")
             )
-        ).get_matches();
+            .arg(
+                Arg::new("second")
+                    .required(true)
+                    .index(2)
+                    .help("second Newick file of trees")
+            )
+            .arg(
+                Arg::new("names")
+                    .long("names")
+                    .help("index to name mapping of the transcripts, for trees labelled by name [default: names.tsv next to each file]")
+            )
+            .arg(
+                Arg::new("output")
+                    .long("output")
+                    .short('o')
+                    .required(true)
+                    .help("path of the table comparing each tree of the first file to its best match in the second")
+            )
+            .arg(
+                Arg::new("summary")
+                    .long("summary")
+                    .short('s')
+                    .help("path of a JSON file summarising the comparison over all trees")
+            )
+        )
+        .subcommand(
+            Command::new("completions")
+            .about("Print the completion script of a shell.")
+            .arg(
+                Arg::new("shell")
+                    .required(true)
+                    .index(1)
+                    .value_parser(value_parser!(Shell))
+                    .help("shell to complete the commands of")
+            )
+        )
+        .subcommand(
+            Command::new("man")
+            .about("Write the man pages of the program and of each subcommand.")
+            .arg(
+                Arg::new("out-dir")
+                    .long("out-dir")
+                    .short('o')
+                    .help("directory where the pages are written, one per subcommand [default: print the page of the program]")
+            )
+        )
+}
+
+// Writes the man page of `cmd`, and of its subcommands below it, to `dir`
+fn write_man_pages(cmd: &Command, dir: &Path) -> Result<bool, io::Error> {
+    let name = cmd.get_display_name().unwrap_or_else(|| cmd.get_name());
+    let mut page = File::create(dir.join(format!("{}.1", name)))?;
+    clap_mangen::Man::new(cmd.clone()).render(&mut page)?;
+    for sub in cmd.get_subcommands().filter(|c| c.get_name() != "help") {
+        write_man_pages(sub, dir)?;
+    }
+    Ok(true)
+}
+
+fn do_man(sub_m: &ArgMatches) -> Result<bool, io::Error> {
+    let mut cmd = cli();
+    cmd.build();
+    match sub_m.get_one::<String>("out-dir") {
+        Some(dir) => {
+            create_dir_all(dir)?;
+            write_man_pages(&cmd, Path::new(dir))
+        }
+        None => {
+            clap_mangen::Man::new(cmd).render(&mut io::stdout())?;
+            Ok(true)
+        }
+    }
+}
 
-    pretty_env_logger::init_timed();
+// The entry point of the program
+// that reads from the files and build
+// graphs or later produces the collapsed
+// files.
+
+fn main() -> io::Result<()> {
+    let args = config::expand_args(std::env::args().collect())?;
+    let matches = cli().get_matches_from(args);
 
     match matches.subcommand() {
-        ("group", Some(sub_m)) => {
-            do_group(sub_m).expect("Grouping failed");
+        Some(("group", group_m)) => {
+            let (mode, sub_m) = group_m.subcommand().unwrap();
+            setup_logging(sub_m);
+            let dname = sub_m.get_one::<String>("dir").unwrap();
+            let out = sub_m.get_one::<String>("out").unwrap();
+            match mode {
+                "per-sample" => do_per_sample(sub_m, dname),
+                "mean" => salmon_dirs(dname)
+                    .and_then(|dir_paths| load_samples(&dir_paths))
+                    .and_then(|(samples, gibbs)| {
+                        do_group(sub_m, true, dname, out, &samples, gibbs)
+                    }),
+                _ => load_sample(dname).and_then(|(sample, gibbs)| {
+                    do_group(sub_m, false, dname, out, &[sample], vec![gibbs])
+                }),
+            }
+            .expect("Grouping failed");
+        }
+        Some(("run", sub_m)) => {
+            setup_logging(sub_m);
+            do_run(sub_m, sub_m.get_one::<String>("dir").unwrap()).expect("Running failed");
         }
-        ("consensus", Some(sub_m)) => {
+        Some(("consensus", sub_m)) => {
+            setup_logging(sub_m);
             do_collapse(sub_m).expect("Grouping failed");
         }
+        Some(("convert", sub_m)) => {
+            setup_logging(sub_m);
+            do_convert(sub_m).expect("Conversion failed");
+        }
+        Some(("cut", sub_m)) => {
+            setup_logging(sub_m);
+            do_cut(sub_m).expect("Cutting the trees failed");
+        }
+        Some(("flatten", sub_m)) => {
+            setup_logging(sub_m);
+            do_flatten(sub_m).expect("Flattening the trees failed");
+        }
+        Some(("compare", sub_m)) => {
+            setup_logging(sub_m);
+            do_compare(sub_m).expect("Comparing the trees failed");
+        }
+        Some(("completions", sub_m)) => {
+            let shell = *sub_m.get_one::<Shell>("shell").unwrap();
+            generate(shell, &mut cli(), PROGRAM_NAME, &mut io::stdout());
+        }
+        Some(("man", sub_m)) => {
+            do_man(sub_m)?;
+        }
         _ => unreachable!(),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    const NTXPS: usize = 8;
+    const NREPS: usize = 10;
+
+    // Writes an experiment of `nsamples` salmon directories, `S0`, `S1`...,
+    // whose transcripts share their reads by genes of four
+    fn write_experiment(name: &str, nsamples: usize) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("treeterminus-main-{}", std::process::id()))
+            .join(name);
+        let names: Vec<String> = (0..NTXPS).map(|t| format!("t{}", t)).collect();
+        for s in 0..nsamples {
+            let sample = dir.join(format!("S{}", s));
+            create_dir_all(&sample).unwrap();
+            let file_list = salmon_types::FileList::new(sample.to_str().unwrap().to_string());
+            create_dir_all(file_list.bootstrap_file.parent().unwrap()).unwrap();
+            let base: Vec<f64> = (0..NTXPS).map(|t| (20 + 10 * t + 5 * s) as f64).collect();
+
+            let mut bfile = GzEncoder::new(
+                File::create(&file_list.bootstrap_file).unwrap(),
+                Compression::default(),
+            );
+            let mut data = vec![0_u8; NTXPS * 8];
+            for b in 0..NREPS {
+                let mut col = Vec::new();
+                for g in (0..NTXPS).step_by(4) {
+                    let total: f64 = base[g..g + 4].iter().sum();
+                    let w: Vec<f64> = (0..4)
+                        .map(|i| ((b * 7 + i * 3 + s) % 5 + 1) as f64)
+                        .collect();
+                    let wsum: f64 = w.iter().sum();
+                    col.extend(w.iter().map(|x| total * x / wsum));
+                }
+                LittleEndian::write_f64_into(&col, &mut data);
+                bfile.write_all(&data).unwrap();
+            }
+            bfile.finish().unwrap();
+
+            let mut eq = format!("{}\n{}\n{}\n", NTXPS, 2 * NTXPS / 4, names.join("\n"));
+            for g in (0..NTXPS).step_by(4) {
+                let total: f64 = base[g..g + 4].iter().sum();
+                eq.push_str(&format!(
+                    "4 {} {} {} {} 0.1 0.2 0.3 0.4 {}\n",
+                    g,
+                    g + 1,
+                    g + 2,
+                    g + 3,
+                    total as u32
+                ));
+                eq.push_str(&format!("2 {} {} 0.35 0.65 {}\n", g, g + 1, base[g] as u32));
+            }
+            write(&file_list.eq_file, eq).unwrap();
+            write(
+                &file_list.mi_file,
+                json!({
+                    "num_valid_targets":NTXPS,
+                    "serialized_eq_classes":true,
+                    "num_bootstraps":NREPS,
+                    "num_eq_classes":2 * NTXPS / 4,
+                    "eq_class_properties":[],
+                    "samp_type":"bootstrap",
+                })
+                .to_string(),
+            )
+            .unwrap();
+            let mut quant = String::from("Name\tLength\tEffectiveLength\tTPM\tNumReads\n");
+            for (t, name) in names.iter().enumerate() {
+                quant.push_str(&format!(
+                    "{}\t1000\t800\t{}\t{}\n",
+                    name,
+                    base[t] / 10.0,
+                    base[t]
+                ));
+            }
+            write(&file_list.quant_file, quant).unwrap();
+        }
+        dir
+    }
+
+    fn meta(ntargets: u32, nreps: u32) -> salmon_types::MetaInfo {
+        salmon_types::MetaInfo {
+            num_valid_targets: ntargets,
+            serialized_eq_classes: true,
+            num_bootstraps: nreps,
+            num_eq_classes: 0,
+            eq_class_properties: Vec::new(),
+            samp_type: "bootstrap".to_string(),
+        }
+    }
+
+    #[test]
+    fn memory_limit_counts_the_resident_samples() {
+        let dir = write_experiment("memory", 1);
+        let dir = dir.to_str().unwrap();
+        // 40 kB, five samples of 8 kB
+        let m = cli().get_matches_from([
+            PROGRAM_NAME,
+            "run",
+            "-d",
+            dir,
+            "-o",
+            dir,
+            "--max-memory",
+            "0.00004",
+        ]);
+        let run_m = m.subcommand_matches("run").unwrap();
+        let metas = vec![meta(10, 100); 3];
+        assert_eq!(memory_limit(run_m, metas.iter(), 0.0), 3);
+        assert_eq!(memory_limit(run_m, metas.iter(), 24000.0), 2);
+        assert_eq!(memory_limit(run_m, metas.iter(), 48000.0), 0);
+
+        let m = cli().get_matches_from([PROGRAM_NAME, "run", "-d", dir, "-o", dir]);
+        let run_m = m.subcommand_matches("run").unwrap();
+        assert_eq!(memory_limit(run_m, metas.iter(), 48000.0), 3);
+    }
+
+    #[test]
+    fn run_writes_its_layout_and_manifest() {
+        let dir = write_experiment("run", 3);
+        let out = dir.parent().unwrap().join("run_out");
+        let (dir, out) = (dir.to_str().unwrap(), out.to_str().unwrap());
+        let m = cli().get_matches_from([PROGRAM_NAME, "run", "-d", dir, "-o", out, "-j", "2"]);
+        assert!(do_run(m.subcommand_matches("run").unwrap(), dir).unwrap());
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&read_to_string(format!("{}/manifest.json", out)).unwrap())
+                .unwrap();
+        assert_eq!(manifest["dir"], dir);
+        let samples = manifest["samples"].as_array().unwrap();
+        let mut names: Vec<&str> = samples
+            .iter()
+            .map(|s| s["name"].as_str().unwrap())
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["S0", "S1", "S2"]);
+        for s in samples.iter() {
+            let name = s["name"].as_str().unwrap();
+            let file_list = salmon_types::FileList::new(format!("{}/samples/{}", out, name));
+            assert_eq!(s["dir"], format!("{}/{}", dir, name));
+            assert_eq!(s["out"], file_list.prefix.to_str().unwrap());
+            assert!(file_list.group_nwk_file.is_file());
+            assert!(file_list.param_log_file.is_file());
+            assert_eq!(s["trees"], file_list.group_nwk_file.to_str().unwrap());
+        }
+        let mean_files = ConsensusFileList::new(format!("{}/mean", out));
+        assert_eq!(manifest["mean"]["out"], format!("{}/mean", out));
+        assert_eq!(
+            manifest["mean"]["trees"],
+            mean_files.cons_nwk_file.to_str().unwrap()
+        );
+        assert!(mean_files.cons_nwk_file.is_file());
+        let consensus_files = ConsensusFileList::new(format!("{}/consensus", out));
+        assert_eq!(
+            manifest["consensus"]["trees"],
+            consensus_files.cons_nwk_file.to_str().unwrap()
+        );
+        assert!(consensus_files.cons_nwk_file.is_file());
+        assert_eq!(manifest["config"]["jobs"], "2");
+        assert_eq!(manifest["config"]["dir"], dir);
+    }
+
+    #[test]
+    fn reads_sample_sheets_and_lists() {
+        let dir = write_experiment("sheets", 0);
+        create_dir_all(&dir).unwrap();
+        let sheet = dir.join("samples.tsv");
+        write(&sheet, "# sample group\nS0\tctrl\n\nS1 treated\nS2\tctrl\n").unwrap();
+        let groups = sample_groups(sheet.to_str().unwrap()).unwrap();
+        let groups: Vec<(Option<&str>, &[String])> = groups
+            .iter()
+            .map(|g| (g.name.as_deref(), &g.samples[..]))
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                (Some("ctrl"), &["S0".to_string(), "S2".to_string()][..]),
+                (Some("treated"), &["S1".to_string()][..]),
+            ]
+        );
+
+        let groups = sample_groups("S0, S2,").unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name, None);
+        assert_eq!(groups[0].samples, vec!["S0", "S2"]);
+        assert!(sample_groups(" , ").is_err());
+
+        for bad in ["S0 ctrl extra\n", "S0 a/b\n", "S0\n", "# nothing\n"] {
+            write(&sheet, bad).unwrap();
+            let e = sample_groups(sheet.to_str().unwrap()).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn consensus_rejects_unknown_duplicate_and_empty_groups() {
+        let dir = write_experiment("groups", 2);
+        let dir_str = dir.to_str().unwrap();
+        let tnames: Vec<String> = (0..NTXPS).map(|t| format!("t{}", t)).collect();
+        let group = |name: Option<&str>, samples: &[&str]| SampleGroup {
+            name: name.map(String::from),
+            samples: samples.iter().map(|s| s.to_string()).collect(),
+        };
+        let consensus = |dir: &str, groups: Option<&[SampleGroup]>| {
+            consensus_trees(
+                dir,
+                dir,
+                dir,
+                &tnames,
+                util::OutputCompression::None,
+                false,
+                groups,
+                Some(&Resample {
+                    resampling: collapse::Resampling::Bootstrap,
+                    nresamples: 2,
+                    seed: 1,
+                }),
+            )
+            .err()
+            .unwrap()
+        };
+        for groups in [
+            vec![group(Some("a"), &["S0", "S5"])],
+            vec![group(Some("a"), &["S0"]), group(Some("b"), &["S1", "S1"])],
+            vec![group(Some("a"), &["S0", "S1"]), group(Some("b"), &[])],
+        ] {
+            let e = consensus(dir_str, Some(&groups));
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", e);
+        }
+
+        // an experiment without samples
+        let empty = dir.parent().unwrap().join("groups_empty");
+        create_dir_all(&empty).unwrap();
+        let e = consensus(empty.to_str().unwrap(), None);
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+
+use crate::binary_tree::{
+    leaf_label, node_annotation, NewickOptions, NodeStats, TreeArena, TreeNode,
+};
+use crate::newick::{leaf_index, NewickError, NewickNode};
+
+/// A node of a rooted tree with any number of children, such as a group of
+/// identical transcripts or the trees passed to the consensus, which
+/// `TreeNode` can only hold as arbitrary binary chains
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NaryNode {
+    /// the transcript of a leaf, unused for internal nodes
+    pub txp: u32,
+    pub children: Vec<NaryNode>,
+    pub stats: Option<NodeStats>,
+}
+
+impl Drop for NaryNode {
+    // The children are detached before being dropped, so that dropping a
+    // deep tree does not recurse down it
+    fn drop(&mut self) {
+        let mut stack = std::mem::take(&mut self.children);
+        while let Some(mut node) = stack.pop() {
+            stack.append(&mut node.children);
+        }
+    }
+}
+
+impl NaryNode {
+    pub fn create_leaf(txp: u32) -> NaryNode {
+        NaryNode {
+            txp,
+            children: Vec::new(),
+            stats: None,
+        }
+    }
+
+    pub fn create_group(children: Vec<NaryNode>) -> NaryNode {
+        NaryNode {
+            txp: 0,
+            children,
+            stats: None,
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// Returns the nodes of the tree in postorder, without recursing
+    pub fn postorder(&self) -> Vec<&NaryNode> {
+        let mut order = Vec::new();
+        let mut stack = vec![(self, false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if node.is_leaf() || expanded {
+                order.push(node);
+            } else {
+                stack.push((node, true));
+                stack.extend(node.children.iter().rev().map(|c| (c, false)));
+            }
+        }
+        order
+    }
+
+    /// Converts the tree of `arena` below `root`, merging its chained nodes
+    /// into their parents
+    pub fn from_arena(arena: &TreeArena, root: usize) -> NaryNode {
+        // the finished nodes whose parent is yet to be built, along with
+        // whether they belong to it
+        let mut done: Vec<(NaryNode, bool)> = Vec::new();
+        for v in arena.postorder(root) {
+            let anode = &arena.nodes[v];
+            let mut node = match anode.children {
+                Some(_) => {
+                    let mut children = Vec::new();
+                    for (mut child, chained) in done.split_off(done.len() - 2) {
+                        if chained {
+                            children.append(&mut child.children);
+                        } else {
+                            children.push(child);
+                        }
+                    }
+                    NaryNode::create_group(children)
+                }
+                None => NaryNode::create_leaf(anode.txp),
+            };
+            node.stats = anode.stats;
+            done.push((node, anode.chained));
+        }
+        done.pop().unwrap().0
+    }
+
+    /// Converts a binary tree, merging its chained nodes into their parents
+    pub fn from_binary(tree: &TreeNode) -> NaryNode {
+        let mut arena = TreeArena::default();
+        let root = arena.push_tree(tree);
+        NaryNode::from_arena(&arena, root)
+    }
+
+    /// Converts the tree into a binary tree, resolving every multifurcation
+    /// into a chain of nodes marked as `chained`, which carry the
+    /// statistics of the node they resolve
+    pub fn to_binary(&self) -> TreeNode {
+        let mut done: Vec<TreeNode> = Vec::new();
+        for node in self.postorder() {
+            if node.is_leaf() {
+                let mut leaf = TreeNode::create_leaf(node.txp.to_string());
+                leaf.stats = node.stats;
+                done.push(leaf);
+                continue;
+            }
+            let mut children = done.split_off(done.len() - node.children.len()).into_iter();
+            let first = children.next().unwrap();
+            let mut tnode = match children.next() {
+                Some(second) => TreeNode::create_group(first, second),
+                None => {
+                    // a node with a single child is skipped
+                    done.push(first);
+                    continue;
+                }
+            };
+            for child in children {
+                tnode.stats = node.stats;
+                tnode.chained = true;
+                tnode = TreeNode::create_group(tnode, child);
+            }
+            tnode.stats = node.stats;
+            done.push(tnode);
+        }
+        done.pop().unwrap()
+    }
+
+    /// Converts a tree read from the Newick format, whose leaves are the
+    /// transcript indices of the labels (see `leaf_index`) and whose
+    /// statistics are taken from the NHX tags. Nodes with a single child
+    /// are skipped.
+    pub fn from_newick(
+        tree: &NewickNode,
+        names: &HashMap<String, usize>,
+    ) -> Result<NaryNode, NewickError> {
+        let mut done: Vec<NaryNode> = Vec::new();
+        for node in tree.postorder() {
+            let mut nnode = match node.children.len() {
+                0 => {
+                    let t = leaf_index(&node.name, names).ok_or_else(|| NewickError {
+                        msg: format!("unknown transcript {:?}", node.name),
+                        pos: None,
+                    })?;
+                    NaryNode::create_leaf(t as u32)
+                }
+                1 => continue,
+                n => NaryNode::create_group(done.split_off(done.len() - n)),
+            };
+            nnode.stats = node.nhx_stats();
+            done.push(nnode);
+        }
+        Ok(done.pop().unwrap())
+    }
+
+    /// Writes the tree in the Newick format, terminated by a `;`, with the
+    /// labels and annotations requested by `opts`
+    pub fn to_newick_string(&self, opts: &NewickOptions) -> String {
+        enum Step<'a> {
+            Enter(&'a NaryNode, Option<&'a NodeStats>),
+            Exit(&'a NaryNode, Option<&'a NodeStats>),
+            Separator,
+        }
+        let mut out = String::new();
+        let mut stack = vec![Step::Enter(self, None)];
+        while let Some(step) = stack.pop() {
+            match step {
+                Step::Enter(node, parent) if node.is_leaf() => {
+                    out.push_str(&leaf_label(node.txp, opts.tnames));
+                    out.push_str(&node_annotation(node.stats.as_ref(), parent, opts));
+                }
+                Step::Enter(node, parent) => {
+                    out.push('(');
+                    stack.push(Step::Exit(node, parent));
+                    for (i, c) in node.children.iter().enumerate().rev() {
+                        stack.push(Step::Enter(c, node.stats.as_ref()));
+                        if i > 0 {
+                            stack.push(Step::Separator);
+                        }
+                    }
+                }
+                Step::Exit(node, parent) => {
+                    out.push(')');
+                    out.push_str(&node_annotation(node.stats.as_ref(), parent, opts));
+                }
+                Step::Separator => out.push(','),
+            }
+        }
+        out.push(';');
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binary_tree::{POSTORDER_CHAIN, POSTORDER_MERGE};
+    use crate::newick::parse_newick_trees;
+
+    const M: u32 = POSTORDER_MERGE;
+    const C: u32 = POSTORDER_CHAIN;
+
+    fn nwk(tree: &NaryNode) -> String {
+        tree.to_newick_string(&NewickOptions::default())
+    }
+
+    fn stats(infrv: f64) -> NodeStats {
+        NodeStats { infrv, mean: 1.0 }
+    }
+
+    #[test]
+    fn merges_chains_from_the_arena() {
+        let mut arena = TreeArena::default();
+        let root = arena.push_postorder(&[0, 1, C, 2, M, 3, M], &[]);
+        assert_eq!(nwk(&NaryNode::from_arena(&arena, root)), "((0,1,2),3);");
+        let root = arena.push_postorder(&[4, 5, M, 6, C, 7, M], &[]);
+        assert_eq!(nwk(&NaryNode::from_arena(&arena, root)), "((4,5),6,7);");
+    }
+
+    #[test]
+    fn resolves_multifurcations_into_chains() {
+        let mut inner = NaryNode::create_group(vec![
+            NaryNode::create_leaf(0),
+            NaryNode::create_leaf(1),
+            NaryNode::create_leaf(2),
+        ]);
+        inner.stats = Some(stats(0.5));
+        let tree = NaryNode::create_group(vec![inner, NaryNode::create_leaf(3)]);
+        let binary = tree.to_binary();
+        assert_eq!(binary.to_postorder(), vec![0, 1, C, 2, M, 3, M]);
+        let chain = binary.left.as_ref().unwrap().left.as_ref().unwrap();
+        assert_eq!(chain.stats, Some(stats(0.5)));
+        assert_eq!(nwk(&NaryNode::from_binary(&binary)), "((0,1,2),3);");
+    }
+
+    #[test]
+    fn skips_nodes_with_a_single_child() {
+        let tree = NaryNode::create_group(vec![
+            NaryNode::create_group(vec![NaryNode::create_leaf(0)]),
+            NaryNode::create_leaf(1),
+        ]);
+        assert_eq!(tree.to_binary().to_postorder(), vec![0, 1, M]);
+    }
+
+    #[test]
+    fn reads_newick_trees() {
+        let names: HashMap<String, usize> = vec![("a".to_string(), 5)].into_iter().collect();
+        let parsed = parse_newick_trees("((a,1,(2))[&&NHX:infRV=0.5:mean=1],3);\n(0,x);").unwrap();
+        let tree = NaryNode::from_newick(&parsed[0], &names).unwrap();
+        assert_eq!(nwk(&tree), "((5,1,2),3);");
+        assert_eq!(tree.children[0].stats, Some(stats(0.5)));
+        assert!(NaryNode::from_newick(&parsed[1], &names).is_err());
+    }
+
+    #[test]
+    fn writes_labels_and_annotations() {
+        let mut inner =
+            NaryNode::create_group(vec![NaryNode::create_leaf(0), NaryNode::create_leaf(1)]);
+        inner.stats = Some(stats(0.25));
+        let mut tree = NaryNode::create_group(vec![inner, NaryNode::create_leaf(2)]);
+        tree.stats = Some(stats(0.125));
+        let tnames = vec!["T0".to_string(), "T|1".to_string(), "T2".to_string()];
+        let opts = NewickOptions {
+            tnames: Some(&tnames),
+            branch_lengths: true,
+            nhx: true,
+        };
+        assert_eq!(
+            tree.to_newick_string(&opts),
+            "((T0,'T|1'):0.125000[&&NHX:infRV=0.250000:mean=1.0000],T2)[&&NHX:infRV=0.125000:mean=1.0000];"
+        );
+    }
+
+    #[test]
+    fn converts_deep_trees_without_recursing() {
+        let n = 100_000;
+        let mut order = vec![0];
+        for t in 1..n {
+            order.push(t);
+            order.push(M);
+        }
+        let mut arena = TreeArena::default();
+        let root = arena.push_postorder(&order, &[]);
+        let tree = NaryNode::from_arena(&arena, root);
+        assert_eq!(tree.to_binary().to_postorder(), order);
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use crate::binary_tree::{newick_label, NodeStats};
+use crate::util::open_reader;
+
+/// A node of a rooted tree read from the Newick format. Unlike `TreeNode`
+/// it may have any number of children, as in the consensus trees.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NewickNode {
+    pub name: String,
+    pub length: Option<f64>,
+    /// the `key=value` pairs of an `[&&NHX:...]` comment, in order
+    pub nhx: Vec<(String, String)>,
+    pub children: Vec<NewickNode>,
+}
+
+/// An error in a Newick string, along with the line and column (both
+/// starting at 1) where it was found, if it is tied to a position
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewickError {
+    pub msg: String,
+    pub pos: Option<(usize, usize)>,
+}
+
+impl fmt::Display for NewickError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pos {
+            Some((line, col)) => write!(f, "{} at line {}, column {}", self.msg, line, col),
+            None => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+impl std::error::Error for NewickError {}
+
+/// Returns the transcript index of a leaf, labelled either by a name of
+/// `names` or by the index itself. The names are looked up first, so that
+/// transcripts named by numbers are not taken for indices.
+pub fn leaf_index(label: &str, names: &HashMap<String, usize>) -> Option<usize> {
+    names
+        .get(label)
+        .copied()
+        .or_else(|| label.parse::<usize>().ok())
+}
+
+impl Drop for NewickNode {
+    // The children are detached before being dropped, so that dropping a
+    // deep tree does not recurse down it
+    fn drop(&mut self) {
+        let mut stack = std::mem::take(&mut self.children);
+        while let Some(mut node) = stack.pop() {
+            stack.append(&mut node.children);
+        }
+    }
+}
+
+impl NewickNode {
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// Returns the value of the NHX tag `key`, if the node has it
+    pub fn nhx_value(&self, key: &str) -> Option<&str> {
+        self.nhx
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the statistics written by `group --nhx`, if the node has them
+    pub fn nhx_stats(&self) -> Option<NodeStats> {
+        let infrv = self.nhx_value("infRV")?.parse::<f64>().ok()?;
+        let mean = self.nhx_value("mean")?.parse::<f64>().ok()?;
+        Some(NodeStats { infrv, mean })
+    }
+
+    /// Returns the nodes of the tree in postorder, without recursing
+    pub fn postorder(&self) -> Vec<&NewickNode> {
+        let mut order = Vec::new();
+        let mut stack = vec![(self, false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if node.is_leaf() || expanded {
+                order.push(node);
+            } else {
+                stack.push((node, true));
+                stack.extend(node.children.iter().rev().map(|c| (c, false)));
+            }
+        }
+        order
+    }
+}
+
+// A leaf read by the parser: the character range of its label, if it has
+// one, where the leaf starts and its name
+struct ParsedLeaf {
+    label: Option<(usize, usize)>,
+    start: usize,
+    name: String,
+}
+
+struct Parser {
+    chars: Vec<char>,
+    i: usize,
+    // the character range of the label last read by `annotate`
+    label: Option<(usize, usize)>,
+    // the leaves read so far
+    leaves: Vec<ParsedLeaf>,
+}
+
+impl Parser {
+    fn new(s: &str) -> Parser {
+        Parser {
+            chars: s.chars().collect(),
+            i: 0,
+            label: None,
+            leaves: Vec::new(),
+        }
+    }
+
+    fn error(&self, msg: String) -> NewickError {
+        let before = &self.chars[..self.i.min(self.chars.len())];
+        let line = before.iter().filter(|c| **c == '\n').count() + 1;
+        let col = before.iter().rev().take_while(|c| **c != '\n').count() + 1;
+        NewickError {
+            msg,
+            pos: Some((line, col)),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+            self.i += 1;
+        }
+    }
+
+    // Reads an unquoted label or branch length
+    fn token(&mut self) -> String {
+        let start = self.i;
+        while let Some(c) = self.peek() {
+            if "(),:;['".contains(c) || c.is_whitespace() {
+                break;
+            }
+            self.i += 1;
+        }
+        self.chars[start..self.i].iter().collect()
+    }
+
+    fn quoted(&mut self) -> Result<String, NewickError> {
+        let start = self.i;
+        let mut name = String::new();
+        self.i += 1;
+        loop {
+            match self.peek() {
+                None => {
+                    self.i = start;
+                    return Err(self.error("unterminated quoted label".to_string()));
+                }
+                // a doubled quote stands for a quote in the label
+                Some('\'') if self.chars.get(self.i + 1) == Some(&'\'') => {
+                    name.push('\'');
+                    self.i += 2;
+                }
+                Some('\'') => {
+                    self.i += 1;
+                    return Ok(name);
+                }
+                Some(c) => {
+                    name.push(c);
+                    self.i += 1;
+                }
+            }
+        }
+    }
+
+    // Reads a comment, which may contain nested comments
+    fn comment(&mut self) -> Result<String, NewickError> {
+        let start = self.i;
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            self.i += 1;
+            match c {
+                '[' => depth += 1,
+                ']' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(self.chars[start + 1..self.i - 1].iter().collect());
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.i = start;
+        Err(self.error("unterminated comment".to_string()))
+    }
+
+    // Reads the label, branch length and comments following a node
+    fn annotate(&mut self, node: &mut NewickNode) -> Result<(), NewickError> {
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(':') => {
+                    if node.length.is_some() {
+                        return Err(self.error("second branch length for a node".to_string()));
+                    }
+                    self.i += 1;
+                    self.skip_whitespace();
+                    let start = self.i;
+                    let len = self.token();
+                    node.length = Some(len.parse::<f64>().map_err(|_| {
+                        self.i = start;
+                        self.error(format!("invalid branch length {:?}", len))
+                    })?);
+                }
+                Some('[') => {
+                    let comment = self.comment()?;
+                    if let Some(tags) = comment.strip_prefix("&&NHX") {
+                        node.nhx
+                            .extend(tags.split(':').filter(|kv| !kv.is_empty()).map(|kv| {
+                                match kv.find('=') {
+                                    Some(i) => (kv[..i].to_string(), kv[i + 1..].to_string()),
+                                    None => (kv.to_string(), String::new()),
+                                }
+                            }));
+                    }
+                }
+                Some(c) if !"(),;".contains(c) => {
+                    if !node.name.is_empty() || node.length.is_some() {
+                        return Err(self.error(format!("unexpected label after {:?}", node.name)));
+                    }
+                    let start = self.i;
+                    node.name = match c {
+                        '\'' => self.quoted()?,
+                        _ => self.token(),
+                    };
+                    self.label = Some((start, self.i));
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Reads a tree up to, and including, its terminating `;`
+    fn tree(&mut self) -> Result<NewickNode, NewickError> {
+        // the internal nodes whose children are being read
+        let mut stack: Vec<NewickNode> = Vec::new();
+        loop {
+            self.skip_whitespace();
+            while self.peek() == Some('(') {
+                stack.push(NewickNode::default());
+                self.i += 1;
+                self.skip_whitespace();
+            }
+            let mut node = NewickNode::default();
+            let start = self.i;
+            self.label = None;
+            self.annotate(&mut node)?;
+            self.leaves.push(ParsedLeaf {
+                label: self.label,
+                start,
+                name: node.name.clone(),
+            });
+            // close the subtrees that end with the node
+            loop {
+                match self.peek() {
+                    Some(',') if !stack.is_empty() => {
+                        stack.last_mut().unwrap().children.push(node);
+                        self.i += 1;
+                        break;
+                    }
+                    Some(')') if !stack.is_empty() => {
+                        let mut parent = stack.pop().unwrap();
+                        parent.children.push(node);
+                        self.i += 1;
+                        node = parent;
+                        self.annotate(&mut node)?;
+                    }
+                    Some(';') if stack.is_empty() => {
+                        self.i += 1;
+                        return Ok(node);
+                    }
+                    Some(';') => {
+                        return Err(
+                            self.error(format!("{} unclosed parentheses before ';'", stack.len()))
+                        )
+                    }
+                    Some(c) => return Err(self.error(format!("unexpected '{}'", c))),
+                    None => return Err(self.error("missing ';' after the tree".to_string())),
+                }
+            }
+        }
+    }
+}
+
+/// Parses the trees, each terminated by a `;`, in `s`. The trees may span
+/// several lines, as written by PHYLIP. Quoted labels, branch lengths and
+/// NHX comments are understood; other comments are skipped.
+pub fn parse_newick_trees(s: &str) -> Result<Vec<NewickNode>, NewickError> {
+    Parser::new(s).trees()
+}
+
+impl Parser {
+    fn trees(&mut self) -> Result<Vec<NewickNode>, NewickError> {
+        let mut trees = Vec::new();
+        self.skip_whitespace();
+        while self.peek().is_some() {
+            trees.push(self.tree()?);
+            self.skip_whitespace();
+        }
+        Ok(trees)
+    }
+}
+
+/// Replaces the transcript indices labelling the leaves of the trees in
+/// `nwk` (e.g. the output of PHYLIP) by the transcript names. Only the leaf
+/// labels are rewritten, so the layout, branch lengths and comments of
+/// `nwk` are kept as they are.
+pub fn relabel_newick(nwk: &str, tnames: &[String]) -> Result<String, NewickError> {
+    let mut parser = Parser::new(nwk);
+    parser.trees()?;
+    let mut out = String::with_capacity(nwk.len());
+    let mut last = 0;
+    for leaf in std::mem::take(&mut parser.leaves) {
+        let tname = leaf
+            .name
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| tnames.get(i))
+            .ok_or_else(|| {
+                parser.i = leaf.start;
+                parser.error(format!("leaf {:?} is not a transcript index", leaf.name))
+            })?;
+        let (from, to) = leaf.label.unwrap();
+        out.extend(parser.chars[last..from].iter());
+        out.push_str(&newick_label(tname));
+        last = to;
+    }
+    out.extend(parser.chars[last..].iter());
+    Ok(out)
+}
+
+/// Reads the trees of a Newick file, such as `group_nwk.txt` or
+/// `cluster_nwk.txt`, which may be compressed
+pub fn read_newick_file(p: &Path) -> Result<Vec<NewickNode>, io::Error> {
+    let mut s = String::new();
+    open_reader(p).read_to_string(&mut s)?;
+    parse_newick_trees(&s)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", p, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("T{}", i)).collect()
+    }
+
+    #[test]
+    fn parses_labels_lengths_and_nhx() {
+        let trees =
+            parse_newick_trees("((0:1.5,'a b''c'[&&NHX:infRV=0.25:mean=3]):0.5,2)root;\n(3,4);")
+                .unwrap();
+        assert_eq!(trees.len(), 2);
+        let root = &trees[0];
+        assert_eq!(root.name, "root");
+        assert_eq!(root.children.len(), 2);
+        let inner = &root.children[0];
+        assert_eq!(inner.length, Some(0.5));
+        assert_eq!(inner.children[0].name, "0");
+        assert_eq!(inner.children[0].length, Some(1.5));
+        assert_eq!(inner.children[1].name, "a b'c");
+        assert_eq!(
+            inner.children[1].nhx_stats(),
+            Some(NodeStats {
+                infrv: 0.25,
+                mean: 3.0
+            })
+        );
+        let leaves: Vec<&str> = trees[1]
+            .postorder()
+            .iter()
+            .filter(|n| n.is_leaf())
+            .map(|n| n.name.as_str())
+            .collect();
+        assert_eq!(leaves, vec!["3", "4"]);
+    }
+
+    #[test]
+    fn parses_trees_spanning_lines() {
+        let trees = parse_newick_trees("(0,\n(1,\n 2)\n);\n").unwrap();
+        assert_eq!(trees.len(), 1);
+        assert_eq!(trees[0].children[0].name, "0");
+        assert_eq!(trees[0].children[1].children.len(), 2);
+        assert_eq!(trees[0].children[1].children[1].name, "2");
+    }
+
+    #[test]
+    fn parses_deep_trees_without_recursing() {
+        let depth = 100_000;
+        let nwk = format!("{}0{};", "(".repeat(depth), ",1)".repeat(depth));
+        let trees = parse_newick_trees(&nwk).unwrap();
+        assert_eq!(trees[0].postorder().len(), 2 * depth + 1);
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        let err = parse_newick_trees("(0,1);\n(2,3").unwrap_err();
+        assert_eq!(err.pos, Some((2, 5)));
+        let err = parse_newick_trees("((0,1);").unwrap_err();
+        assert_eq!(err.pos, Some((1, 7)));
+        let err = parse_newick_trees("(0:x,1);").unwrap_err();
+        assert_eq!(err.pos, Some((1, 4)));
+        assert!(parse_newick_trees("(0,'1);").is_err());
+        assert!(parse_newick_trees("(0,1)[x;").is_err());
+        assert!(parse_newick_trees("(0,1)a b;").is_err());
+    }
+
+    #[test]
+    fn leaf_index_prefers_names() {
+        let names: HashMap<String, usize> = vec![("12".to_string(), 0), ("ENST1".to_string(), 1)]
+            .into_iter()
+            .collect();
+        assert_eq!(leaf_index("12", &names), Some(0));
+        assert_eq!(leaf_index("ENST1", &names), Some(1));
+        assert_eq!(leaf_index("3", &names), Some(3));
+        assert_eq!(leaf_index("x", &names), None);
+    }
+
+    #[test]
+    fn relabels_leaves_in_place() {
+        let nwk = "((0:6.00,1:1.50)1.00:2.00,\n 2);\n(3,4);\n";
+        assert_eq!(
+            relabel_newick(nwk, &names(5)).unwrap(),
+            "((T0:6.00,T1:1.50)1.00:2.00,\n T2);\n(T3,T4);\n"
+        );
+    }
+
+    #[test]
+    fn relabel_quotes_names_when_needed() {
+        let tnames = vec!["a:b".to_string(), "c".to_string()];
+        assert_eq!(relabel_newick("(0,1);", &tnames).unwrap(), "('a:b',c);");
+    }
+
+    #[test]
+    fn relabel_rejects_unknown_leaves() {
+        let err = relabel_newick("(0,\n7);", &names(5)).unwrap_err();
+        assert_eq!(err.pos, Some((2, 1)));
+        assert!(relabel_newick("(0,x);", &names(5)).is_err());
+        assert!(relabel_newick("(0,);", &names(5)).is_err());
+    }
+}
@@ -2,13 +2,16 @@ use serde::{Deserialize, Serialize};
 use std::fs::*;
 use std::hash::Hash;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub struct ConsensusFileList {
     pub cons_nwk_file: PathBuf,
     pub cluster_bp_splits_file: PathBuf,
     pub merged_groups_file: PathBuf,
+    pub tree_names_file: PathBuf,
+    pub param_log_file: PathBuf,
+    pub clade_support_file: PathBuf,
     //pub groups_length: PathBuf,
 }
 impl ConsensusFileList {
@@ -28,9 +31,35 @@ impl ConsensusFileList {
             cluster_bp_splits_file: dir.as_path().join("cluster_bipart_splits.txt"),
             cons_nwk_file: dir.as_path().join("cluster_nwk.txt"),
             merged_groups_file: dir.as_path().join("merged_groups_length.txt"),
+            tree_names_file: dir.as_path().join("names.tsv"),
+            param_log_file: dir.as_path().join("consensus_param_log.json"),
+            clade_support_file: dir.as_path().join("clade_support.tsv"),
             //      groups_length: dir.as_path().join("groups_length.txt")
         }
     }
+
+    /// The files of the consensus of the samples of `group`, which share the
+    /// names and parameter logs of the experiment
+    pub fn of_group(&self, group: &str) -> ConsensusFileList {
+        ConsensusFileList {
+            cluster_bp_splits_file: group_path(&self.cluster_bp_splits_file, group),
+            cons_nwk_file: group_path(&self.cons_nwk_file, group),
+            merged_groups_file: group_path(&self.merged_groups_file, group),
+            tree_names_file: self.tree_names_file.clone(),
+            param_log_file: self.param_log_file.clone(),
+            clade_support_file: group_path(&self.clade_support_file, group),
+        }
+    }
+}
+
+/// Inserts the name of a group of samples before the extension of `p`,
+/// e.g. `cluster_nwk.<group>.txt`
+pub fn group_path(p: &Path, group: &str) -> PathBuf {
+    let stem = p.file_stem().unwrap().to_string_lossy();
+    match p.extension() {
+        Some(ext) => p.with_file_name(format!("{}.{}.{}", stem, group, ext.to_string_lossy())),
+        None => p.with_file_name(format!("{}.{}", stem, group)),
+    }
 }
 
 #[derive(Debug)]
@@ -46,8 +75,10 @@ pub struct FileList {
     pub collapsed_log_file: PathBuf,
     pub golden_collapses_log_file: PathBuf,
     pub allele_collapses_log_file: PathBuf,
+    pub cross_gene_collapses_log_file: PathBuf,
     pub group_file: PathBuf,
     pub collapse_order_file: PathBuf,
+    pub collapse_order_bin_file: PathBuf,
     pub delta_file: PathBuf,
     pub cluster_file: PathBuf,
     pub gene_cluster_file: PathBuf,
@@ -55,6 +86,12 @@ pub struct FileList {
     pub cluster_bp_splits_file: PathBuf,
     pub group_nwk_file: PathBuf,
     pub mgroup_nwk_file: PathBuf,
+    pub tree_names_file: PathBuf,
+    pub tree_annotation_file: PathBuf,
+    pub gene_summary_file: PathBuf,
+    pub allele_leaves_file: PathBuf,
+    pub allelic_ratio_file: PathBuf,
+    pub norm_factors_file: PathBuf,
     pub param_log_file: PathBuf,
 }
 
@@ -100,15 +137,23 @@ impl FileList {
             collapsed_log_file: dir.as_path().join("collapsed.log"),
             golden_collapses_log_file: dir.as_path().join("golden_collapses.log"),
             allele_collapses_log_file: dir.as_path().join("allele_collapses.log"),
+            cross_gene_collapses_log_file: dir.as_path().join("cross_gene_collapses.log"),
             group_file: dir.as_path().join("groups.txt"),
             //group_order_file: dir.as_path().join("order.txt"),
             collapse_order_file: dir.as_path().join("collapse_order.json"),
+            collapse_order_bin_file: dir.as_path().join("collapse_order.bin"),
             delta_file: dir.as_path().join("delta.log"),
             gene_cluster_file: dir.as_path().join("gene_cluster.log"),
             group_bp_splits_file: dir.as_path().join("group_bipart_splits.txt"),
             cluster_bp_splits_file: dir.as_path().join("cluster_bipart_splits.txt"),
             group_nwk_file: dir.as_path().join("group_nwk.txt"),
             mgroup_nwk_file: dir.as_path().join("mgroup_nwk.txt"),
+            tree_names_file: dir.as_path().join("names.tsv"),
+            tree_annotation_file: dir.as_path().join("tree_annotation.tsv"),
+            gene_summary_file: dir.as_path().join("gene_summary.json"),
+            allele_leaves_file: dir.as_path().join("allele_leaves.tsv"),
+            allelic_ratio_file: dir.as_path().join("allelic_ratios.tsv"),
+            norm_factors_file: dir.as_path().join("norm_factors.tsv"),
             param_log_file: dir.as_path().join("param_log_file.json"),
             //cluster_nwk_file: dir.as_path().join("cluster_nwk.txt"),
         }
@@ -172,7 +217,7 @@ impl<'a> Iterator for IterEqList<'a> {
 }
 
 impl EqList {
-    pub fn iter(&self) -> IterEqList {
+    pub fn iter(&self) -> IterEqList<'_> {
         IterEqList {
             inner: self,
             pos: 0,
@@ -212,7 +257,7 @@ impl EqClassExperiment {
     ///
     /// * `labs` - A vector of the transcript ids for this eq class
     /// * `weights` - A vector of the conditional probability weights for the
-    ///               transcripts in `labs`.
+    ///   transcripts in `labs`.
     /// * `count` - The number of fragments that are equivalent with respect to this class.
     ///
     pub fn add_class(&mut self, labs: &mut Vec<u32>, weights: &mut Vec<f32>, count: u32) {
@@ -246,8 +291,8 @@ pub struct TxpRecord {
 }
 
 // Iterator over an EqList that yields
-/// each (label, weight, count) tuple in turn.
-///
+// each (label, weight, count) tuple in turn.
+//
 //impl<'a> Iterator for IterTxpRecord<'a> {
 //    type Item = (&'a String, &'a u32, &'a f32, &'a f32, &'a f32);
 //    fn next(&mut self) -> Option<Self::Item> {
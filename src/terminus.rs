@@ -0,0 +1,210 @@
+use std::fs::*;
+use std::io;
+use std::io::{BufReader, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ndarray::prelude::*;
+
+use crate::salmon_types::{FileList, MetaInfo, TxpRecord};
+use crate::util::parse_quant;
+
+// The record of a group, whose lengths are the averages of those of its
+// transcripts weighted by their reads
+fn group_record(name: String, members: &[usize], quant: &[TxpRecord]) -> TxpRecord {
+    let reads: f32 = members.iter().map(|t| quant[*t].NumReads).sum();
+    let weight = |t: usize| match reads > 0.0 {
+        true => quant[t].NumReads / reads,
+        false => 1.0 / members.len() as f32,
+    };
+    TxpRecord {
+        Name: name,
+        Length: members
+            .iter()
+            .map(|t| weight(*t) * quant[*t].Length as f32)
+            .sum::<f32>()
+            .round() as u32,
+        EffectiveLength: members
+            .iter()
+            .map(|t| weight(*t) * quant[*t].EffectiveLength)
+            .sum(),
+        TPM: members.iter().map(|t| quant[*t].TPM).sum(),
+        NumReads: reads,
+    }
+}
+
+/// Writes the collapsed quantification of a salmon directory in the layout
+/// of Terminus's `collapse` step: `clusters.txt`, `quant.sf`, and the
+/// bootstraps, names and meta information in `aux_info`. The transcripts
+/// outside of `groups` are kept in their order, followed by the groups,
+/// named `NewTr<i>`, whose counts are the sums of those of their transcripts.
+pub fn write_collapsed(
+    sal_dir: &FileList,
+    mi: &MetaInfo,
+    gibbs_mat: &Array2<f64>,
+    groups: &[Vec<usize>],
+    out_dir: &Path,
+) -> Result<bool, io::Error> {
+    create_dir_all(out_dir.join("aux_info").join("bootstrap"))?;
+    let file_list_out = FileList::new(out_dir.to_str().unwrap().to_string());
+    let quant = parse_quant(&sal_dir.quant_file, mi)?;
+
+    let mut grouped = vec![false; quant.len()];
+    for g in groups.iter() {
+        for t in g.iter() {
+            grouped[*t] = true;
+        }
+    }
+    let mut rows: Vec<Vec<usize>> = (0..quant.len())
+        .filter(|t| !grouped[*t])
+        .map(|t| vec![t])
+        .collect();
+    let mut records: Vec<TxpRecord> = rows.iter().map(|r| quant[r[0]].clone()).collect();
+
+    let mut cfile = io::BufWriter::new(File::create(&file_list_out.cluster_file)?);
+    for (i, g) in groups.iter().enumerate() {
+        let name = format!("NewTr{}", i);
+        let names: Vec<&str> = g.iter().map(|t| quant[*t].Name.as_str()).collect();
+        writeln!(cfile, "{},{}", name, names.join(","))?;
+        records.push(group_record(name, g, &quant));
+        rows.push(g.clone());
+    }
+    cfile.flush()?;
+
+    let mut qfile = io::BufWriter::new(File::create(&file_list_out.quant_file)?);
+    writeln!(qfile, "Name\tLength\tEffectiveLength\tTPM\tNumReads")?;
+    for r in records.iter() {
+        writeln!(
+            qfile,
+            "{}\t{}\t{}\t{}\t{}",
+            r.Name, r.Length, r.EffectiveLength, r.TPM, r.NumReads
+        )?;
+    }
+    qfile.flush()?;
+
+    let mut bfile = GzEncoder::new(
+        File::create(&file_list_out.bootstrap_file)?,
+        Compression::default(),
+    );
+    let mut data = vec![0_u8; rows.len() * 8];
+    for b in 0..gibbs_mat.shape()[1] {
+        let col: Vec<f64> = rows
+            .iter()
+            .map(|r| r.iter().map(|t| gibbs_mat[[*t, b]]).sum())
+            .collect();
+        LittleEndian::write_f64_into(&col, &mut data);
+        bfile.write_all(&data)?;
+    }
+    bfile.finish()?;
+
+    let mut nfile = GzEncoder::new(
+        File::create(&file_list_out.names_tsv_file)?,
+        Compression::default(),
+    );
+    let names: Vec<&str> = records.iter().map(|r| r.Name.as_str()).collect();
+    writeln!(nfile, "{}", names.join("\t"))?;
+    nfile.finish()?;
+
+    // the other fields of the meta information are kept as they are. No
+    // equivalence classes are written, so none are reported either.
+    let mut meta: serde_json::Value =
+        serde_json::from_reader(BufReader::new(File::open(&sal_dir.mi_file)?))?;
+    meta["num_valid_targets"] = serde_json::json!(records.len());
+    meta["num_eq_classes"] = serde_json::json!(0);
+    serde_json::to_writer_pretty(File::create(&file_list_out.mi_file)?, &meta)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use crate::util::{parse_json, read_gibbs_array};
+
+    // A salmon directory of four transcripts and two replicates
+    fn salmon_dir(name: &str) -> (FileList, MetaInfo, Array2<f64>) {
+        let dir = std::env::temp_dir()
+            .join(format!("treeterminus-terminus-{}", std::process::id()))
+            .join(name);
+        create_dir_all(dir.join("aux_info")).unwrap();
+        std::fs::write(
+            dir.join("quant.sf"),
+            "Name\tLength\tEffectiveLength\tTPM\tNumReads\n\
+             A\t100\t80\t1\t10\n\
+             B\t300\t280\t2\t30\n\
+             C\t200\t180\t3\t0\n\
+             D\t400\t380\t4\t0\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("aux_info").join("meta_info.json"),
+            r#"{"num_valid_targets": 4, "serialized_eq_classes": true, "num_bootstraps": 2, "num_eq_classes": 7, "eq_class_properties": [], "samp_type": "bootstrap", "salmon_version": "1.10.0"}"#,
+        )
+        .unwrap();
+        let file_list = FileList::new(dir.to_str().unwrap().to_string());
+        let mi = parse_json(&file_list.mi_file).unwrap();
+        let gibbs = array![[1.0, 2.0], [10.0, 20.0], [100.0, 200.0], [0.0, 4.0]];
+        (file_list, mi, gibbs)
+    }
+
+    #[test]
+    fn writes_the_collapsed_quantification() {
+        let (file_list, mi, gibbs) = salmon_dir("in");
+        let out_dir = file_list.prefix.parent().unwrap().join("out");
+        write_collapsed(&file_list, &mi, &gibbs, &[vec![0, 1], vec![2, 3]], &out_dir).unwrap();
+        let out = FileList::new(out_dir.to_str().unwrap().to_string());
+
+        assert_eq!(
+            read_to_string(&out.cluster_file).unwrap(),
+            "NewTr0,A,B\nNewTr1,C,D\n"
+        );
+        let quant = read_to_string(&out.quant_file).unwrap();
+        let lines: Vec<&str> = quant.lines().collect();
+        assert_eq!(lines[0], "Name\tLength\tEffectiveLength\tTPM\tNumReads");
+        // the lengths are weighted by the reads, or averaged without any
+        assert_eq!(lines[1], "NewTr0\t250\t230\t3\t40");
+        assert_eq!(lines[2], "NewTr1\t300\t280\t7\t0");
+        assert_eq!(lines.len(), 3);
+
+        let out_mi = parse_json(&out.mi_file).unwrap();
+        assert_eq!(out_mi.num_valid_targets, 2);
+        assert_eq!(out_mi.num_eq_classes, 0);
+        assert_eq!(out_mi.num_bootstraps, 2);
+        let mut sums = Array2::<f64>::zeros((2, 2));
+        read_gibbs_array(&out.bootstrap_file, &out_mi, &mut sums);
+        assert_eq!(sums, array![[11.0, 22.0], [100.0, 204.0]]);
+
+        let mut names = String::new();
+        GzDecoder::new(File::open(&out.names_tsv_file).unwrap())
+            .read_to_string(&mut names)
+            .unwrap();
+        assert_eq!(names, "NewTr0\tNewTr1\n");
+    }
+
+    #[test]
+    fn keeps_ungrouped_transcripts_first() {
+        let (file_list, mi, gibbs) = salmon_dir("partial");
+        let out_dir = file_list.prefix.parent().unwrap().join("partial_out");
+        write_collapsed(&file_list, &mi, &gibbs, &[vec![3, 1]], &out_dir).unwrap();
+        let out = FileList::new(out_dir.to_str().unwrap().to_string());
+
+        assert_eq!(read_to_string(&out.cluster_file).unwrap(), "NewTr0,D,B\n");
+        let quant = read_to_string(&out.quant_file).unwrap();
+        let names: Vec<&str> = quant
+            .lines()
+            .skip(1)
+            .map(|l| l.split('\t').next().unwrap())
+            .collect();
+        assert_eq!(names, vec!["A", "C", "NewTr0"]);
+
+        let out_mi = parse_json(&out.mi_file).unwrap();
+        let mut sums = Array2::<f64>::zeros((3, 2));
+        read_gibbs_array(&out.bootstrap_file, &out_mi, &mut sums);
+        assert_eq!(sums, array![[1.0, 2.0], [100.0, 200.0], [10.0, 24.0]]);
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::binary_tree::{NodeStats, TreeNode};
+
+// Writes a value that holds no tree, such as a label or the statistics
+fn to_json<T: Serialize + ?Sized>(v: &T) -> String {
+    serde_json::to_string(v).expect("could not write a JSON value")
+}
+
+// Writes a tree as nested `{"id", "left", "right", "stats", "chained"}`
+// objects, the last two only when set
+fn write_tree(out: &mut dyn Write, tree: &TreeNode) -> Result<(), io::Error> {
+    enum Step<'a> {
+        Enter(&'a TreeNode),
+        Right(&'a TreeNode),
+        Exit(&'a TreeNode),
+    }
+    let mut stack = vec![Step::Enter(tree)];
+    while let Some(step) = stack.pop() {
+        match step {
+            Step::Enter(node) => {
+                write!(out, "{{\"id\":{},\"left\":", to_json(&node.legacy_id()))?;
+                stack.push(Step::Right(node));
+                match node.left.as_deref() {
+                    Some(l) => stack.push(Step::Enter(l)),
+                    None => out.write_all(b"null")?,
+                }
+            }
+            Step::Right(node) => {
+                out.write_all(b",\"right\":")?;
+                stack.push(Step::Exit(node));
+                match node.right.as_deref() {
+                    Some(r) => stack.push(Step::Enter(r)),
+                    None => out.write_all(b"null")?,
+                }
+            }
+            Step::Exit(node) => {
+                if let Some(stats) = node.stats.as_ref() {
+                    write!(out, ",\"stats\":{}", to_json(stats))?;
+                }
+                if node.chained {
+                    out.write_all(b",\"chained\":true")?;
+                }
+                out.write_all(b"}")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the trees as a JSON object mapping each group id to its tree, the
+/// layout of the `collapse_order.json` of earlier versions, without recursing
+pub fn write_tree_map(
+    out: &mut dyn Write,
+    trees: &HashMap<String, TreeNode>,
+) -> Result<bool, io::Error> {
+    out.write_all(b"{")?;
+    for (i, (group, tree)) in trees.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        write!(out, "{}:", to_json(group))?;
+        write_tree(out, tree)?;
+    }
+    out.write_all(b"}")?;
+    Ok(true)
+}
+
+// A node whose object is being read, along with the children read so far
+struct Frame {
+    id: Option<String>,
+    left: Option<TreeNode>,
+    right: Option<TreeNode>,
+    stats: Option<NodeStats>,
+    chained: bool,
+    // whether the object being read below is the left child
+    reading_left: bool,
+    first_field: bool,
+}
+
+impl Frame {
+    fn new() -> Frame {
+        Frame {
+            id: None,
+            left: None,
+            right: None,
+            stats: None,
+            chained: false,
+            reading_left: false,
+            first_field: true,
+        }
+    }
+}
+
+// A reader of the tree map that keeps the open objects on its own stack.
+// serde_json recurses into nested values, both when parsing and dropping
+// them, which the deep chains of the collapse order would overflow.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, msg: &str) -> io::Error {
+        let before = &self.bytes[..self.i.min(self.bytes.len())];
+        let line = before.iter().filter(|c| **c == b'\n').count() + 1;
+        let col = before.iter().rev().take_while(|c| **c != b'\n').count() + 1;
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} at line {}, column {}", msg, line, col),
+        )
+    }
+
+    // Returns the next character that is not a whitespace
+    fn peek(&mut self) -> Option<u8> {
+        while matches!(self.bytes.get(self.i), Some(c) if c.is_ascii_whitespace()) {
+            self.i += 1;
+        }
+        self.bytes.get(self.i).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), io::Error> {
+        match self.peek() {
+            Some(d) if d == c => {
+                self.i += 1;
+                Ok(())
+            }
+            Some(d) => Err(self.error(&format!("expected '{}', found '{}'", c as char, d as char))),
+            None => Err(self.error(&format!("expected '{}', found the end", c as char))),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, io::Error> {
+        if self.peek() != Some(b'"') {
+            return Err(self.error("expected a string"));
+        }
+        let start = self.i;
+        self.i += 1;
+        loop {
+            match self.bytes.get(self.i) {
+                Some(b'"') => break,
+                Some(b'\\') => self.i += 2,
+                Some(_) => self.i += 1,
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+        self.i += 1;
+        serde_json::from_slice(&self.bytes[start..self.i]).map_err(|_| self.error("invalid string"))
+    }
+
+    // Reads a number, `true`, `false` or `null`
+    fn scalar(&mut self) -> Result<&'a str, io::Error> {
+        self.peek();
+        let start = self.i;
+        while matches!(self.bytes.get(self.i), Some(c) if !b",:{}[]\" \t\r\n".contains(c)) {
+            self.i += 1;
+        }
+        match start == self.i {
+            true => Err(self.error("expected a value")),
+            false => std::str::from_utf8(&self.bytes[start..self.i])
+                .map_err(|_| self.error("invalid value")),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, io::Error> {
+        let s = self.scalar()?;
+        s.parse::<f64>()
+            .map_err(|_| self.error(&format!("invalid number {:?}", s)))
+    }
+
+    fn boolean(&mut self) -> Result<bool, io::Error> {
+        match self.scalar()? {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            s => Err(self.error(&format!("invalid boolean {:?}", s))),
+        }
+    }
+
+    // Reads a `null`, returning false if the value is something else
+    fn null(&mut self) -> bool {
+        self.peek();
+        let null = self.bytes[self.i..].starts_with(b"null");
+        if null {
+            self.i += 4;
+        }
+        null
+    }
+
+    // Skips a value of a field this reader does not know
+    fn skip_value(&mut self) -> Result<(), io::Error> {
+        let mut depth = 0_usize;
+        loop {
+            match self.peek() {
+                Some(b'{') | Some(b'[') => {
+                    self.i += 1;
+                    depth += 1;
+                }
+                Some(b'}') | Some(b']') if depth > 0 => {
+                    self.i += 1;
+                    depth -= 1;
+                }
+                Some(b',') | Some(b':') if depth > 0 => self.i += 1,
+                Some(b'"') => {
+                    self.string()?;
+                }
+                _ => {
+                    self.scalar()?;
+                }
+            }
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn stats(&mut self) -> Result<NodeStats, io::Error> {
+        let (mut infrv, mut mean) = (None, None);
+        self.expect(b'{')?;
+        let mut first_field = true;
+        while self.peek() != Some(b'}') {
+            if !first_field {
+                self.expect(b',')?;
+            }
+            first_field = false;
+            let key = self.string()?;
+            self.expect(b':')?;
+            match key.as_str() {
+                "infrv" => infrv = Some(self.number()?),
+                "mean" => mean = Some(self.number()?),
+                _ => self.skip_value()?,
+            }
+        }
+        self.i += 1;
+        match (infrv, mean) {
+            (Some(infrv), Some(mean)) => Ok(NodeStats { infrv, mean }),
+            _ => Err(self.error("statistics without infrv or mean")),
+        }
+    }
+
+    // Reads a tree, keeping the nodes whose objects are open on a stack
+    fn tree(&mut self) -> Result<TreeNode, io::Error> {
+        self.expect(b'{')?;
+        let mut stack = vec![Frame::new()];
+        loop {
+            let frame = stack.last_mut().unwrap();
+            if self.peek() == Some(b'}') {
+                self.i += 1;
+                let frame = stack.pop().unwrap();
+                let id = match frame.id {
+                    Some(id) => id,
+                    None => return Err(self.error("node without an id")),
+                };
+                let (left, right) = match (frame.left, frame.right) {
+                    (Some(l), Some(r)) => (Some(Box::new(l)), Some(Box::new(r))),
+                    (None, None) => (None, None),
+                    _ => return Err(self.error(&format!("node {} has a single child", id))),
+                };
+                let node = TreeNode {
+                    id,
+                    left,
+                    right,
+                    stats: frame.stats,
+                    chained: frame.chained,
+                };
+                match stack.last_mut() {
+                    None => return Ok(node),
+                    Some(parent) if parent.reading_left => parent.left = Some(node),
+                    Some(parent) => parent.right = Some(node),
+                }
+                continue;
+            }
+            if !frame.first_field {
+                self.expect(b',')?;
+            }
+            frame.first_field = false;
+            let key = self.string()?;
+            self.expect(b':')?;
+            match key.as_str() {
+                "id" => frame.id = Some(self.string()?),
+                "stats" if self.null() => frame.stats = None,
+                "stats" => frame.stats = Some(self.stats()?),
+                "chained" => frame.chained = self.boolean()?,
+                "left" | "right" if self.null() => {}
+                "left" | "right" => {
+                    self.expect(b'{')?;
+                    frame.reading_left = key == "left";
+                    stack.push(Frame::new());
+                }
+                _ => self.skip_value()?,
+            }
+        }
+    }
+}
+
+/// Reads a JSON object mapping group ids to their trees, as written by
+/// `write_tree_map`, without recursing however deep the trees are
+pub fn read_tree_map(bytes: &[u8]) -> Result<HashMap<String, TreeNode>, io::Error> {
+    let mut reader = Reader { bytes, i: 0 };
+    let mut trees = HashMap::new();
+    reader.expect(b'{')?;
+    while reader.peek() != Some(b'}') {
+        if !trees.is_empty() {
+            reader.expect(b',')?;
+        }
+        let group = reader.string()?;
+        reader.expect(b':')?;
+        let tree = reader.tree()?;
+        trees.insert(group, tree);
+    }
+    reader.i += 1;
+    if reader.peek().is_some() {
+        return Err(reader.error("unexpected characters after the trees"));
+    }
+    Ok(trees)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(infrv: f64) -> Option<NodeStats> {
+        Some(NodeStats { infrv, mean: 2.5 })
+    }
+
+    // ((0,1),2) with statistics and a chained node, and the single leaf 3
+    fn trees() -> HashMap<String, TreeNode> {
+        let mut inner = TreeNode::create_group(
+            TreeNode::create_leaf("0".to_string()),
+            TreeNode::create_leaf("1".to_string()),
+        );
+        inner.chained = true;
+        inner.stats = stats(0.5);
+        let mut root = TreeNode::create_group(inner, TreeNode::create_leaf("2".to_string()));
+        root.stats = stats(0.25);
+        let mut trees = HashMap::new();
+        trees.insert("0_1_2".to_string(), root);
+        trees.insert("3".to_string(), TreeNode::create_leaf("3".to_string()));
+        trees
+    }
+
+    fn to_string(trees: &HashMap<String, TreeNode>) -> String {
+        let mut out = Vec::new();
+        write_tree_map(&mut out, trees).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn shape(tree: &TreeNode) -> Vec<(String, bool, Option<NodeStats>)> {
+        tree.postorder()
+            .iter()
+            .map(|n| (n.legacy_id(), n.chained, n.stats))
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_trees() {
+        let trees = trees();
+        let read = read_tree_map(to_string(&trees).as_bytes()).unwrap();
+        assert_eq!(read.len(), 2);
+        for (group, tree) in trees.iter() {
+            assert_eq!(shape(&read[group]), shape(tree));
+        }
+    }
+
+    #[test]
+    fn writes_optional_fields_only_when_set() {
+        let mut trees = HashMap::new();
+        trees.insert("0_1".to_string(), trees_of("0", "1"));
+        assert_eq!(
+            to_string(&trees),
+            r#"{"0_1":{"id":"0_1","left":{"id":"0","left":null,"right":null},"right":{"id":"1","left":null,"right":null}}}"#
+        );
+    }
+
+    fn trees_of(a: &str, b: &str) -> TreeNode {
+        TreeNode::create_group(
+            TreeNode::create_leaf(a.to_string()),
+            TreeNode::create_leaf(b.to_string()),
+        )
+    }
+
+    #[test]
+    fn reads_formatted_json_with_unknown_fields() {
+        let json = r#"
+        {
+            "1_2": {
+                "id": "1_2",
+                "extra": [1, {"a": "}"}, null],
+                "left": {"id": "1", "left": null, "right": null, "stats": null},
+                "right": {"id": "2", "left": null, "right": null, "chained": false},
+                "stats": {"infrv": 1e-2, "mean": 3, "other": true}
+            },
+            "esc\"aped": {"id": "3", "left": null, "right": null}
+        }
+        "#;
+        let trees = read_tree_map(json.as_bytes()).unwrap();
+        let tree = &trees["1_2"];
+        assert_eq!(
+            shape(tree),
+            vec![
+                ("1".to_string(), false, None),
+                ("2".to_string(), false, None),
+                (
+                    "1_2".to_string(),
+                    false,
+                    Some(NodeStats {
+                        infrv: 0.01,
+                        mean: 3.0
+                    })
+                ),
+            ]
+        );
+        assert!(trees.contains_key("esc\"aped"));
+        assert!(read_tree_map(b"{}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn round_trips_deep_chains() {
+        let mut tree = TreeNode::create_leaf("0".to_string());
+        for i in 1..100_000 {
+            tree = TreeNode {
+                id: format!("g{}", i),
+                left: Some(Box::new(tree)),
+                right: Some(Box::new(TreeNode::create_leaf(i.to_string()))),
+                stats: None,
+                chained: false,
+            };
+        }
+        let mut trees = HashMap::new();
+        trees.insert("chain".to_string(), tree);
+        let read = read_tree_map(to_string(&trees).as_bytes()).unwrap();
+        assert_eq!(read["chain"].postorder().len(), 199_999);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let leaf = r#"{"id":"0","left":null,"right":null}"#;
+        let malformed = [
+            String::new(),
+            "[]".to_string(),
+            "{".to_string(),
+            format!(r#"{{"a":{}"#, leaf),
+            format!(r#"{{"a":{}}} x"#, leaf),
+            format!(r#"{{"a":{} "b":{}}}"#, leaf, leaf),
+            r#"{"a":{"left":null,"right":null}}"#.to_string(),
+            format!(r#"{{"a":{{"id":"1","left":{},"right":null}}}}"#, leaf),
+            r#"{"a":{"id":"0","left":null,"right":null,"stats":{"infrv":x,"mean":1}}}"#.to_string(),
+            r#"{"a":{"id":"0","left":null,"right":null,"stats":{"infrv":1}}}"#.to_string(),
+            r#"{"a":{"id":"0","left":null,"right":null,"chained":yes}}"#.to_string(),
+            r#"{"a":{"id":"0,"left":null,"right":null}}"#.to_string(),
+            r#"{"a":{"id":0,"left":null,"right":null}}"#.to_string(),
+        ];
+        for json in malformed.iter() {
+            assert!(read_tree_map(json.as_bytes()).is_err(), "accepted {}", json);
+        }
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        let err = read_tree_map(b"{\n\"a\": {\"id\": 1}}").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().ends_with("at line 2, column 13"), "{}", err);
+    }
+}
@@ -22,16 +22,26 @@ use rand::distributions::{Distribution, Uniform};
 use rand_core::SeedableRng;
 use rand_pcg::Pcg64;
 use refinery::Partition;
+use serde::Serialize;
 //use rand::thread_rng;
 //use rgsl::statistics::correlation;
-use crate::binary_tree::{get_binary_rooted_newick_string, sort_group_id, TreeNode};
+use crate::binary_tree::{
+    group_id, CollapseOrder, NewickOptions, NodeStats, TreeArena, TreeNode, COLLAPSE_ORDER_VERSION,
+};
+use crate::nary_tree::NaryNode;
 use crate::salmon_types::{EdgeInfo, EqClassExperiment, FileList, MetaInfo, TxpRecord};
+use crate::tree_json::{read_tree_map, write_tree_map};
 use flate2::read::GzDecoder;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, error, info, warn};
 use statrs::distribution::{ContinuousCDF, Normal};
 use std::iter::FromIterator;
 
-// use flate2::write::GzEncoder;
-// use flate2::Compression;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 
 // General functions to r/w files
 // files to be handled
@@ -41,6 +51,159 @@ use std::iter::FromIterator;
 // bootstraps.gz
 // ambig_info.tsv
 
+/// Compression applied to the large output files (logs, collapse order
+/// and bipartition splits).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl OutputCompression {
+    pub const ALL: [OutputCompression; 3] = [
+        OutputCompression::None,
+        OutputCompression::Gzip,
+        OutputCompression::Zstd,
+    ];
+
+    /// The extension appended to a file name compressed with this method
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputCompression::None => "",
+            OutputCompression::Gzip => ".gz",
+            OutputCompression::Zstd => ".zst",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputCompression::None => "none",
+            OutputCompression::Gzip => "gzip",
+            OutputCompression::Zstd => "zstd",
+        }
+    }
+}
+
+impl FromStr for OutputCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(OutputCompression::None),
+            "gzip" | "gz" => Ok(OutputCompression::Gzip),
+            "zstd" | "zst" => Ok(OutputCompression::Zstd),
+            _ => Err(format!("unknown compression {}", s)),
+        }
+    }
+}
+
+fn with_extension(p: &Path, ext: &str) -> PathBuf {
+    let mut s = p.as_os_str().to_owned();
+    s.push(ext);
+    PathBuf::from(s)
+}
+
+/// A file opened by `create_writer`. Compressed files are only complete
+/// once their trailer is written, so `finish` has to be called when all
+/// the output is written to report the errors that dropping would ignore.
+pub enum OutputWriter {
+    Plain(io::BufWriter<File>),
+    Gzip(io::BufWriter<GzEncoder<File>>),
+    Zstd(io::BufWriter<zstd::stream::write::Encoder<'static, File>>),
+}
+
+impl OutputWriter {
+    /// Flushes the buffered output and writes the compression trailer
+    pub fn finish(self) -> Result<(), io::Error> {
+        match self {
+            OutputWriter::Plain(w) => {
+                w.into_inner().map_err(io::IntoInnerError::into_error)?;
+            }
+            OutputWriter::Gzip(w) => {
+                w.into_inner()
+                    .map_err(io::IntoInnerError::into_error)?
+                    .finish()?;
+            }
+            OutputWriter::Zstd(w) => {
+                w.into_inner()
+                    .map_err(io::IntoInnerError::into_error)?
+                    .finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(w) => w.write(buf),
+            OutputWriter::Gzip(w) => w.write(buf),
+            OutputWriter::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(w) => w.flush(),
+            OutputWriter::Gzip(w) => w.flush(),
+            OutputWriter::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// Creates `p` (with the extension of `compression` appended) for writing.
+/// Copies of `p` written earlier with a different compression are removed,
+/// so that readers never pick up stale output.
+pub fn create_writer(p: &Path, compression: OutputCompression) -> OutputWriter {
+    for c in OutputCompression::ALL.iter() {
+        let stale = with_extension(p, c.extension());
+        if *c != compression && stale.is_file() {
+            remove_file(&stale).unwrap_or_else(|_| panic!("could not remove {:?}", stale));
+        }
+    }
+    let path = with_extension(p, compression.extension());
+    let file = File::create(&path).unwrap_or_else(|_| panic!("could not create {:?}", path));
+    match compression {
+        OutputCompression::None => OutputWriter::Plain(io::BufWriter::new(file)),
+        OutputCompression::Gzip => OutputWriter::Gzip(io::BufWriter::new(GzEncoder::new(
+            file,
+            Compression::default(),
+        ))),
+        OutputCompression::Zstd => OutputWriter::Zstd(io::BufWriter::new(
+            zstd::stream::write::Encoder::new(file, 0).expect("could not create zstd encoder"),
+        )),
+    }
+}
+
+/// Returns the path of the file written at `p`, whichever compression was
+/// used to write it.
+pub fn resolve_compressed_path(p: &Path) -> Option<PathBuf> {
+    OutputCompression::ALL
+        .iter()
+        .map(|c| with_extension(p, c.extension()))
+        .find(|path| path.is_file())
+}
+
+/// Opens a file written by `create_writer` at `p`, transparently
+/// decompressing it if needed.
+pub fn open_reader(p: &Path) -> Box<dyn BufRead> {
+    let path = resolve_compressed_path(p)
+        .unwrap_or_else(|| panic!("could not find {:?} or a compressed version of it", p));
+    let file = File::open(&path).unwrap_or_else(|_| panic!("could not open {:?}", path));
+    let name = path.to_string_lossy();
+    if name.ends_with(".gz") {
+        Box::new(BufReader::new(GzDecoder::new(file)))
+    } else if name.ends_with(".zst") {
+        Box::new(BufReader::new(
+            zstd::stream::read::Decoder::new(file).expect("could not create zstd decoder"),
+        ))
+    } else {
+        Box::new(BufReader::new(file))
+    }
+}
+
 // pub fn bipart_writer(
 //     g_bp_file: &mut File,
 //     group_bipart: &HashMap<String, Vec<String>>
@@ -55,12 +218,12 @@ use std::iter::FromIterator;
 //     }
 //     Ok(true)
 // }
-fn conv_names(g: &str, tnames: &[String]) -> String {
-    let s: Vec<String> = g
-        .split('_')
-        .map(|x| tnames[x.parse::<usize>().unwrap()].clone())
-        .collect();
-    s.join(",")
+/// Returns the names of the transcripts `txps`, joined by `,`
+pub fn txp_names(txps: &[u32], tnames: &[String]) -> String {
+    txps.iter()
+        .map(|t| tnames[*t as usize].as_str())
+        .collect::<Vec<&str>>()
+        .join(",")
 }
 
 // pub trait MapTrait {
@@ -69,8 +232,8 @@ fn conv_names(g: &str, tnames: &[String]) -> String {
 
 // impl MapTrait for HashMap<String, HashMap<String, u32>> {
 pub fn bipart_writer(
-    part_hash: &HashMap<String, HashMap<String, u32>>,
-    g_bp_file: &mut File,
+    part_hash: &HashMap<Vec<u32>, HashMap<Vec<u32>, u32>>,
+    g_bp_file: &mut dyn Write,
     tnames: &[String],
 ) -> Result<bool, io::Error> {
     //let l = group_bipart.len();
@@ -79,13 +242,13 @@ pub fn bipart_writer(
         writeln!(
             g_bp_file,
             "gr\t{}\t{}",
-            conv_names(group_id, tnames),
+            txp_names(group_id, tnames),
             bpart_hash.len()
         )?;
         let mut v = Vec::from_iter(bpart_hash);
         v.sort_by(|&(_, a), &(_, b)| b.cmp(a));
         for (bpart, count) in v {
-            writeln!(g_bp_file, "{}\t{}", conv_names(bpart, tnames), count)?;
+            writeln!(g_bp_file, "{}\t{}", txp_names(bpart, tnames), count)?;
         }
     }
     Ok(true)
@@ -108,6 +271,33 @@ pub fn bipart_writer(
 //     Ok(true)
 // }
 
+/// Writes the index to name mapping of the transcripts, which labels the
+/// leaves of the trees written next to it
+pub fn names_writer(p: &Path, tnames: &[String]) -> Result<bool, io::Error> {
+    let mut nfile = io::BufWriter::new(File::create(p)?);
+    for (i, name) in tnames.iter().enumerate() {
+        writeln!(nfile, "{}\t{}", i, name)?;
+    }
+    nfile.flush()?;
+    Ok(true)
+}
+
+/// Reads the mapping written by `names_writer`, from transcript name to index
+pub fn names_reader(p: &Path) -> HashMap<String, usize> {
+    let mut names = HashMap::new();
+    for line in open_reader(p).lines() {
+        let line = line.unwrap_or_else(|_| panic!("could not read {:?}", p));
+        let mut fields = line.splitn(2, '\t');
+        let i = fields
+            .next()
+            .and_then(|i| i.parse::<usize>().ok())
+            .unwrap_or_else(|| panic!("malformed line {:?} in {:?}", line, p));
+        let name = fields.next().unwrap_or_default();
+        names.insert(name.to_string(), i);
+    }
+    names
+}
+
 pub fn group_writer(
     gfile: &mut File,
     groups: &HashMap<usize, Vec<usize>>,
@@ -123,40 +313,108 @@ pub fn group_writer(
 }
 
 pub fn collapse_order_writer(
-    co_file: &mut File,
+    co_file: &mut dyn Write,
     nwk_file: &mut File,
     groups: &HashMap<usize, Vec<usize>>,
-    c_order: &[TreeNode],
+    c_order: &TreeArena,
+    nwk_opts: &NewickOptions,
 ) -> Result<bool, io::Error> {
     //let mut buffer = File::create("groups.txt")?;
     //let mut buffer = File::create("foo.txt").unwrap();
     //let mut file = GzEncoder::new(file_handle, Compression::default());
-    let mut co_updated: HashMap<String, TreeNode> = HashMap::new();
+    let mut co_updated = CollapseOrder {
+        version: COLLAPSE_ORDER_VERSION,
+        trees: Vec::with_capacity(groups.len()),
+        stats: Vec::with_capacity(groups.len()),
+    };
 
-    // co_updated.insert(0, c_order[0].clone());
-    // co_updated.insert(1, c_order[1].clone());
-    // co_updated.insert(2, c_order[10].clone());
     for group_id in groups.keys() {
-        co_updated.insert(
-            sort_group_id(&c_order[*group_id].id),
-            c_order[*group_id].clone(),
-        );
-        let mut nwk = get_binary_rooted_newick_string(&c_order[*group_id]);
-        nwk.push(';');
+        let root = c_order.roots[*group_id];
+        co_updated.trees.push(c_order.to_postorder(root));
+        co_updated.stats.push(c_order.postorder_stats(root));
+        let nwk = NaryNode::from_arena(c_order, root).to_newick_string(nwk_opts);
         writeln!(nwk_file, "{}", nwk)?;
     }
-    //println!("{:?}", co_updated);
-    let err_write = format!(
-        "Could not create/write collapsed_order.json in {:?}",
-        co_file
-    );
 
     //let serialized = serde_pickle::to_writer(co_file, &co_updated, true);
-    ::serde_json::to_writer(co_file, &co_updated).expect(&err_write);
+    write_collapse_order_bin(co_file, &co_updated)
+}
 
+pub fn write_collapse_order_bin(
+    co_file: &mut dyn Write,
+    co: &CollapseOrder,
+) -> Result<bool, io::Error> {
+    bincode::serialize_into(&mut *co_file, co).expect("Could not create/write collapse_order.bin");
+    co_file.flush()?;
     Ok(true)
 }
 
+/// Reads the compact collapse order written by `collapse_order_writer`
+pub fn read_collapse_order_bin(p: &Path) -> CollapseOrder {
+    let reader = open_reader(p);
+    let co: CollapseOrder = bincode::deserialize_from(reader)
+        .unwrap_or_else(|e| panic!("could not parse the collapse order {:?}: {}", p, e));
+    if co.version != COLLAPSE_ORDER_VERSION {
+        panic!(
+            "collapse order {:?} has version {}, expected {}",
+            p, co.version, COLLAPSE_ORDER_VERSION
+        );
+    }
+    co
+}
+
+/// Reads the collapse order stored as nested JSON by earlier versions
+pub fn read_collapse_order_json(p: &Path) -> HashMap<String, TreeNode> {
+    let mut bytes = Vec::new();
+    open_reader(p)
+        .read_to_end(&mut bytes)
+        .unwrap_or_else(|e| panic!("could not read the collapse order {:?}: {}", p, e));
+    read_tree_map(&bytes)
+        .unwrap_or_else(|e| panic!("could not parse the collapse order {:?}: {}", p, e))
+}
+
+pub fn write_collapse_order_json(
+    co_file: &mut dyn Write,
+    trees: &HashMap<String, TreeNode>,
+) -> Result<bool, io::Error> {
+    write_tree_map(co_file, trees)?;
+    co_file.flush()?;
+    Ok(true)
+}
+
+/// Converts the compact collapse order into the map from the (sorted)
+/// group id to its tree that the JSON format stores
+pub fn collapse_order_to_map(co: &CollapseOrder) -> HashMap<String, TreeNode> {
+    co.trees
+        .iter()
+        .enumerate()
+        .map(|(i, order)| {
+            let stats = co.stats.get(i).map_or(&[][..], |s| &s[..]);
+            let tree = TreeNode::from_postorder(order, stats);
+            (group_id(&tree.leaves()), tree)
+        })
+        .collect()
+}
+
+/// Reads the group trees of a run from its output directory, from the
+/// compact collapse order if present and from the JSON otherwise, into
+/// the roots of an arena
+pub fn read_collapse_order(file_list: &FileList) -> TreeArena {
+    let mut arena = TreeArena::default();
+    if resolve_compressed_path(&file_list.collapse_order_bin_file).is_some() {
+        let co = read_collapse_order_bin(&file_list.collapse_order_bin_file);
+        for (i, order) in co.trees.iter().enumerate() {
+            let stats = co.stats.get(i).map_or(&[][..], |s| &s[..]);
+            arena.push_postorder(order, stats);
+        }
+    } else {
+        for tree in read_collapse_order_json(&file_list.collapse_order_file).values() {
+            arena.push_tree(tree);
+        }
+    }
+    arena
+}
+
 #[allow(dead_code)]
 pub fn get_merged_mat(
     gibbs_mat: &Array2<f64>,
@@ -177,7 +435,6 @@ pub fn get_merged_mat(
     merged_gibbs_mat
 }
 
-#[allow(dead_code)]
 pub fn parse_quant(p: &std::path::Path, mi: &MetaInfo) -> Result<Vec<TxpRecord>, io::Error> {
     let file = File::open(p);
     let mut rdr = csv::ReaderBuilder::new()
@@ -200,14 +457,127 @@ pub fn parse_quant(p: &std::path::Path, mi: &MetaInfo) -> Result<Vec<TxpRecord>,
     Ok(rs)
 }
 
+/// The library size normalisation applied to the replicates of each sample
+/// in the mean mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    None,
+    /// the total number of reads
+    Total,
+    /// the median of the ratios of the reads of each transcript to their
+    /// geometric mean over the samples, as in DESeq2
+    MedianOfRatios,
+}
+
+impl Normalization {
+    pub fn name(self) -> &'static str {
+        match self {
+            Normalization::None => "none",
+            Normalization::Total => "total",
+            Normalization::MedianOfRatios => "mor",
+        }
+    }
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Normalization::None),
+            "total" => Ok(Normalization::Total),
+            "mor" => Ok(Normalization::MedianOfRatios),
+            _ => Err(format!("unknown normalization {}", s)),
+        }
+    }
+}
+
+/// Returns the normalisation factor of each sample from the `NumReads` of
+/// its quantification, scaled so that their geometric mean is 1. The counts
+/// of a sample are divided by its factor. Samples without reads are rejected,
+/// and the median of ratios only uses the transcripts with reads in every
+/// sample, as DESeq does.
+pub fn norm_factors(quants: &[Vec<TxpRecord>], norm: Normalization) -> Result<Vec<f64>, io::Error> {
+    let log_factors: Vec<f64> = match norm {
+        Normalization::None => vec![0.0; quants.len()],
+        Normalization::Total => {
+            let mut log_totals = Vec::with_capacity(quants.len());
+            for (i, q) in quants.iter().enumerate() {
+                let total = q.iter().map(|r| r.NumReads as f64).sum::<f64>();
+                if total <= 0.0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("sample {} has no reads, cannot normalize by its total", i),
+                    ));
+                }
+                log_totals.push(total.ln());
+            }
+            log_totals
+        }
+        Normalization::MedianOfRatios => {
+            // the transcripts with reads in every sample
+            let ntxps = quants.first().map_or(0, |q| q.len());
+            let expressed: Vec<usize> = (0..ntxps)
+                .filter(|t| quants.iter().all(|q| q[*t].NumReads > 0.0))
+                .collect();
+            if expressed.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "no transcript has reads in every sample, cannot compute the median of ratios",
+                ));
+            }
+            let log_geo_means: Vec<f64> = expressed
+                .iter()
+                .map(|t| {
+                    quants
+                        .iter()
+                        .map(|q| (q[*t].NumReads as f64).ln())
+                        .sum::<f64>()
+                        / quants.len() as f64
+                })
+                .collect();
+            quants
+                .iter()
+                .map(|q| {
+                    let mut ratios: Vec<f64> = expressed
+                        .iter()
+                        .zip(log_geo_means.iter())
+                        .map(|(t, g)| (q[*t].NumReads as f64).ln() - g)
+                        .collect();
+                    let n = ratios.len();
+                    rgsl::sort::vectors::sort(&mut ratios, 1, n);
+                    rgsl::statistics::quantile_from_sorted_data(&ratios, 1, n, 0.5)
+                })
+                .collect()
+        }
+    };
+    let log_mean = log_factors.iter().sum::<f64>() / log_factors.len() as f64;
+    Ok(log_factors.iter().map(|f| (f - log_mean).exp()).collect())
+}
+
+/// Writes the total reads and normalisation factor of each sample
+pub fn norm_factors_writer(
+    out: &mut dyn Write,
+    samples: &[String],
+    quants: &[Vec<TxpRecord>],
+    factors: &[f64],
+) -> Result<bool, io::Error> {
+    writeln!(out, "sample\tnum_reads\tnorm_factor")?;
+    for ((sample, q), f) in samples.iter().zip(quants.iter()).zip(factors.iter()) {
+        let reads: f64 = q.iter().map(|r| r.NumReads as f64).sum();
+        writeln!(out, "{}\t{}\t{}", sample, reads, f)?;
+    }
+    Ok(true)
+}
+
 pub fn parse_json(p: &std::path::Path) -> Result<MetaInfo, io::Error> {
     let file = File::open(p);
     let reader = BufReader::new(file.unwrap());
 
     let jd: MetaInfo = serde_json::from_reader(reader).unwrap();
-    println!("# targets : {}", jd.num_valid_targets);
-    println!("did serialize eq classes : {}", jd.serialized_eq_classes);
-    println!("# boot : {}", jd.num_bootstraps);
+    info!("# targets : {}", jd.num_valid_targets);
+    debug!("did serialize eq classes : {}", jd.serialized_eq_classes);
+    info!("# boot : {}", jd.num_bootstraps);
     Ok(jd)
 }
 
@@ -282,15 +652,18 @@ pub fn get_ambig(filename: &std::path::Path) -> Vec<u32> {
 /// *`ent2_ent1map` - A mutable reference to hashmap that would store for each ent2 - corresponding ent1 indexes
 /// *`ent1_ent2map` - A mutable reference to vector that would store for each ent1 - its corresponding ent2 index
 /// *`tnames` - Txp/Allele (aka ent1) names that are present in eq_class file
+///
+/// Returns the names of the ent2, in the order of their indexes
 pub fn get_map_bw_ent(
     filename: &std::path::Path,
     ent2_ent1map: &mut HashMap<usize, Vec<usize>>,
     ent1_ent2map: &mut Vec<usize>,
     tnames: &HashMap<String, usize>,
-) {
+) -> Vec<String> {
     let file = File::open(filename).expect("File could not be opened");
     let buf_reader = BufReader::new(file);
     let mut ent2_map = HashMap::<String, usize>::new();
+    let mut ent2_names = Vec::<String>::new();
     *ent1_ent2map = vec![0; tnames.len()];
     let mut j = 0;
     for l in buf_reader.lines() {
@@ -310,14 +683,16 @@ pub fn get_map_bw_ent(
         // let ent2_ind = ent2_map.get(&ent2);
         if !ent2_map.contains_key(&ent2) {
             ent2_map.insert(ent2.clone(), j);
+            ent2_names.push(ent2.clone());
             j += 1;
         }
 
         let ent2_ind = *ent2_map.get(&ent2).unwrap();
-        let val = ent2_ent1map.entry(ent2_ind).or_insert_with(Vec::new);
+        let val = ent2_ent1map.entry(ent2_ind).or_default();
         ent1_ent2map[index] = ent2_ind;
         val.push(index);
     }
+    ent2_names
 }
 
 #[allow(dead_code)]
@@ -371,15 +746,83 @@ fn var_1d(a: ArrayView1<'_, f64>) -> f64 {
 }
 */
 
-// find min and max divide by mean
-fn infrv_1d(a: ArrayView1<'_, f64>) -> f64 {
+/// The measure of inferential uncertainty computed over the replicates of
+/// a transcript or group
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InfRVMeasure {
+    /// the inferential relative variance of Swish, the variance in excess of
+    /// the mean relative to the mean
+    Swish,
+    /// the coefficient of variation
+    CV,
+    /// the variance of the log counts
+    LogVar,
+    /// the width of the central 95% interval of the replicates relative to
+    /// the mean
+    Interval,
+}
+
+impl FromStr for InfRVMeasure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "swish" => Ok(InfRVMeasure::Swish),
+            "cv" => Ok(InfRVMeasure::CV),
+            "logvar" => Ok(InfRVMeasure::LogVar),
+            "interval" => Ok(InfRVMeasure::Interval),
+            _ => Err(format!(
+                "unknown infRV measure {:?}, expected swish, cv, logvar or interval",
+                s
+            )),
+        }
+    }
+}
+
+/// The definition of the infRV used throughout the grouping: the measure,
+/// the pseudocount added to the mean (or to the counts before taking their
+/// log) and the offset added to, and flooring, the result
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InfRV {
+    pub measure: InfRVMeasure,
+    pub pseudocount: f64,
+    pub offset: f64,
+}
+
+impl Default for InfRV {
+    fn default() -> InfRV {
+        InfRV {
+            measure: InfRVMeasure::Swish,
+            pseudocount: 5.,
+            offset: 0.01,
+        }
+    }
+}
+
+pub fn infrv_1d(a: ArrayView1<'_, f64>, infrv_def: &InfRV) -> f64 {
     let mu = a.mean().unwrap();
-    let var = a.var_axis(Axis(0), 1.).into_scalar();
+    let pc = infrv_def.pseudocount;
+    let x = match infrv_def.measure {
+        InfRVMeasure::Swish => (a.var_axis(Axis(0), 1.).into_scalar() - mu) / (mu + pc),
+        InfRVMeasure::CV => a.std_axis(Axis(0), 1.).into_scalar() / (mu + pc),
+        InfRVMeasure::LogVar => a
+            .mapv(|c| (c + pc).ln())
+            .var_axis(Axis(0), 1.)
+            .into_scalar(),
+        InfRVMeasure::Interval => {
+            let mut sorted = a.to_vec();
+            let n = sorted.len();
+            rgsl::sort::vectors::sort(&mut sorted, 1, n);
+            let lo = rgsl::statistics::quantile_from_sorted_data(&sorted, 1, n, 0.025);
+            let hi = rgsl::statistics::quantile_from_sorted_data(&sorted, 1, n, 0.975);
+            (hi - lo) / (mu + pc)
+        }
+    };
     //(var) / (mu + 0.1) + 0.01
-    if (var - mu) >= 0. {
-        (var - mu) / (mu + 5.) + 0.01
+    if x >= 0. {
+        x + infrv_def.offset
     } else {
-        0.01
+        infrv_def.offset
     }
 }
 
@@ -395,15 +838,225 @@ fn spread1d(a: ArrayView1<'_, f64>) -> f64 {
     (maximum - minimum) / mean
 }
 
-fn infrv(a: &Array2<f64>, axis: Axis) -> Array1<f64> {
-    a.map_axis(axis, infrv_1d)
+/// How a statistic computed in each sample is combined over the samples
+/// in the mean mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Max,
+    Min,
+    Mean,
+    Median,
+    Quantile(f64),
+}
+
+impl Aggregate {
+    pub fn of(self, xs: &[f64]) -> f64 {
+        match self {
+            Aggregate::Max => xs.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Aggregate::Min => xs.iter().copied().fold(f64::INFINITY, f64::min),
+            Aggregate::Mean => xs.iter().sum::<f64>() / xs.len() as f64,
+            Aggregate::Median => Aggregate::Quantile(0.5).of(xs),
+            Aggregate::Quantile(q) => {
+                let mut sorted = xs.to_vec();
+                let n = sorted.len();
+                rgsl::sort::vectors::sort(&mut sorted, 1, n);
+                rgsl::statistics::quantile_from_sorted_data(&sorted, 1, n, q)
+            }
+        }
+    }
+
+    /// Combines the per-sample arrays element by element
+    pub fn of_arrays(self, arrays: &[Array1<f64>]) -> Array1<f64> {
+        let mut xs = vec![0.0; arrays.len()];
+        Array1::from_shape_fn(arrays[0].len(), |j| {
+            for (x, a) in xs.iter_mut().zip(arrays.iter()) {
+                *x = a[j];
+            }
+            self.of(&xs)
+        })
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            Aggregate::Max => "max".to_string(),
+            Aggregate::Min => "min".to_string(),
+            Aggregate::Mean => "mean".to_string(),
+            Aggregate::Median => "median".to_string(),
+            Aggregate::Quantile(q) => format!("quantile:{}", q),
+        }
+    }
+}
+
+impl FromStr for Aggregate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max" => Ok(Aggregate::Max),
+            "min" => Ok(Aggregate::Min),
+            "mean" => Ok(Aggregate::Mean),
+            "median" => Ok(Aggregate::Median),
+            _ => match s.strip_prefix("quantile:").map(|q| q.parse::<f64>()) {
+                Some(Ok(q)) if (0.0..=1.0).contains(&q) => Ok(Aggregate::Quantile(q)),
+                _ => Err(format!(
+                    "unknown aggregate {:?}, expected max, min, mean, median or quantile:<q> with q in [0,1]",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// The initial mean counts of the transcripts in the mean mode, combined
+/// over the samples by `rule`
+pub fn sample_means(gibbs_mat_vec: &[Array2<f64>], rule: Aggregate) -> Array1<f64> {
+    let means: Vec<Array1<f64>> = gibbs_mat_vec
+        .iter()
+        .map(|gb| gb.mean_axis(Axis(1)).unwrap())
+        .collect();
+    rule.of_arrays(&means)
+}
+
+/// The rule combining each family of per-sample statistics in the mean
+/// mode, by default those of earlier versions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregation {
+    /// the infRV of the transcripts and groups
+    pub infrv: Aggregate,
+    /// the mean counts of the groups, used by the edge count filter, and of
+    /// the transcripts in the expression filter
+    pub mean: Aggregate,
+    /// the spreads, used by the spread filter
+    pub spread: Aggregate,
+    /// the collapse scores of the edges
+    pub score: Aggregate,
+    /// the thresholds on the collapse scores
+    pub threshold: Aggregate,
+    /// the infRV percentiles below which transcripts are not grouped
+    pub percentile: Aggregate,
+    /// the initial mean counts of the transcripts, used by the edge count
+    /// filter
+    pub txp_mean: Aggregate,
+}
+
+impl Default for Aggregation {
+    fn default() -> Aggregation {
+        Aggregation {
+            infrv: Aggregate::Max,
+            mean: Aggregate::Max,
+            spread: Aggregate::Max,
+            score: Aggregate::Mean,
+            threshold: Aggregate::Mean,
+            percentile: Aggregate::Min,
+            txp_mean: Aggregate::Mean,
+        }
+    }
+}
+
+impl Aggregation {
+    /// Sets the rule of a family given as `<family>=<rule>`
+    pub fn set(&mut self, spec: &str) -> Result<(), String> {
+        let (family, rule) = match spec.find('=') {
+            Some(i) => (&spec[..i], spec[i + 1..].parse::<Aggregate>()?),
+            None => return Err(format!("aggregate {:?} is not <family>=<rule>", spec)),
+        };
+        match family {
+            "infrv" => self.infrv = rule,
+            "mean" => self.mean = rule,
+            "spread" => self.spread = rule,
+            "score" => self.score = rule,
+            "threshold" => self.threshold = rule,
+            "percentile" => self.percentile = rule,
+            "txp_mean" => self.txp_mean = rule,
+            _ => {
+                return Err(format!(
+                "unknown statistic family {:?}, expected infrv, mean, spread, score, threshold, percentile or txp_mean",
+                family
+            ))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The expression and spread filters deciding which transcripts and edges
+/// are considered for grouping
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Filters {
+    /// the mean count a transcript must exceed to be attached to an edge
+    pub min_mean: f64,
+    /// the spread one endpoint of an edge must exceed
+    pub min_spread: f64,
+    /// the weight of a transcript in an equivalence class with `n`
+    /// transcripts must reach `eq_weight_frac / n` to be retained
+    pub eq_weight_frac: f64,
+    /// the count of an edge must exceed `edge_count_frac` times the smaller
+    /// mean count of its endpoints
+    pub edge_count_frac: f64,
+    /// the sum over the replicates a transcript must exceed to enter the
+    /// infRV percentile and threshold
+    pub min_replicate_sum: f64,
+}
+
+impl Default for Filters {
+    fn default() -> Filters {
+        Filters {
+            min_mean: 1.0,
+            min_spread: 0.1,
+            eq_weight_frac: 0.1,
+            edge_count_frac: 1.0,
+            min_replicate_sum: 1.0,
+        }
+    }
+}
+
+/// How many transcripts and edges each filter dropped
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FilterCounts {
+    /// transcripts not exceeding `min_mean`
+    pub low_mean_txps: usize,
+    /// transcripts exceeding `min_mean` but not `min_spread`
+    pub low_spread_txps: usize,
+    /// transcripts of equivalence classes left out for their weight
+    pub low_weight_entries: usize,
+    /// edges dropped from the graph for their count
+    pub low_count_edges: usize,
+    /// merges skipped for the count of their edge
+    pub low_count_merges: usize,
+    /// transcripts left out of the infRV percentile and threshold, per
+    /// sample
+    pub low_sum_txps: Vec<usize>,
+}
+
+/// The number of transcripts whose sum over the replicates does not exceed
+/// `min_sum`
+pub fn count_low_sum(gibbs_mat: &Array2<f64>, min_sum: f64) -> usize {
+    gibbs_mat
+        .sum_axis(Axis(1))
+        .iter()
+        .filter(|&&item| item <= min_sum)
+        .count()
+}
+
+// The values at index `j` of the per-sample arrays
+fn column(arrays: &[Array1<f64>], j: usize) -> Vec<f64> {
+    arrays.iter().map(|a| a[j]).collect()
+}
+
+fn infrv(a: &Array2<f64>, axis: Axis, infrv_def: &InfRV) -> Array1<f64> {
+    a.map_axis(axis, |r| infrv_1d(r, infrv_def))
 }
 
 fn spread(a: &Array2<f64>, axis: Axis) -> Array1<f64> {
     a.map_axis(axis, spread1d)
 }
 
-pub fn get_infrv_percentile(gibbs_mat: &Array2<f64>, p: f64) -> f64 {
+pub fn get_infrv_percentile(
+    gibbs_mat: &Array2<f64>,
+    p: f64,
+    infrv_def: &InfRV,
+    filters: &Filters,
+) -> f64 {
     // assert!(0. < p);
     if p == 0.0 {
         return 0.0;
@@ -412,10 +1065,16 @@ pub fn get_infrv_percentile(gibbs_mat: &Array2<f64>, p: f64) -> f64 {
     let gibbs_mat_sum = gibbs_mat.sum_axis(Axis(1));
     let gibbs_nz: Vec<_> = gibbs_mat_sum
         .indexed_iter()
-        .filter_map(|(index, &item)| if item > 1.0 { Some(index) } else { None })
+        .filter_map(|(index, &item)| {
+            if item > filters.min_replicate_sum {
+                Some(index)
+            } else {
+                None
+            }
+        })
         .collect();
 
-    let infrv_array = infrv(gibbs_mat, Axis(1));
+    let infrv_array = infrv(gibbs_mat, Axis(1), infrv_def);
     let mut infrv_sort: Vec<f64> = gibbs_nz.iter().map(|i| infrv_array[*i]).collect();
     let n = infrv_sort.len();
     rgsl::sort::vectors::sort(&mut infrv_sort, 1, n);
@@ -432,37 +1091,51 @@ fn variance(a: &Array2<f64>, axis: Axis) -> Array1<f64> {
 }
 */
 
+#[allow(clippy::too_many_arguments)]
 pub fn get_threshold(
     gibbs_mat: &Array2<f64>,
     infrv_quant: f64,
     seed: u64,
     file_list: &FileList,
     red_perc: f64,
+    compression: OutputCompression,
+    infrv_def: &InfRV,
+    filters: &Filters,
 ) -> f64 {
-    println!("Calculating threshold");
+    info!("Calculating threshold");
     let gibbs_mat_sum = gibbs_mat.sum_axis(Axis(1));
     let gibbs_mat_mean = gibbs_mat.mean_axis(Axis(1)).unwrap();
     let gibbs_nz: Vec<_> = gibbs_mat_sum
         .indexed_iter()
-        .filter_map(|(index, &item)| if item > 1.0 { Some(index) } else { None })
+        .filter_map(|(index, &item)| {
+            if item > filters.min_replicate_sum {
+                Some(index)
+            } else {
+                None
+            }
+        })
         .collect();
 
-    let infrv_array = infrv(gibbs_mat, Axis(1));
+    let infrv_array = infrv(gibbs_mat, Axis(1), infrv_def);
 
     let dat = gibbs_nz
         .iter()
         .map(|i| format!("{}\t{}", gibbs_mat_mean[*i], infrv_array[*i]))
         .join("\n");
     let infrv_log = file_list.prefix.as_path().join("infrv.log");
-    std::fs::write(infrv_log, dat).expect("could not write to the infrv.log");
+    let mut infrv_file = create_writer(&infrv_log, compression);
+    infrv_file
+        .write_all(dat.as_bytes())
+        .and_then(|_| infrv_file.finish())
+        .expect("could not write to the infrv.log");
 
     let die_roll_log = file_list.prefix.as_path().join("die_roll.log");
-    let mut dfile = File::create(die_roll_log).expect("could not create die roll.log");
+    let mut dfile = create_writer(&die_roll_log, compression);
     // let infrv_array = variance(&gibbs_mat, Axis(1));
     let mut converged = false;
     let starting_num_samples = (gibbs_nz.len() as f64) * 1.;
     // let starting_num_samples = 1000 as f64;
-    println!("\n\nstarting samp : {}\n\n", starting_num_samples);
+    debug!("starting samp : {}", starting_num_samples);
 
     let mut starting_num_samples = starting_num_samples as usize;
 
@@ -480,6 +1153,7 @@ pub fn get_threshold(
         let mut sampled_infrv = vec![OrderedFloat(0.0); starting_num_samples];
         let mut dice_iter = 0_usize;
         let mut mean_sum = 0.0_f64;
+        let pb = progress_bar(starting_num_samples as u64, "dice roll");
 
         while dice_iter < starting_num_samples {
             let i1 = roll_die.next().unwrap();
@@ -492,7 +1166,7 @@ pub fn get_threshold(
             if !endpoints_overdispersed(&infrv_array, infrv_quant, t1, t2) {
                 continue;
             }
-            let s = get_collapse_score(gibbs_mat, &infrv_array, t1, t2);
+            let s = get_collapse_score(gibbs_mat, &infrv_array, t1, t2, infrv_def);
             // let s = get_collapse_score(&gibbs_mat, &infrv_array, t1, t2);
             // let s = get_variance_fold_change(&gibbs_mat, &infrv_array, t1, t2);
             // let s = get_infrv_fold_change(&gibbs_mat, &infrv_array, t1, t2);
@@ -507,9 +1181,7 @@ pub fn get_threshold(
             mean_sum += s;
             dice_iter += 1;
 
-            if dice_iter % 1000 == 1 {
-                print!("dice roll: {}\r", dice_iter);
-            }
+            pb.inc(1);
         }
         // calculate threshold
         // z=(x-mu)/sigma, => x = mu + z*sigma
@@ -517,6 +1189,7 @@ pub fn get_threshold(
         // x = mu + mad*1.48*quant_norm(q),
         // since sd = mad*1.48, (a more robust estimator of sd for normal distribution),
         // similarly, zscore can be obtained by using the inverse cumulative distribution on the quantile
+        pb.finish_and_clear();
         sampled_infrv.sort();
         let mean = mean_sum / (dice_iter as f64);
         let shifted_samples: Vec<f64> = sampled_infrv
@@ -542,6 +1215,7 @@ pub fn get_threshold(
         old_threshold = new_threshold;
         starting_num_samples *= 2;
     }
+    dfile.finish().expect("could not write to the die_roll.log");
     new_threshold
 }
 
@@ -550,13 +1224,14 @@ pub fn get_collapse_score(
     infrv_array: &Array1<f64>,
     x: usize,
     y: usize,
+    infrv_def: &InfRV,
 ) -> f64 {
     let infa = infrv_array[x];
     let infb = infrv_array[y];
     let sum = &gibbs_mat.row(x) + &gibbs_mat.row(y);
     //let submat = stack![Axis(0), gibbs_mat.slice(s![x..x+1,..]), gibbs_mat.slice(s![y..y+1,..])];
     //let covmat = submat.cov(1.).unwrap();
-    let infsum = infrv_1d(sum.view());
+    let infsum = infrv_1d(sum.view(), infrv_def);
     //let (maxrv, minrv) = if infa < infb { (infb, infa) } else { (infa, infb) };
     infsum - ((infa + infb) * 0.5)
     //infsum - (infa + infb) - covmat[[0,1]]
@@ -621,6 +1296,31 @@ fn order_group(source: usize, target: usize, group_order: &mut [String]) {
     };
     //group_order[target] = format!("{}{}", source, "p");
 }
+
+// The statistics of the transcript or group whose counts are in row `r`,
+// combined over the samples by the rules of `agg` in the mean mode.
+fn row_stats(
+    gibbs_mat: &Array2<f64>,
+    gibbs_mat_vec: &[Array2<f64>],
+    infrv_array: &Array1<f64>,
+    mean_inf: bool,
+    mean_agg: Aggregate,
+    r: usize,
+) -> NodeStats {
+    let row_mean = |gb: &Array2<f64>| {
+        let s = gb.index_axis(Axis(0), r);
+        s.sum() / (s.len() as f64)
+    };
+    let mean = match mean_inf {
+        true => mean_agg.of(&gibbs_mat_vec.iter().map(row_mean).collect::<Vec<f64>>()),
+        false => row_mean(gibbs_mat),
+    };
+    NodeStats {
+        infrv: infrv_array[r],
+        mean,
+    }
+}
+
 #[allow(
     dead_code,
     clippy::too_many_arguments,
@@ -635,29 +1335,35 @@ pub fn eq_experiment_to_graph(
     tolerance: f64,
     thr: f64,
     infrv_quant: f64,
-    min_spread: f64,
-    delta_file: &mut File,
+    delta_file: &mut dyn Write,
     unionfind_struct: &mut UnionFind<usize>,
     genevec: &[usize],
+    cross_gene_thr: Option<f64>,
     original_id_to_old_id_map: &HashMap<usize, Vec<usize>>,
     group_order: &mut [String],
-    collapse_order: &mut [TreeNode],
+    collapse_order: &mut TreeArena,
     mean_inf: bool,
-    gold_col_file: &mut File,
-    allele_col_file: &mut File,
+    gold_col_file: &mut dyn Write,
+    allele_col_file: &mut dyn Write,
+    infrv_def: &InfRV,
+    agg: &Aggregation,
+    filters: &Filters,
+    counts: &mut FilterCounts,
 ) -> pg::Graph<usize, EdgeInfo, petgraph::Undirected> {
     let start = Instant::now();
 
     let txpmode: bool = !genevec.is_empty();
     let asemode: bool = !original_id_to_old_id_map.is_empty();
 
-    println!("txp mode and ase mode {},{}", txpmode, asemode);
-    println!("Creating partition refinery");
+    debug!("txp mode and ase mode {},{}", txpmode, asemode);
+    info!("Creating partition refinery");
     let mut part_cache = HashSet::new();
     let part_start = Instant::now();
     let mut valid_transcripts = vec![false; exp.targets.len()];
     let mut part = Partition::simple(exp.targets.len());
-    for (i, x) in exp.classes.iter().enumerate() {
+    let pb = progress_bar(exp.neq as u64, "refining partitions");
+    for x in exp.classes.iter() {
+        pb.inc(1);
         let ns = x.0;
         let ws = x.1;
 
@@ -718,27 +1424,29 @@ pub fn eq_experiment_to_graph(
             //valid_transcripts[pair_vec[j].0 as usize] = true;
             //partition_sets.push(vec![pair_vec[j].0 as usize]);
         }
-        if i % 10000 == 1 {
-            print!("{}\r", i);
-            io::stdout().flush().unwrap();
-        }
     }
+    pb.finish_and_clear();
 
     // a blanket merge in asemode
-    let mut infrv_array = match mean_inf {
-        true => Array1::<f64>::zeros(gibbs_mat_vec[0].shape()[0]),
-        false => Array1::<f64>::zeros(gibbs_mat.shape()[0]),
-    };
+    let mut infrv_array;
     let mut infrv_array_vec: Vec<Array1<f64>> = Vec::new();
     if mean_inf {
-        for (_i, gb) in gibbs_mat_vec.iter_mut().enumerate() {
-            infrv_array_vec.push(infrv(gb, Axis(1)));
-            for _j in 0..infrv_array.shape()[0] {
-                infrv_array[_j] = infrv_array[_j].max(infrv_array_vec[_i][_j]);
-            }
+        for gb in gibbs_mat_vec.iter() {
+            infrv_array_vec.push(infrv(gb, Axis(1), infrv_def));
         }
+        infrv_array = agg.infrv.of_arrays(&infrv_array_vec);
     } else {
-        infrv_array = infrv(gibbs_mat, Axis(1));
+        infrv_array = infrv(gibbs_mat, Axis(1), infrv_def);
+    }
+    for i in 0..collapse_order.roots.len() {
+        collapse_order.root_mut(i).stats = Some(row_stats(
+            gibbs_mat,
+            gibbs_mat_vec,
+            &infrv_array,
+            mean_inf,
+            agg.mean,
+            i,
+        ));
     }
 
     if asemode {
@@ -763,10 +1471,7 @@ pub fn eq_experiment_to_graph(
                         }
                         order_group(source, *t, group_order);
                         //println!("{}",collapse_order[*t as usize].id);
-                        collapse_order[act_source] = TreeNode::create_group(
-                            collapse_order[act_source].clone(),
-                            collapse_order[act_target].clone(),
-                        );
+                        collapse_order.merge(act_source, act_target);
                         let target = *t;
 
                         let msg = format!(
@@ -778,31 +1483,37 @@ pub fn eq_experiment_to_graph(
                             .write_all(&msg.into_bytes())
                             .expect("could not write into allele collapse log");
                         if mean_inf {
-                            infrv_array[source] = 0.0;
                             for (_i, gb) in gibbs_mat_vec.iter_mut().enumerate() {
                                 // let infrv = infrv(&gb, Axis(1));
                                 // infrv_array += infrv
                                 let to_add = gb.index_axis(Axis(0), target).to_owned();
                                 let mut s = gb.slice_mut(s![source, ..]);
                                 s += &to_add;
-                                infrv_array_vec[_i][source] = infrv_1d(s.view());
-                                infrv_array[source] =
-                                    infrv_array[source].max(infrv_array_vec[_i][source]);
+                                infrv_array_vec[_i][source] = infrv_1d(s.view(), infrv_def);
                             }
+                            infrv_array[source] = agg.infrv.of(&column(&infrv_array_vec, source));
                             // infrv_array[source] = infrv_array[source]/gibbs_mat_vec.len() as f64;
                         } else {
                             let to_add = gibbs_mat.index_axis(Axis(0), target).to_owned();
                             let mut s = gibbs_mat.slice_mut(s![source, ..]);
                             s += &to_add;
-                            infrv_array[source] = infrv_1d(s.view());
+                            infrv_array[source] = infrv_1d(s.view(), infrv_def);
                         }
+                        collapse_order.root_mut(act_source).stats = Some(row_stats(
+                            gibbs_mat,
+                            gibbs_mat_vec,
+                            &infrv_array,
+                            mean_inf,
+                            agg.mean,
+                            source,
+                        ));
                     }
 
                     allelic_collapses += 1;
                 }
             }
         }
-        println!("Number of alleleic collapses {}", allelic_collapses);
+        info!("Number of alleleic collapses {}", allelic_collapses);
     }
 
     let part_vec = part.iter().collect::<Vec<_>>();
@@ -814,13 +1525,16 @@ pub fn eq_experiment_to_graph(
             //println!("{:?}", p);
             if valid_transcripts[p[0]] {
                 if p.len() > 10 {
-                    println!("{},{}", p.len(), p[0]);
+                    debug!("large partition of size {} starting at {}", p.len(), p[0]);
                 }
                 let mut tlist = p.to_vec();
 
                 tlist.sort_unstable();
                 let source = tlist[0];
                 let mut act_source: usize = source;
+                // identical transcripts form a single multifurcating node,
+                // so each node of the chain built so far belongs to the next
+                let mut chained = false;
                 for t in tlist.iter().skip(1) {
                     let target = *t;
                     let mut act_target = unionfind_struct.find(*t); // parent of current target node
@@ -833,10 +1547,9 @@ pub fn eq_experiment_to_graph(
                         if act_source == act_target {
                             act_target = par_source;
                         }
-                        collapse_order[act_source] = TreeNode::create_group(
-                            collapse_order[act_source].clone(),
-                            collapse_order[act_target].clone(),
-                        );
+                        collapse_order.root_mut(par_source).chained = chained;
+                        chained = true;
+                        collapse_order.merge(act_source, act_target);
                         let msg = format!(
                             "{}\t{}\t{}\t{}\n",
                             source, target, infrv_array[source], infrv_array[target]
@@ -850,28 +1563,38 @@ pub fn eq_experiment_to_graph(
                                 let to_add = gb.index_axis(Axis(0), target).to_owned();
                                 let mut s = gb.slice_mut(s![source, ..]);
                                 s += &to_add;
-                                infrv_array_vec[_i][source] = infrv_1d(s.view());
-                                infrv_array[source] =
-                                    infrv_array[source].max(infrv_array_vec[_i][source]);
+                                infrv_array_vec[_i][source] = infrv_1d(s.view(), infrv_def);
                             }
+                            infrv_array[source] = agg.infrv.of(&column(&infrv_array_vec, source));
                             // infrv_array[source] = infrv_array[source]/gibbs_mat_vec.len() as f64;
                         } else {
                             let to_add = gibbs_mat.index_axis(Axis(0), target).to_owned();
                             let mut s = gibbs_mat.slice_mut(s![source, ..]);
                             s += &to_add;
-                            infrv_array[source] = infrv_1d(s.view());
+                            infrv_array[source] = infrv_1d(s.view(), infrv_def);
                         }
+                        collapse_order.root_mut(act_source).stats = Some(row_stats(
+                            gibbs_mat,
+                            gibbs_mat_vec,
+                            &infrv_array,
+                            mean_inf,
+                            agg.mean,
+                            source,
+                        ));
                     }
                     golden_collapses += 1;
                 }
             } else if p.len() > 10 {
-                println!("{}", p.len());
+                debug!(
+                    "large partition of size {} with no valid transcripts",
+                    p.len()
+                );
             }
         }
     }
-    println!("Number of golden collapses {}", golden_collapses);
-    println!("Number of true golden collapses {}", t_golden_collapses);
-    println!("The refinery code ran for {:?}", part_start.elapsed());
+    info!("Number of golden collapses {}", golden_collapses);
+    info!("Number of true golden collapses {}", t_golden_collapses);
+    info!("The refinery code ran for {:?}", part_start.elapsed());
 
     let mut og = pg::Graph::<usize, EdgeInfo, petgraph::Undirected>::new_undirected();
     for (i, _n) in exp.targets.iter().enumerate() {
@@ -882,8 +1605,8 @@ pub fn eq_experiment_to_graph(
     }
 
     // compute the mean of the elemenrs
-    let mut gibbs_mat_mean;
-    let mut gibbs_mat_spread;
+    let gibbs_mat_mean;
+    let gibbs_mat_spread;
     // let mut gibbs_mat_mean = Array2::<f64>::zeros((1 as usize));
     // let mut gibbs_mat_mean_vec = Vec::new();
     // let mut gibbs_mat_spread = Array2::<f64>::zeros((1 as usize));
@@ -893,16 +1616,14 @@ pub fn eq_experiment_to_graph(
         gibbs_mat_mean = gibbs_mat.mean_axis(Axis(1)).unwrap();
         gibbs_mat_spread = spread(gibbs_mat, Axis(1));
     } else {
-        gibbs_mat_mean = Array1::<f64>::zeros(gibbs_mat_vec[0].shape()[0]);
-        gibbs_mat_spread = Array1::<f64>::zeros(gibbs_mat_vec[0].shape()[0]);
-        for gb in gibbs_mat_vec.iter() {
-            let mean = gb.mean_axis(Axis(1)).unwrap();
-            let spread = spread(gb, Axis(1));
-            for j in 0..infrv_array.shape()[0] {
-                gibbs_mat_mean[j] = gibbs_mat_mean[j].max(mean[j]);
-                gibbs_mat_spread[j] = gibbs_mat_spread[j].max(spread[j]);
-            }
-        }
+        let means: Vec<Array1<f64>> = gibbs_mat_vec
+            .iter()
+            .map(|gb| gb.mean_axis(Axis(1)).unwrap())
+            .collect();
+        let spreads: Vec<Array1<f64>> =
+            gibbs_mat_vec.iter().map(|gb| spread(gb, Axis(1))).collect();
+        gibbs_mat_mean = agg.mean.of_arrays(&means);
+        gibbs_mat_spread = agg.spread.of_arrays(&spreads);
         // gibbs_mat_mean = gibbs_mat_mean/gibbs_mat_vec.len() as f64;
         // gibbs_mat_spread = gibbs_mat_spread/gibbs_mat_vec.len() as f64;
     }
@@ -910,7 +1631,7 @@ pub fn eq_experiment_to_graph(
     let filtered_indices_spread: Vec<u32> = gibbs_mat_spread
         .indexed_iter()
         .filter_map(|(index, &item)| {
-            if item > min_spread && gibbs_mat_mean[index] > 1.0 {
+            if item > filters.min_spread && gibbs_mat_mean[index] > filters.min_mean {
                 Some(index as u32)
             } else {
                 None
@@ -921,7 +1642,7 @@ pub fn eq_experiment_to_graph(
     let filtered_indices_exp: Vec<u32> = gibbs_mat_spread
         .indexed_iter()
         .filter_map(|(index, &_item)| {
-            if gibbs_mat_mean[index] > 1.0 {
+            if gibbs_mat_mean[index] > filters.min_mean {
                 Some(index as u32)
             } else {
                 None
@@ -938,6 +1659,12 @@ pub fn eq_experiment_to_graph(
     for i in filtered_indices_exp {
         filtered_indices_mean_vec[i as usize] = true;
     }
+    counts.low_mean_txps = filtered_indices_mean_vec.iter().filter(|f| !**f).count();
+    counts.low_spread_txps = filtered_indices_mean_vec
+        .iter()
+        .zip(filtered_indices_vec.iter())
+        .filter(|(m, s)| **m && !**s)
+        .count();
 
     //let shape = gibbs_mat.shape() ;
     // let infrv_array = match(mean_inf) {
@@ -965,7 +1692,7 @@ pub fn eq_experiment_to_graph(
         let eq_count = x.2;
         assert!(eq_count == eq_class_count[i]);
 
-        let thresh = 0.1 * (1.0 / ns.len() as f32);
+        let thresh = (filters.eq_weight_frac as f32) * (1.0 / ns.len() as f32);
 
         let retained: std::vec::Vec<usize> = (0..ns.len())
             .filter_map(|j| {
@@ -976,6 +1703,7 @@ pub fn eq_experiment_to_graph(
                 }
             })
             .collect();
+        counts.low_weight_entries += ns.len() - retained.len();
 
         for a in 0..retained.len() {
             let mut na = retained[a];
@@ -988,11 +1716,17 @@ pub fn eq_experiment_to_graph(
             for nb in retained.iter().skip(a + 1) {
                 let mut nbd = *nb;
 
+                // edges across genes are skipped, unless they pass the
+                // stricter threshold of the soft restriction
+                let mut edge_thr = thr;
                 if txpmode {
                     let gene_a = genevec[na];
                     let gene_b = genevec[nbd];
                     if gene_a != gene_b {
-                        continue;
+                        match cross_gene_thr {
+                            Some(t) => edge_thr = edge_thr.min(t),
+                            None => continue,
+                        }
                     }
                 }
 
@@ -1025,15 +1759,24 @@ pub fn eq_experiment_to_graph(
                             // only add the edge if the correlation is sufficientl
                             // small
                             let delta = match mean_inf {
-                                false => get_collapse_score(gibbs_mat, &infrv_array, na, *nb),
+                                false => {
+                                    get_collapse_score(gibbs_mat, &infrv_array, na, *nb, infrv_def)
+                                }
                                 true => {
-                                    let mut col_score = 0.0;
-                                    for (_i, gb) in gibbs_mat_vec.iter().enumerate() {
-                                        col_score +=
-                                            get_collapse_score(gb, &infrv_array_vec[_i], na, *nb);
-                                    }
-                                    col_score /= gibbs_mat_vec.len() as f64;
-                                    col_score
+                                    let scores: Vec<f64> = gibbs_mat_vec
+                                        .iter()
+                                        .enumerate()
+                                        .map(|(_i, gb)| {
+                                            get_collapse_score(
+                                                gb,
+                                                &infrv_array_vec[_i],
+                                                na,
+                                                *nb,
+                                                infrv_def,
+                                            )
+                                        })
+                                        .collect();
+                                    agg.score.of(&scores)
                                 }
                             };
 
@@ -1047,7 +1790,7 @@ pub fn eq_experiment_to_graph(
                             //    if (u == 116212) && (v == 116212){
                             //        println!("=================={}================", delta);
                             //    }
-                            if delta < thr {
+                            if delta < edge_thr {
                                 og.add_edge(
                                     va,
                                     vb,
@@ -1071,7 +1814,7 @@ pub fn eq_experiment_to_graph(
         |ei, e| {
             let (a, b) = og.edge_endpoints(ei).unwrap();
             let min_mean = gibbs_mat_mean[a.index()].min(gibbs_mat_mean[b.index()]);
-            if (e.count as f64) > min_mean {
+            if (e.count as f64) > filters.edge_count_frac * min_mean {
                 Some(EdgeInfo {
                     infrv_gain: e.infrv_gain,
                     count: e.count,
@@ -1083,15 +1826,45 @@ pub fn eq_experiment_to_graph(
             }
         },
     );
-    println!("Prev node count: {}", og.node_count());
-    println!("Prev edge count: {}", og.edge_count());
-    println!("New node count: {}", og2.node_count());
-    println!("New edge count: {}", og2.edge_count());
+    counts.low_count_edges = og.edge_count() - og2.edge_count();
+    info!("Prev node count: {}", og.node_count());
+    info!("Prev edge count: {}", og.edge_count());
+    info!("New node count: {}", og2.node_count());
+    info!("New edge count: {}", og2.edge_count());
     //println!("# cc : {}", pg::algo::connected_components(&og));
-    println!("Elapsed time for computing graph {:?}", start.elapsed());
+    info!("Elapsed time for computing graph {:?}", start.elapsed());
     og2
 }
 
+/// Whether progress bars are drawn, set from the console verbosity
+static SHOW_PROGRESS: AtomicBool = AtomicBool::new(true);
+
+/// Shows or hides the progress bars created by `progress_bar`. It follows
+/// the console verbosity rather than the logger level, which a log file
+/// can raise above the console one.
+pub fn show_progress(show: bool) {
+    SHOW_PROGRESS.store(show, AtomicOrdering::Relaxed);
+}
+
+/// Creates a progress bar of length `len`, labelled with `msg`. The bar is
+/// hidden when info level messages are not shown on the console (e.g. with
+/// `--quiet`).
+pub fn progress_bar(len: u64, msg: &str) -> ProgressBar {
+    let pb = if SHOW_PROGRESS.load(AtomicOrdering::Relaxed) {
+        ProgressBar::new(len)
+    } else {
+        ProgressBar::hidden()
+    };
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{msg} [{elapsed_precise}] {bar:40.cyan/blue} {pos:>9}/{len:9}")
+            .progress_chars("##-"),
+    );
+    pb.set_draw_delta((len / 100).max(1));
+    pb.set_message(msg);
+    pb
+}
+
 // find intersection
 #[allow(
     dead_code,
@@ -1186,11 +1959,38 @@ pub fn verify_graph(
             vc += eq_class_count[*eq] as usize;
         }
         if vc != c as usize {
-            println!("verified edge count = {}, but stored count = {}", vc, c);
+            warn!("verified edge count = {}, but stored count = {}", vc, c);
         }
     }
 }
 
+// The threshold the collapse of the groups `u` and `v` must pass, the
+// stricter `cross_gene_thr` under the soft gene restriction unless both
+// groups are of the same single gene in `group_gene`
+fn gene_merge_thr(
+    group_gene: &[Option<usize>],
+    thr: f64,
+    cross_gene_thr: Option<f64>,
+    u: usize,
+    v: usize,
+) -> f64 {
+    match cross_gene_thr {
+        Some(t) if group_gene[u].is_none() || group_gene[u] != group_gene[v] => thr.min(t),
+        _ => thr,
+    }
+}
+
+// Records the collapse of the group `v` into `u` under the soft gene
+// restriction, returning whether it crosses genes, in which case `u` no
+// longer has a single gene
+fn merge_genes(group_gene: &mut [Option<usize>], u: usize, v: usize) -> bool {
+    if group_gene.is_empty() || (group_gene[u].is_some() && group_gene[u] == group_gene[v]) {
+        return false;
+    }
+    group_gene[u] = None;
+    true
+}
+
 //util::work_on_component(&eq_class, &gibbs_array, &og, &comp);
 #[allow(clippy::too_many_arguments, clippy::cognitive_complexity)]
 pub fn work_on_component(
@@ -1204,26 +2004,39 @@ pub fn work_on_component(
     num_collapses: &mut usize,
     thr: f64,
     infrv_quant: f64,
-    cfile: &mut File,
-    collapse_order: &mut [TreeNode],
+    cfile: &mut dyn Write,
+    collapse_order: &mut TreeArena,
     mean_inf: bool,
-) {
-    // make a set of edges to be visited
-    let mut infrv_array = match mean_inf {
-        true => Array1::<f64>::zeros(gibbs_mat_vec[0].shape()[0]),
-        false => Array1::<f64>::zeros(gibbs_mat.shape()[0]),
+    genevec: &[usize],
+    cross_gene_thr: Option<f64>,
+    cross_gene_file: &mut dyn Write,
+    infrv_def: &InfRV,
+    agg: &Aggregation,
+    filters: &Filters,
+    counts: &mut FilterCounts,
+) -> usize {
+    // under the soft gene restriction, the gene of each group if it has a
+    // single one, the others needing the cross-gene threshold to merge
+    let mut group_gene: Vec<Option<usize>> = match cross_gene_thr {
+        Some(_) => genevec.iter().map(|g| Some(*g)).collect(),
+        None => Vec::new(),
     };
+    let merge_thr = |group_gene: &[Option<usize>], u: usize, v: usize| -> f64 {
+        gene_merge_thr(group_gene, thr, cross_gene_thr, u, v)
+    };
+    let mut num_cross_gene = 0_usize;
+
+    // make a set of edges to be visited
+    let mut infrv_array;
     let mut infrv_array_vec: Vec<Array1<f64>> = Vec::new();
     if mean_inf {
-        for (_i, gb) in gibbs_mat_vec.iter_mut().enumerate() {
-            infrv_array_vec.push(infrv(gb, Axis(1)));
-            for _j in 0..infrv_array.shape()[0] {
-                infrv_array[_j] = infrv_array[_j].max(infrv_array_vec[_i][_j]);
-            }
+        for gb in gibbs_mat_vec.iter() {
+            infrv_array_vec.push(infrv(gb, Axis(1), infrv_def));
         }
+        infrv_array = agg.infrv.of_arrays(&infrv_array_vec);
         // infrv_array /= gibbs_mat_vec.len() as f64;
     } else {
-        infrv_array = infrv(gibbs_mat, Axis(1));
+        infrv_array = infrv(gibbs_mat, Axis(1), infrv_def);
     }
     //let mut infrv_array = variance(&gibbs_mat, Axis(1));
     //let shape = gibbs_mat.shape().to_vec() ;
@@ -1261,7 +2074,7 @@ pub fn work_on_component(
     {
         // take the next available edge that can be collapsed
         // correlation less than threashold
-        if infrv_gain < OrderedFloat(thr) {
+        if infrv_gain < OrderedFloat(merge_thr(&group_gene, source, target)) {
             // println!("mincorr {}\r",corr);
             // if the edge count satisfies the criteria
             let source_node = pg::graph::NodeIndex::new(source);
@@ -1296,15 +2109,21 @@ pub fn work_on_component(
             let min_mean = gibbs_mat_mean[source].min(gibbs_mat_mean[target]);
 
             // the count along this edge must be large enough to "matter"
-            if f64::from(u_to_v_info.count) >= min_mean {
+            if f64::from(u_to_v_info.count) >= filters.edge_count_frac * min_mean {
                 let msg = format!(
                     "{}\t{}\t{}\t{}\t{}\n",
                     source, target, infrv_array[source], infrv_array[target], infrv_gain
                 );
                 //println!("{}",msg);
                 cfile
-                    .write_all(&msg.into_bytes())
+                    .write_all(msg.as_bytes())
                     .expect("could not write into collapse log");
+                if merge_genes(&mut group_gene, source, target) {
+                    cross_gene_file
+                        .write_all(msg.as_bytes())
+                        .expect("could not write into cross-gene collapse log");
+                    num_cross_gene += 1;
+                }
 
                 // one collapse guaranteed
                 *num_collapses += 1;
@@ -1333,33 +2152,36 @@ pub fn work_on_component(
                     }
                     //order_group(source as usize, *t as usize, group_order);
                     //println!("{}",collapse_order[*t as usize].id);
-                    collapse_order[act_source] = TreeNode::create_group(
-                        collapse_order[act_source].clone(),
-                        collapse_order[act_target].clone(),
-                    );
+                    collapse_order.merge(act_source, act_target);
                 }
 
                 if mean_inf {
-                    infrv_array[source] = 0.0;
-                    gibbs_mat_mean[source] = 0.0;
+                    let mut means = Vec::with_capacity(gibbs_mat_vec.len());
                     for (_i, gb) in gibbs_mat_vec.iter_mut().enumerate() {
                         let to_add = gb.index_axis(Axis(0), target).to_owned();
                         let mut s = gb.slice_mut(s![source, ..]);
                         s += &to_add;
-                        infrv_array_vec[_i][source] = infrv_1d(s.view());
-                        infrv_array[source] = infrv_array[source].max(infrv_array_vec[_i][source]);
-                        gibbs_mat_mean[source] =
-                            gibbs_mat_mean[source].max(s.sum() / (s.len() as f64));
+                        infrv_array_vec[_i][source] = infrv_1d(s.view(), infrv_def);
+                        means.push(s.sum() / (s.len() as f64));
                     }
+                    infrv_array[source] = agg.infrv.of(&column(&infrv_array_vec, source));
+                    gibbs_mat_mean[source] = agg.mean.of(&means);
                     // infrv_array[source] = infrv_array[source]/gibbs_mat_vec.len() as f64;
                     // gibbs_mat_mean[source] /= gibbs_mat_vec.len() as f64;
                 } else {
                     let to_add = gibbs_mat.index_axis(Axis(0), target).to_owned();
                     let mut s = gibbs_mat.slice_mut(s![source, ..]);
                     s += &to_add;
-                    infrv_array[source] = infrv_1d(s.view());
+                    infrv_array[source] = infrv_1d(s.view(), infrv_def);
                     gibbs_mat_mean[source] = s.sum() / (s.len() as f64);
                 }
+                if merge {
+                    collapse_order.root_mut(unionfind_struct.find(source)).stats =
+                        Some(NodeStats {
+                            infrv: infrv_array[source],
+                            mean: gibbs_mat_mean[source],
+                        });
+                }
 
                 // update correlation for (u*v) to new and existing neighbors
                 let mut source_adj: Vec<usize> =
@@ -1390,15 +2212,22 @@ pub fn work_on_component(
                     let curr_state = u_to_x_info_inner.state;
 
                     let delta = match mean_inf {
-                        false => get_collapse_score(gibbs_mat, &infrv_array, source, *x),
+                        false => get_collapse_score(gibbs_mat, &infrv_array, source, *x, infrv_def),
                         true => {
-                            let mut col_score = 0.0;
-                            for (_i, gb) in gibbs_mat_vec.iter().enumerate() {
-                                col_score +=
-                                    get_collapse_score(gb, &infrv_array_vec[_i], source, *x);
-                            }
-                            col_score /= gibbs_mat_vec.len() as f64;
-                            col_score
+                            let scores: Vec<f64> = gibbs_mat_vec
+                                .iter()
+                                .enumerate()
+                                .map(|(_i, gb)| {
+                                    get_collapse_score(
+                                        gb,
+                                        &infrv_array_vec[_i],
+                                        source,
+                                        *x,
+                                        infrv_def,
+                                    )
+                                })
+                                .collect();
+                            agg.score.of(&scores)
                         }
                     };
                     // let delta = get_variance_fold_change(&gibbs_mat, &infrv_array, source, *x);
@@ -1408,7 +2237,8 @@ pub fn work_on_component(
                     u_to_x_info_inner.state += 1;
 
                     // update heap
-                    if delta < thr && endpoints_overdispersed(&infrv_array, infrv_quant, source, *x)
+                    if delta < merge_thr(&group_gene, source, *x)
+                        && endpoints_overdispersed(&infrv_array, infrv_quant, source, *x)
                     {
                         let (a, b) = if source > *x {
                             (*x, source)
@@ -1448,15 +2278,22 @@ pub fn work_on_component(
                     let v_to_x_eqlist = &v_to_x_info_inner.eqlist.to_vec();
 
                     let delta = match mean_inf {
-                        false => get_collapse_score(gibbs_mat, &infrv_array, source, *x),
+                        false => get_collapse_score(gibbs_mat, &infrv_array, source, *x, infrv_def),
                         true => {
-                            let mut col_score = 0.0;
-                            for (_i, gb) in gibbs_mat_vec.iter().enumerate() {
-                                col_score +=
-                                    get_collapse_score(gb, &infrv_array_vec[_i], source, *x);
-                            }
-                            col_score /= gibbs_mat_vec.len() as f64;
-                            col_score
+                            let scores: Vec<f64> = gibbs_mat_vec
+                                .iter()
+                                .enumerate()
+                                .map(|(_i, gb)| {
+                                    get_collapse_score(
+                                        gb,
+                                        &infrv_array_vec[_i],
+                                        source,
+                                        *x,
+                                        infrv_def,
+                                    )
+                                })
+                                .collect();
+                            agg.score.of(&scores)
                         }
                     };
                     // let delta = get_variance_fold_change(&gibbs_mat, &infrv_array, source, *x);
@@ -1476,7 +2313,8 @@ pub fn work_on_component(
                     );
 
                     // update heap
-                    if delta < thr && endpoints_overdispersed(&infrv_array, infrv_quant, source, *x)
+                    if delta < merge_thr(&group_gene, source, *x)
+                        && endpoints_overdispersed(&infrv_array, infrv_quant, source, *x)
                     {
                         let (a, b) = if source > *x {
                             (*x, source)
@@ -1532,8 +2370,8 @@ pub fn work_on_component(
 
                     // TODO(@hiraksarkar) : once confident, we can remove this check
                     if sum > tot_current_count {
-                        println!("sum = {}, tot_current_count = {}", sum, tot_current_count);
-                        println!(
+                        error!("sum = {}, tot_current_count = {}", sum, tot_current_count);
+                        error!(
                             "u-x : {:?}, v-x : {:?}, intersection : {:?}",
                             u_to_x_info.eqlist, v_to_x_eq, intersecting_eqlist
                         );
@@ -1553,15 +2391,22 @@ pub fn work_on_component(
                     let final_count = tot_current_count - sum;
 
                     let delta = match mean_inf {
-                        false => get_collapse_score(gibbs_mat, &infrv_array, source, *x),
+                        false => get_collapse_score(gibbs_mat, &infrv_array, source, *x, infrv_def),
                         true => {
-                            let mut col_score = 0.0;
-                            for (_i, gb) in gibbs_mat_vec.iter().enumerate() {
-                                col_score +=
-                                    get_collapse_score(gb, &infrv_array_vec[_i], source, *x);
-                            }
-                            col_score /= gibbs_mat_vec.len() as f64;
-                            col_score
+                            let scores: Vec<f64> = gibbs_mat_vec
+                                .iter()
+                                .enumerate()
+                                .map(|(_i, gb)| {
+                                    get_collapse_score(
+                                        gb,
+                                        &infrv_array_vec[_i],
+                                        source,
+                                        *x,
+                                        infrv_def,
+                                    )
+                                })
+                                .collect();
+                            agg.score.of(&scores)
                         }
                     };
 
@@ -1573,7 +2418,8 @@ pub fn work_on_component(
                     u_to_x_info.state += 1;
 
                     // update heap
-                    if delta < thr && endpoints_overdispersed(&infrv_array, infrv_quant, source, *x)
+                    if delta < merge_thr(&group_gene, source, *x)
+                        && endpoints_overdispersed(&infrv_array, infrv_quant, source, *x)
                     {
                         let (a, b) = if source > *x {
                             (*x, source)
@@ -1597,10 +2443,13 @@ pub fn work_on_component(
                     }
                     og.remove_edge(v_to_x_inner);
                 }
+            } else {
+                counts.low_count_merges += 1;
             }
         }
     }
     //println!("curr state {}", curr_state);
+    num_cross_gene
 }
 
 pub fn parse_eq(filename: &std::path::Path) -> Result<EqClassExperiment, io::Error> {
@@ -1635,7 +2484,7 @@ pub fn parse_eq(filename: &std::path::Path) -> Result<EqClassExperiment, io::Err
 
     let mut tnames = Vec::<String>::with_capacity(num_target);
 
-    println!(
+    info!(
         "Number of transcript {}, number of equivalence classes {}",
         num_target, num_eq
     );
@@ -1679,3 +2528,391 @@ pub fn parse_eq(filename: &std::path::Path) -> Result<EqClassExperiment, io::Err
     Ok(exp)
     // make graph from these
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("treeterminus-util-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn quant(reads: &[f32]) -> Vec<TxpRecord> {
+        reads
+            .iter()
+            .enumerate()
+            .map(|(i, r)| TxpRecord {
+                Name: format!("t{}", i),
+                Length: 1000,
+                EffectiveLength: 1000.0,
+                TPM: 0.0,
+                NumReads: *r,
+            })
+            .collect()
+    }
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn norm_factors_have_unit_geometric_mean() {
+        let quants = vec![quant(&[10.0, 30.0]), quant(&[40.0, 120.0])];
+        let none = norm_factors(&quants, Normalization::None).unwrap();
+        assert_close(&none, &[1.0, 1.0]);
+        let total = norm_factors(&quants, Normalization::Total).unwrap();
+        assert_close(&total, &[0.5, 2.0]);
+        let mor = norm_factors(&quants, Normalization::MedianOfRatios).unwrap();
+        assert_close(&mor, &[0.5, 2.0]);
+    }
+
+    #[test]
+    fn median_of_ratios_skips_transcripts_without_reads() {
+        // transcript 1 has no reads in the first sample and would otherwise
+        // make its ratios infinite
+        let quants = vec![quant(&[10.0, 0.0, 20.0]), quant(&[40.0, 500.0, 80.0])];
+        let mor = norm_factors(&quants, Normalization::MedianOfRatios).unwrap();
+        assert_close(&mor, &[0.5, 2.0]);
+        assert!(mor.iter().all(|f| f.is_finite()));
+    }
+
+    #[test]
+    fn norm_factors_reject_samples_without_reads() {
+        let quants = vec![quant(&[10.0, 30.0]), quant(&[0.0, 0.0])];
+        assert!(norm_factors(&quants, Normalization::Total).is_err());
+        assert!(norm_factors(&quants, Normalization::MedianOfRatios).is_err());
+        assert!(norm_factors(&quants, Normalization::None).is_ok());
+    }
+
+    #[test]
+    fn aggregates_combine_samples() {
+        let xs = [3.0, 1.0, 4.0, 2.0];
+        assert_eq!(Aggregate::Max.of(&xs), 4.0);
+        assert_eq!(Aggregate::Min.of(&xs), 1.0);
+        assert_eq!(Aggregate::Mean.of(&xs), 2.5);
+        assert_eq!(Aggregate::Median.of(&xs), 2.5);
+        assert_eq!(Aggregate::Quantile(0.0).of(&xs), 1.0);
+        assert_eq!(Aggregate::Quantile(1.0).of(&xs), 4.0);
+        let arrays = vec![Array1::from(vec![1.0, 5.0]), Array1::from(vec![3.0, 2.0])];
+        assert_eq!(
+            Aggregate::Max.of_arrays(&arrays),
+            Array1::from(vec![3.0, 5.0])
+        );
+        assert_eq!(
+            Aggregate::Mean.of_arrays(&arrays),
+            Array1::from(vec![2.0, 3.5])
+        );
+    }
+
+    #[test]
+    fn aggregates_parse_their_names() {
+        for a in [
+            Aggregate::Max,
+            Aggregate::Min,
+            Aggregate::Mean,
+            Aggregate::Median,
+            Aggregate::Quantile(0.9),
+        ] {
+            assert_eq!(a.name().parse::<Aggregate>(), Ok(a));
+        }
+        assert!("quantile:1.5".parse::<Aggregate>().is_err());
+        assert!("sum".parse::<Aggregate>().is_err());
+
+        let mut agg = Aggregation::default();
+        agg.set("infrv=median").unwrap();
+        assert_eq!(agg.infrv, Aggregate::Median);
+        assert!(agg.set("infrv").is_err());
+        assert!(agg.set("reads=max").is_err());
+    }
+
+    #[test]
+    fn default_mean_mode_averages_the_initial_means() {
+        // the running sum of earlier versions
+        let gibbs = vec![
+            Array2::from_shape_vec((2, 2), vec![1.0, 3.0, 10.0, 10.0]).unwrap(),
+            Array2::from_shape_vec((2, 2), vec![6.0, 6.0, 0.0, 4.0]).unwrap(),
+        ];
+        let mut baseline = Array1::<f64>::zeros(2);
+        for gb in gibbs.iter() {
+            baseline += &gb.mean_axis(Axis(1)).unwrap();
+        }
+        baseline /= gibbs.len() as f64;
+        assert_eq!(sample_means(&gibbs, Aggregate::Mean), baseline);
+        assert_eq!(baseline, Array1::from(vec![4.0, 6.0]));
+        assert_eq!(
+            sample_means(&gibbs, Aggregate::Max),
+            Array1::from(vec![6.0, 10.0])
+        );
+
+        let agg = Aggregation::default();
+        assert_eq!(
+            (agg.infrv, agg.mean, agg.spread),
+            (Aggregate::Max, Aggregate::Max, Aggregate::Max)
+        );
+        assert_eq!(
+            (agg.score, agg.threshold, agg.percentile),
+            (Aggregate::Mean, Aggregate::Mean, Aggregate::Min)
+        );
+        assert_eq!(agg.txp_mean, Aggregate::Mean);
+    }
+
+    #[test]
+    fn cross_gene_merges_need_the_stricter_threshold() {
+        let mut group_gene = vec![Some(0), Some(0), Some(1)];
+        assert_eq!(gene_merge_thr(&group_gene, -0.1, Some(-0.5), 0, 1), -0.1);
+        assert_eq!(gene_merge_thr(&group_gene, -0.1, Some(-0.5), 1, 2), -0.5);
+        // without the soft restriction there are no cross-gene edges
+        assert_eq!(gene_merge_thr(&[], -0.1, None, 1, 2), -0.1);
+
+        assert!(!merge_genes(&mut group_gene, 0, 1));
+        assert_eq!(group_gene, vec![Some(0), Some(0), Some(1)]);
+        assert!(merge_genes(&mut group_gene, 1, 2));
+        assert_eq!(group_gene, vec![Some(0), None, Some(1)]);
+        // a group spanning genes needs the stricter threshold even with its
+        // former gene, and merging into it crosses genes again
+        assert_eq!(gene_merge_thr(&group_gene, -0.1, Some(-0.5), 1, 0), -0.5);
+        assert!(merge_genes(&mut group_gene, 1, 0));
+        assert!(!merge_genes(&mut [], 1, 0));
+    }
+
+    // Collapses the single edge between two transcripts of different genes,
+    // scored `gain`, returning the number of collapses and of cross-gene ones
+    fn collapse_cross_gene_edge(gain: f64, thr: f64, cross_gene_thr: f64) -> (usize, usize) {
+        let mut gibbs = array![[10.0, 20.0, 30.0], [30.0, 20.0, 10.0]];
+        let mut gibbs_mean = gibbs.mean_axis(Axis(1)).unwrap();
+        let mut og = pg::Graph::<usize, EdgeInfo, petgraph::Undirected>::new_undirected();
+        let a = og.add_node(0);
+        let b = og.add_node(1);
+        og.add_edge(
+            a,
+            b,
+            EdgeInfo {
+                infrv_gain: gain,
+                count: 100,
+                state: -1,
+                eqlist: vec![0],
+            },
+        );
+        let mut uf = UnionFind::new(2);
+        let mut collapse_order = TreeArena::with_leaves(2);
+        let mut num_collapses = 0;
+        let mut cross_gene_log = Vec::new();
+        let num_cross_gene = work_on_component(
+            &[100],
+            &mut gibbs,
+            &mut [],
+            &mut gibbs_mean,
+            &mut uf,
+            &mut og,
+            &[a, b],
+            &mut num_collapses,
+            thr,
+            0.0,
+            &mut Vec::new(),
+            &mut collapse_order,
+            false,
+            &[0, 1],
+            Some(cross_gene_thr),
+            &mut cross_gene_log,
+            &InfRV::default(),
+            &Aggregation::default(),
+            &Filters::default(),
+            &mut FilterCounts::default(),
+        );
+        assert_eq!(
+            String::from_utf8(cross_gene_log).unwrap().lines().count(),
+            num_cross_gene
+        );
+        (num_collapses, num_cross_gene)
+    }
+
+    #[test]
+    fn soft_gene_restriction_holds_back_cross_gene_edges() {
+        // the edge passes thr but not the cross-gene threshold
+        assert_eq!(collapse_cross_gene_edge(-0.3, -0.1, -0.5), (0, 0));
+        // and passes both
+        assert_eq!(collapse_cross_gene_edge(-0.6, -0.1, -0.5), (1, 1));
+    }
+
+    #[test]
+    fn default_filters_keep_the_former_cutoffs() {
+        let filters = Filters::default();
+        assert_eq!(
+            filters,
+            Filters {
+                min_mean: 1.0,
+                min_spread: 0.1,
+                eq_weight_frac: 0.1,
+                edge_count_frac: 1.0,
+                min_replicate_sum: 1.0,
+            }
+        );
+        // a sum of exactly 1 was left out of the percentile
+        let gibbs = array![[0.5, 0.5], [0.5, 0.6], [0.0, 0.0]];
+        assert_eq!(count_low_sum(&gibbs, filters.min_replicate_sum), 2);
+    }
+
+    #[test]
+    fn filter_counts_record_what_each_filter_drops() {
+        let mut exp = EqClassExperiment::new();
+        exp.targets = (0..5).map(|i| format!("t{}", i)).collect();
+        exp.ntarget = 5;
+        // the weight of t4 is below 0.1 / 3
+        exp.add_class(&mut vec![0, 1], &mut vec![0.4, 0.6], 100);
+        exp.add_class(&mut vec![0, 1, 4], &mut vec![0.3, 0.68, 0.02], 50);
+        // the count of the edge is the mean of t3, which is not enough
+        exp.add_class(&mut vec![1, 3], &mut vec![0.25, 0.75], 5);
+        exp.add_class(&mut vec![2], &mut vec![1.0], 10);
+        exp.neq = 4;
+        let eq_counts: Vec<u32> = exp.classes.iter().map(|c| c.2).collect();
+
+        let mut gibbs = array![
+            [10.0, 30.0, 10.0, 30.0],
+            [30.0, 10.0, 30.0, 10.0],
+            // a mean of exactly 1 and no spread
+            [0.0, 2.0, 0.0, 2.0],
+            [5.0, 5.0, 5.0, 5.0],
+            [10.0, 20.0, 30.0, 20.0],
+        ];
+        let mut uf = UnionFind::new(5);
+        let mut group_order: Vec<String> = (0..5).map(|i| i.to_string()).collect();
+        let mut collapse_order = TreeArena::with_leaves(5);
+        let mut counts = FilterCounts::default();
+        let og = eq_experiment_to_graph(
+            &exp,
+            &mut gibbs,
+            &mut [],
+            &eq_counts,
+            1e-3,
+            f64::MAX,
+            0.0,
+            &mut Vec::new(),
+            &mut uf,
+            &[],
+            None,
+            &HashMap::new(),
+            &mut group_order,
+            &mut collapse_order,
+            false,
+            &mut Vec::new(),
+            &mut Vec::new(),
+            &InfRV::default(),
+            &Aggregation::default(),
+            &Filters::default(),
+            &mut counts,
+        );
+        assert_eq!(counts.low_mean_txps, 1);
+        assert_eq!(counts.low_spread_txps, 1);
+        assert_eq!(counts.low_weight_entries, 1);
+        assert_eq!(counts.low_count_edges, 1);
+        assert_eq!(og.edge_count(), 1);
+        let e = og.edge_references().next().unwrap();
+        assert_eq!((e.source().index(), e.target().index()), (0, 1));
+        assert_eq!(e.weight().count, 150);
+    }
+
+    fn assert_near(x: f64, y: f64) {
+        assert!((x - y).abs() < 1e-9, "{} != {}", x, y);
+    }
+
+    #[test]
+    fn infrv_measures_of_known_replicates() {
+        let def = |measure: InfRVMeasure, pseudocount: f64, offset: f64| InfRV {
+            measure,
+            pseudocount,
+            offset,
+        };
+        // mean 5 and variance 50
+        let a = array![0.0, 10.0];
+        assert_near(infrv_1d(a.view(), &InfRV::default()), 4.5 + 0.01);
+        assert_near(infrv_1d(a.view(), &def(InfRVMeasure::Swish, 0.0, 0.0)), 9.0);
+
+        // mean 4 and standard deviation 2
+        let b = array![2.0, 4.0, 6.0];
+        assert_near(infrv_1d(b.view(), &def(InfRVMeasure::CV, 0.0, 0.0)), 0.5);
+        assert_near(infrv_1d(b.view(), &def(InfRVMeasure::CV, 4.0, 0.1)), 0.35);
+
+        // log counts of 1 and 3 with the pseudocount
+        let c = array![1f64.exp() - 1.0, 3f64.exp() - 1.0];
+        assert_near(
+            infrv_1d(c.view(), &def(InfRVMeasure::LogVar, 1.0, 0.0)),
+            2.0,
+        );
+
+        // the central 95% of 0..=100 spans 2.5 to 97.5
+        let d = Array1::from((0..=100).map(f64::from).collect::<Vec<f64>>());
+        assert_near(
+            infrv_1d(d.view(), &def(InfRVMeasure::Interval, 0.0, 0.0)),
+            1.9,
+        );
+        assert_near(
+            infrv_1d(d.view(), &def(InfRVMeasure::Interval, 50.0, 0.0)),
+            0.95,
+        );
+    }
+
+    #[test]
+    fn infrv_is_floored_at_the_offset() {
+        // less variance than the mean gives a negative Swish infRV
+        let a = array![5.0, 5.0, 5.0];
+        assert_eq!(infrv_1d(a.view(), &InfRV::default()), 0.01);
+        let def = InfRV {
+            offset: 0.5,
+            ..InfRV::default()
+        };
+        assert_eq!(infrv_1d(a.view(), &def), 0.5);
+
+        let m = array![[0.0, 10.0], [5.0, 5.0]];
+        let rows = infrv(&m, Axis(1), &def);
+        assert_near(rows[0], 45.0 / 10.0 + 0.5);
+        assert_eq!(rows[1], 0.5);
+
+        for name in ["swish", "cv", "logvar", "interval"] {
+            assert!(name.parse::<InfRVMeasure>().is_ok());
+        }
+        assert!("var".parse::<InfRVMeasure>().is_err());
+    }
+
+    #[test]
+    fn writer_round_trips_every_compression() {
+        for c in OutputCompression::ALL.iter() {
+            let p = temp_path(&format!("round_trip_{}.txt", c.name()));
+            let mut w = create_writer(&p, *c);
+            for i in 0..1000 {
+                writeln!(w, "line {}", i).unwrap();
+            }
+            w.finish().unwrap();
+            assert!(with_extension(&p, c.extension()).is_file());
+
+            let lines: Vec<String> = open_reader(&p).lines().map(|l| l.unwrap()).collect();
+            assert_eq!(lines.len(), 1000);
+            assert_eq!(lines[0], "line 0");
+            assert_eq!(lines[999], "line 999");
+        }
+    }
+
+    #[test]
+    fn writer_removes_stale_compressions() {
+        let p = temp_path("stale.txt");
+        let mut w = create_writer(&p, OutputCompression::Gzip);
+        write!(w, "old").unwrap();
+        w.finish().unwrap();
+        let mut w = create_writer(&p, OutputCompression::Zstd);
+        write!(w, "new").unwrap();
+        w.finish().unwrap();
+
+        assert!(!with_extension(&p, ".gz").is_file());
+        assert_eq!(
+            resolve_compressed_path(&p),
+            Some(with_extension(&p, ".zst"))
+        );
+        let mut s = String::new();
+        open_reader(&p).read_to_string(&mut s).unwrap();
+        assert_eq!(s, "new");
+    }
+}